@@ -0,0 +1,18 @@
+// Replaces preview cards' links with their players when clicked, so that nothing is loaded from the
+// provider until then.
+document.addEventListener('click', (event) => {
+    const link = event.target.closest('a[data-embed-src]');
+    if (!link) {
+        return;
+    }
+    event.preventDefault();
+    const iframe = document.createElement('iframe');
+    iframe.src = link.dataset.embedSrc;
+    iframe.width = link.dataset.embedWidth;
+    iframe.height = link.dataset.embedHeight;
+    iframe.allow = 'autoplay; fullscreen; picture-in-picture';
+    iframe.allowFullscreen = true;
+    iframe.setAttribute('sandbox', 'allow-scripts allow-same-origin allow-popups allow-presentation');
+    iframe.style.maxWidth = '100%';
+    link.replaceWith(iframe);
+});
@@ -0,0 +1,10 @@
+create table oembed (
+    url text primary key not null,
+    response text not null,
+    fetched_at timestamp not null default current_timestamp,
+    expires_at timestamp not null
+);
+
+alter table link_preview add column embed_src text;
+alter table link_preview add column embed_width integer;
+alter table link_preview add column embed_height integer;
\ No newline at end of file
@@ -0,0 +1,7 @@
+create view note_with_preview as
+select n.note_id, n.body, n.created_at, n.html, n.renderer_version,
+       p.url as preview_url, p.title as preview_title, p.description as preview_description,
+       p.site_name as preview_site_name, p.image_id as preview_image_id,
+       p.embed_src as preview_embed_src, p.embed_width as preview_embed_width,
+       p.embed_height as preview_embed_height
+from note n left join link_preview p on p.note_id = n.note_id;
\ No newline at end of file
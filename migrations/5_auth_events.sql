@@ -0,0 +1,7 @@
+create table auth_event (
+    auth_event_id integer primary key autoincrement,
+    kind text not null,
+    ip_address text,
+    user_agent text,
+    created_at timestamp not null default current_timestamp
+);
\ No newline at end of file
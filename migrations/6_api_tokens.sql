@@ -0,0 +1,9 @@
+create table api_token (
+    api_token_id text primary key not null,
+    name text not null,
+    token_hash blob not null unique,
+    scopes text not null,
+    expires_at timestamp,
+    last_used_at timestamp,
+    created_at timestamp not null default current_timestamp
+);
\ No newline at end of file
@@ -0,0 +1,5 @@
+create table recovery_token (
+    token_hash blob primary key not null,
+    expires_at timestamp not null,
+    created_at timestamp not null default current_timestamp
+);
\ No newline at end of file
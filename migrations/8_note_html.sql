@@ -0,0 +1,2 @@
+alter table note add column html text;
+alter table note add column renderer_version text;
\ No newline at end of file
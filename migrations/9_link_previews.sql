@@ -0,0 +1,9 @@
+create table link_preview (
+    note_id text primary key not null references note (note_id) on delete cascade,
+    url text not null,
+    title text not null,
+    description text,
+    site_name text,
+    image_id text,
+    fetched_at timestamp not null default current_timestamp
+);
\ No newline at end of file
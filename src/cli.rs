@@ -0,0 +1,551 @@
+use std::io::{self, IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use std::{env, fs, process};
+
+use anyhow::Context;
+use chrono::NaiveDateTime;
+use clap::{Args, Subcommand};
+use sqlx::SqlitePool;
+use tokio_util::io::ReaderStream;
+use url::Url;
+use uuid::fmt::Hyphenated;
+use uuid::Uuid;
+
+use crate::config::FeedConfig;
+use crate::services::audit::{AuditService, AuthEventKind};
+use crate::services::backup::BackupService;
+use crate::services::export::ExportService;
+use crate::services::images::{ImageService, ImageSizes};
+use crate::services::import::{ImportOptions, ImportService, ImportSummary};
+use crate::services::notes::{excerpt, NoteService, Sanitizer};
+use crate::services::passkeys::PasskeyService;
+use crate::services::previews::PreviewService;
+use crate::services::sessions::SessionService;
+use crate::web::static_site::StaticSite;
+use crate::web::theme::Theme;
+use crate::web::Site;
+
+/// The user agent recorded in the audit log for CLI actions.
+const USER_AGENT: &str = "yellhole-cli";
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Create, list, show, and delete notes.
+    #[clap(subcommand)]
+    Note(NoteCommand),
+
+    /// Add images.
+    #[clap(subcommand)]
+    Image(ImageCommand),
+
+    /// List and revoke passkeys.
+    #[clap(subcommand)]
+    Passkey(PasskeyCommand),
+
+    /// Manage login sessions.
+    #[clap(subcommand)]
+    Sessions(SessionsCommand),
+
+    /// Write a backup of the database, images, and uploads to a gzipped tarball. Safe to run while
+    /// the server is running.
+    Backup { path: PathBuf },
+
+    /// Export all notes and images as a gzipped tarball of Markdown files and original images.
+    Export { path: PathBuf },
+
+    /// Render the public site (index, notes, monthly archives, Atom feed, images, and assets) as
+    /// static files. Only months which changed since the last export are re-rendered.
+    ExportStatic {
+        dir: PathBuf,
+
+        /// Re-render every page.
+        #[clap(long)]
+        force: bool,
+    },
+
+    /// Import notes and images.
+    #[clap(subcommand)]
+    Import(ImportCommand),
+
+    /// Verify and restore a backup into the data directory. Stop the server first.
+    Restore {
+        archive: PathBuf,
+
+        /// Overwrite an existing database.
+        #[clap(long)]
+        force: bool,
+    },
+
+    /// Check the configuration.
+    #[clap(subcommand)]
+    Config(ConfigCommand),
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ConfigCommand {
+    /// Validate the merged configuration file, environment variables, and flags, then print the
+    /// resulting settings.
+    Check,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum NoteCommand {
+    /// Create a new note, reading the body from stdin or, if stdin is a terminal, from $EDITOR.
+    New,
+
+    /// List the most recent notes.
+    List {
+        /// The maximum number of notes to list.
+        #[clap(long, default_value = "20")]
+        limit: u16,
+    },
+
+    /// Show a note's Markdown body.
+    Show { note_id: Uuid },
+
+    /// Delete a note.
+    Delete { note_id: Uuid },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ImageCommand {
+    /// Add an image from a local file or an HTTP(S) URL.
+    Add { source: String },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum PasskeyCommand {
+    /// List all registered passkeys.
+    List,
+
+    /// Revoke a passkey by its base64url-encoded ID.
+    Revoke { passkey_id: String },
+
+    /// Print a single-use link which allows registering a new passkey without logging in.
+    Recover {
+        /// How long the link is valid for (e.g. "15m", "1h").
+        #[clap(long, default_value = "15m", value_parser = humantime::parse_duration)]
+        expires_in: Duration,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ImportCommand {
+    /// Import an archive created by `yellhole export`, preserving note and image IDs and
+    /// timestamps. Notes and images which already exist are skipped.
+    Archive { path: PathBuf },
+
+    /// Import public statuses from an unpacked Mastodon archive.
+    Mastodon(ImportArgs),
+
+    /// Import tweets from an unpacked Twitter archive.
+    Twitter(ImportArgs),
+
+    /// Import posts from an unpacked Tumblr export. Tumblr doesn't record time zones, so post
+    /// times are treated as UTC.
+    Tumblr(ImportArgs),
+}
+
+/// Arguments for importing from other services. Imports are idempotent: posts which were
+/// already imported are skipped.
+#[derive(Debug, Args)]
+pub struct ImportArgs {
+    /// The unpacked archive directory.
+    dir: PathBuf,
+
+    /// Skip replies to other posts.
+    #[clap(long)]
+    skip_replies: bool,
+
+    /// Skip boosts, retweets, and reblogs.
+    #[clap(long)]
+    skip_reblogs: bool,
+}
+
+impl ImportArgs {
+    fn options(&self) -> ImportOptions {
+        ImportOptions { skip_replies: self.skip_replies, skip_reblogs: self.skip_reblogs }
+    }
+}
+
+#[derive(Debug, Subcommand)]
+pub enum SessionsCommand {
+    /// Revoke all login sessions.
+    Clear,
+}
+
+/// The services CLI commands operate on, sharing the server's database and data directory.
+#[derive(Debug)]
+pub struct Services {
+    base_url: Url,
+    notes: NoteService,
+    images: ImageService,
+    previews: PreviewService,
+    passkeys: PasskeyService,
+    sessions: SessionService,
+    audit: AuditService,
+    backups: BackupService,
+    export: ExportService,
+    import: ImportService,
+    static_site: StaticSite,
+}
+
+impl Services {
+    pub fn new(
+        db: SqlitePool,
+        data_dir: &Path,
+        base_url: Url,
+        site: Site,
+        sanitizer: Sanitizer,
+        feed: FeedConfig,
+        image_sizes: ImageSizes,
+    ) -> Result<Services, anyhow::Error> {
+        let notes = NoteService::new(db.clone()).with_sanitizer(sanitizer.clone());
+        let images = ImageService::new(db.clone(), data_dir)?.with_sizes(image_sizes);
+        Ok(Services {
+            export: ExportService::new(notes.clone(), images.clone(), data_dir),
+            import: ImportService::new(notes.clone(), images.clone()),
+            static_site: StaticSite::new(
+                notes.clone(),
+                data_dir,
+                base_url.clone(),
+                site,
+                Theme::load(data_dir)?,
+                feed,
+            ),
+            previews: PreviewService::new(db.clone(), images.clone()).with_sanitizer(sanitizer),
+            notes,
+            images,
+            passkeys: PasskeyService::new(db.clone(), &base_url),
+            sessions: SessionService::store(&db, Duration::ZERO),
+            audit: AuditService::new(db.clone()),
+            backups: BackupService::new(db, data_dir),
+            base_url,
+        })
+    }
+}
+
+impl Services {
+    /// Waits at most `timeout` for linked pages when fetching preview cards.
+    pub fn with_preview_timeout(self, timeout: Duration) -> Services {
+        Services { previews: self.previews.with_timeout(timeout), ..self }
+    }
+}
+
+impl Command {
+    pub async fn run(self, ctx: &Services, out: &mut impl Write) -> Result<(), anyhow::Error> {
+        match self {
+            Command::Note(NoteCommand::New) => {
+                let body = read_body()?;
+                anyhow::ensure!(!body.trim().is_empty(), "empty note body, aborting");
+                let note_id = ctx.notes.create(&body).await?;
+                if let Err(err) = ctx.previews.refresh(&note_id, &body).await {
+                    tracing::warn!(?err, %note_id, "unable to fetch link preview");
+                }
+                writeln!(out, "{}", ctx.base_url.join(&format!("note/{note_id}"))?)?;
+            }
+            Command::Note(NoteCommand::List { limit }) => {
+                for note in ctx.notes.most_recent(limit).await? {
+                    let summary = note.body.lines().next().unwrap_or_default();
+                    writeln!(
+                        out,
+                        "{}  {}  {}",
+                        note.note_id,
+                        format_timestamp(&note.created_at),
+                        excerpt(summary, 60)
+                    )?;
+                }
+            }
+            Command::Note(NoteCommand::Show { note_id }) => {
+                let note = ctx
+                    .notes
+                    .by_id(note_id.as_hyphenated())
+                    .await?
+                    .ok_or_else(|| anyhow::anyhow!("no such note: {note_id}"))?;
+                writeln!(out, "{}", note.body)?;
+            }
+            Command::Note(NoteCommand::Delete { note_id }) => {
+                anyhow::ensure!(
+                    ctx.notes.delete(note_id.as_hyphenated()).await?,
+                    "no such note: {note_id}"
+                );
+                writeln!(out, "deleted note {note_id}")?;
+            }
+            Command::Image(ImageCommand::Add { source }) => {
+                let image_id = add_image(ctx, &source).await?;
+                let image = ctx
+                    .images
+                    .by_id(&image_id)
+                    .await?
+                    .ok_or_else(|| anyhow::anyhow!("image {image_id} was not added"))?;
+                writeln!(out, "{}", ctx.base_url.join(&image.main_src())?)?;
+            }
+            Command::Passkey(PasskeyCommand::List) => {
+                for passkey in ctx.passkeys.all().await? {
+                    writeln!(
+                        out,
+                        "{}  {}",
+                        base64::encode_config(&passkey.passkey_id, base64::URL_SAFE_NO_PAD),
+                        format_timestamp(&passkey.created_at)
+                    )?;
+                }
+            }
+            Command::Passkey(PasskeyCommand::Revoke { passkey_id }) => {
+                let id = base64::decode_config(
+                    passkey_id.trim_end_matches('='),
+                    base64::URL_SAFE_NO_PAD,
+                )
+                .context("invalid passkey ID")?;
+                anyhow::ensure!(ctx.passkeys.revoke(&id).await?, "no such passkey: {passkey_id}");
+                ctx.audit.record(AuthEventKind::PasskeyRevoked, None, Some(USER_AGENT)).await?;
+                writeln!(out, "revoked passkey {passkey_id}")?;
+            }
+            Command::Passkey(PasskeyCommand::Recover { expires_in }) => {
+                let token = ctx.passkeys.create_recovery_token(expires_in).await?;
+                ctx.audit
+                    .record(AuthEventKind::RecoveryLinkCreated, None, Some(USER_AGENT))
+                    .await?;
+                let mut link = ctx.base_url.join("register")?;
+                link.query_pairs_mut().append_pair("token", &token);
+                writeln!(out, "{link}")?;
+                writeln!(
+                    out,
+                    "This link can be used once and expires in {}.",
+                    humantime::format_duration(expires_in)
+                )?;
+            }
+            Command::Sessions(SessionsCommand::Clear) => {
+                let n = ctx.sessions.revoke_all().await.map_err(|err| anyhow::anyhow!(err))?;
+                ctx.audit.record(AuthEventKind::SessionRevoked, None, Some(USER_AGENT)).await?;
+                writeln!(out, "revoked {n} sessions")?;
+            }
+            Command::Backup { path } => {
+                let manifest = ctx.backups.create(&path).await?;
+                writeln!(out, "backed up {} files to {}", manifest.files.len(), path.display())?;
+            }
+            Command::Export { path } => {
+                let manifest = ctx.export.export_to(&path).await?;
+                writeln!(
+                    out,
+                    "exported {} notes and {} images to {}",
+                    manifest.notes.len(),
+                    manifest.images.len(),
+                    path.display()
+                )?;
+            }
+            Command::ExportStatic { dir, force } => {
+                let summary = ctx.static_site.export(&dir, force).await?;
+                writeln!(
+                    out,
+                    "rendered {} months and {} notes ({} months unchanged), copied {} images to {}",
+                    summary.months_rendered,
+                    summary.notes_rendered,
+                    summary.months_unchanged,
+                    summary.images_copied,
+                    dir.display()
+                )?;
+            }
+            Command::Import(ImportCommand::Archive { path }) => {
+                let summary = ctx.export.import(&path).await?;
+                writeln!(
+                    out,
+                    "imported {} notes and {} images ({} notes and {} images already existed)",
+                    summary.notes_imported,
+                    summary.images_imported,
+                    summary.notes_skipped,
+                    summary.images_skipped
+                )?;
+            }
+            Command::Import(ImportCommand::Mastodon(args)) => {
+                let summary = ctx.import.mastodon(&args.dir, &args.options()).await?;
+                write_import_summary(out, &summary)?;
+            }
+            Command::Import(ImportCommand::Twitter(args)) => {
+                let summary = ctx.import.twitter(&args.dir, &args.options()).await?;
+                write_import_summary(out, &summary)?;
+            }
+            Command::Import(ImportCommand::Tumblr(args)) => {
+                let summary = ctx.import.tumblr(&args.dir, &args.options()).await?;
+                write_import_summary(out, &summary)?;
+            }
+            Command::Restore { .. } => {
+                anyhow::bail!("backups must be restored before the database is opened");
+            }
+            Command::Config(ConfigCommand::Check) => {
+                anyhow::bail!("the configuration must be checked before the database is opened");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Restores a backup into the data directory. Unlike other commands, this runs before the database
+/// is opened, since it replaces the database file.
+pub async fn restore(
+    archive: &Path,
+    data_dir: &Path,
+    force: bool,
+    out: &mut impl Write,
+) -> Result<(), anyhow::Error> {
+    let manifest = BackupService::restore(archive, data_dir, force).await?;
+    writeln!(
+        out,
+        "restored {} files from a backup created at {}",
+        manifest.files.len(),
+        manifest.created_at
+    )?;
+    Ok(())
+}
+
+/// Writes how many notes and images an import added and skipped.
+fn write_import_summary(out: &mut impl Write, summary: &ImportSummary) -> io::Result<()> {
+    writeln!(
+        out,
+        "imported {} notes with {} images ({} already imported, {} skipped, {} images failed)",
+        summary.imported,
+        summary.images,
+        summary.already_imported,
+        summary.skipped,
+        summary.failed_images
+    )
+}
+
+/// Adds an image from an HTTP(S) URL or a local file.
+async fn add_image(ctx: &Services, source: &str) -> Result<Hyphenated, anyhow::Error> {
+    if let Ok(url) = source.parse::<Url>() {
+        if matches!(url.scheme(), "http" | "https") {
+            return ctx.images.download(url).await;
+        }
+    }
+
+    let path = PathBuf::from(source);
+    let content_type = mime_guess::from_path(&path)
+        .first()
+        .filter(|m| m.type_() == mime::IMAGE)
+        .ok_or_else(|| anyhow::anyhow!("{} is not an image file", path.display()))?;
+    let original_filename =
+        path.file_name().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+    let file = tokio::fs::File::open(&path)
+        .await
+        .with_context(|| format!("unable to open {}", path.display()))?;
+    ctx.images.add(&original_filename, &content_type, ReaderStream::new(file)).await
+}
+
+/// Reads a note body from stdin, or from `$VISUAL`/`$EDITOR` if stdin is a terminal.
+fn read_body() -> Result<String, anyhow::Error> {
+    let mut body = String::new();
+    if !io::stdin().is_terminal() {
+        io::stdin().read_to_string(&mut body)?;
+        return Ok(body);
+    }
+
+    let editor = env::var("VISUAL").or_else(|_| env::var("EDITOR")).unwrap_or_else(|_| "vi".into());
+    let path = env::temp_dir().join(format!("yellhole-note-{}.md", Uuid::new_v4()));
+    fs::write(&path, "")?;
+
+    // Run the editor via the shell so that values like `code --wait` work.
+    let status = process::Command::new("sh")
+        .arg("-c")
+        .arg(format!("{editor} \"$1\""))
+        .arg("sh")
+        .arg(&path)
+        .status()
+        .with_context(|| format!("unable to run {editor}"));
+    let body = fs::read_to_string(&path);
+    fs::remove_file(&path)?;
+    anyhow::ensure!(status?.success(), "{editor} exited unsuccessfully");
+    Ok(body?)
+}
+
+fn format_timestamp(t: &NaiveDateTime) -> String {
+    t.format("%Y-%m-%d %H:%M:%S UTC").to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{Author, Title};
+
+    use super::*;
+
+    #[sqlx::test]
+    async fn managing_notes(db: SqlitePool) -> Result<(), anyhow::Error> {
+        let temp_dir = tempdir::TempDir::new("yellhole-test")?;
+        let ctx = services(db, temp_dir.path())?;
+
+        let note_id = ctx.notes.create("It's a me, _Mario_.\n\nWahoo!").await?;
+
+        let out = run(&ctx, Command::Note(NoteCommand::List { limit: 20 })).await?;
+        assert!(out.starts_with(&note_id.to_string()));
+        assert!(out.ends_with("  It's a me, _Mario_.\n"));
+
+        let note_id = Uuid::from(note_id);
+        let out = run(&ctx, Command::Note(NoteCommand::Show { note_id })).await?;
+        assert_eq!(out, "It's a me, _Mario_.\n\nWahoo!\n");
+
+        let out = run(&ctx, Command::Note(NoteCommand::Delete { note_id })).await?;
+        assert_eq!(out, format!("deleted note {note_id}\n"));
+        assert!(run(&ctx, Command::Note(NoteCommand::Show { note_id })).await.is_err());
+        assert!(run(&ctx, Command::Note(NoteCommand::Delete { note_id })).await.is_err());
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn clearing_sessions(db: SqlitePool) -> Result<(), anyhow::Error> {
+        let temp_dir = tempdir::TempDir::new("yellhole-test")?;
+        let ctx = services(db.clone(), temp_dir.path())?;
+
+        sqlx::query(r"insert into session (session_id, as_json) values ('a', '{}'), ('b', '{}')")
+            .execute(&db)
+            .await?;
+
+        let out = run(&ctx, Command::Sessions(SessionsCommand::Clear)).await?;
+        assert_eq!(out, "revoked 2 sessions\n");
+
+        let events = ctx.audit.most_recent(1).await?;
+        assert_eq!(events[0].kind, "session-revoked");
+        assert_eq!(events[0].user_agent.as_deref(), Some(USER_AGENT));
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn creating_recovery_links(db: SqlitePool) -> Result<(), anyhow::Error> {
+        let temp_dir = tempdir::TempDir::new("yellhole-test")?;
+        let ctx = services(db, temp_dir.path())?;
+
+        let expires_in = Duration::from_secs(15 * 60);
+        let out = run(&ctx, Command::Passkey(PasskeyCommand::Recover { expires_in })).await?;
+        let link = out.lines().next().unwrap_or_default().parse::<Url>()?;
+        assert_eq!(link.path(), "/register");
+
+        let (_, token) = link.query_pairs().find(|(k, _)| k == "token").expect("missing token");
+        assert!(ctx.passkeys.recovery_token_is_valid(&token).await?);
+
+        let events = ctx.audit.most_recent(1).await?;
+        assert_eq!(events[0].kind, "recovery-link-created");
+
+        Ok(())
+    }
+
+    fn services(db: SqlitePool, data_dir: &Path) -> Result<Services, anyhow::Error> {
+        let base_url = "http://example.com".parse()?;
+        let site = Site::new(Title("Yellhole".into()), Author("Mr Magoo".into()), chrono_tz::UTC);
+        Services::new(
+            db,
+            data_dir,
+            base_url,
+            site,
+            Sanitizer::default(),
+            Default::default(),
+            Default::default(),
+        )
+    }
+
+    async fn run(ctx: &Services, cmd: Command) -> Result<String, anyhow::Error> {
+        let mut out = Vec::new();
+        cmd.run(ctx, &mut out).await?;
+        Ok(String::from_utf8(out)?)
+    }
+}
@@ -1,5 +1,15 @@
 use std::convert::Infallible;
+use std::fmt;
+use std::fs;
+use std::path::Path;
 use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::Context;
+use axum::http::header::{HeaderName, InvalidHeaderName};
+use axum::http::HeaderValue;
+use rand::{thread_rng, RngCore};
+use url::Url;
 
 #[derive(Debug, Clone)]
 pub struct Author(pub String);
@@ -22,3 +32,186 @@ impl FromStr for Title {
         Ok(Title(s.into()))
     }
 }
+
+/// The header in which a trusted reverse proxy passes the client's IP address.
+#[derive(Debug, Clone)]
+pub struct ClientIpHeader(pub HeaderName);
+
+impl FromStr for ClientIpHeader {
+    type Err = InvalidHeaderName;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(ClientIpHeader(s.parse()?))
+    }
+}
+
+/// The URLs of the author's profiles on other sites, linked with `rel="me"` so those sites can
+/// verify that the same person runs this one.
+#[derive(Debug, Clone, Default)]
+pub struct ProfileUrls(pub Vec<Url>);
+
+/// How many notes the index page and the Atom feed contain.
+#[derive(Debug, Clone, Copy)]
+pub struct FeedConfig {
+    pub index_length: u16,
+    pub atom_length: u16,
+}
+
+impl Default for FeedConfig {
+    fn default() -> Self {
+        FeedConfig { index_length: 100, atom_length: 20 }
+    }
+}
+
+/// Request body limits and response cache lifetimes.
+#[derive(Debug, Clone, Copy)]
+pub struct HttpConfig {
+    /// The maximum size of request bodies for uploads and API writes, in bytes.
+    pub body_limit: usize,
+
+    /// How long clients may cache pages which change as notes are added.
+    pub cache_max_age: Duration,
+
+    /// How long clients may cache pages and files which never change.
+    pub immutable_max_age: Duration,
+}
+
+impl HttpConfig {
+    /// The `Cache-Control` value for pages which change as notes are added.
+    pub fn cache_control(&self) -> HeaderValue {
+        let value = format!("max-age={}", self.cache_max_age.as_secs());
+        HeaderValue::from_str(&value).expect("invalid header")
+    }
+
+    /// The `Cache-Control` value for pages and files which never change.
+    pub fn immutable_cache_control(&self) -> HeaderValue {
+        let value = format!("max-age={},immutable", self.immutable_max_age.as_secs());
+        HeaderValue::from_str(&value).expect("invalid header")
+    }
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        HttpConfig {
+            body_limit: 32 * 1024 * 1024,
+            cache_max_age: Duration::from_secs(300),
+            immutable_max_age: Duration::from_secs(31_536_000),
+        }
+    }
+}
+
+/// Parses a number of bytes with an optional binary unit suffix (e.g. `512KiB`, `32MiB`).
+pub fn parse_byte_size(s: &str) -> Result<usize, anyhow::Error> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (n, unit) = s.split_at(split);
+    let n = n.parse::<usize>().with_context(|| format!("invalid size: {s:?}"))?;
+    let multiplier = match unit.trim() {
+        "" | "B" => 1,
+        "KiB" => 1024,
+        "MiB" => 1024 * 1024,
+        "GiB" => 1024 * 1024 * 1024,
+        unit => anyhow::bail!("invalid size unit {unit:?}, expected B, KiB, MiB, or GiB"),
+    };
+    n.checked_mul(multiplier).with_context(|| format!("size too large: {s:?}"))
+}
+
+/// Reads the settings from a TOML config file as pairs of names and values. Names are the same
+/// as the long flags, with either dashes or underscores.
+pub fn read_file(path: &Path) -> Result<Vec<(String, toml::Value)>, anyhow::Error> {
+    let toml = fs::read_to_string(path)
+        .with_context(|| format!("error reading config file {}", path.display()))?;
+    let table = toml::from_str::<toml::value::Table>(&toml)
+        .with_context(|| format!("invalid config file {}", path.display()))?;
+    Ok(table.into_iter().map(|(k, v)| (k.replace('_', "-"), v)).collect())
+}
+
+/// A secret key used to sign session cookies. Must be at least 64 bytes long.
+#[derive(Clone)]
+pub struct SessionSecret(pub Vec<u8>);
+
+impl SessionSecret {
+    const MIN_LEN: usize = 64;
+
+    /// Generates a new random secret.
+    pub fn generate() -> SessionSecret {
+        let mut secret = vec![0; Self::MIN_LEN];
+        thread_rng().fill_bytes(&mut secret);
+        SessionSecret(secret)
+    }
+
+    /// Loads a hex-encoded secret from the given path, generating and storing a new secret if the
+    /// file doesn't exist.
+    pub fn load_or_generate(path: &Path) -> anyhow::Result<SessionSecret> {
+        if path.exists() {
+            let hex = fs::read_to_string(path).context("error reading session secret")?;
+            return hex
+                .trim()
+                .parse()
+                .with_context(|| format!("invalid session secret in {path:?}"));
+        }
+
+        tracing::info!(?path, "generating new session secret");
+        let secret = SessionSecret::generate();
+        write_private(path, hex::encode(&secret.0).as_bytes())
+            .context("error writing session secret")?;
+        Ok(secret)
+    }
+}
+
+impl fmt::Debug for SessionSecret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SessionSecret(<redacted>)")
+    }
+}
+
+impl FromStr for SessionSecret {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let secret = hex::decode(s).context("session secret must be hex-encoded")?;
+        anyhow::ensure!(
+            secret.len() >= Self::MIN_LEN,
+            "session secret must be at least {} bytes long",
+            Self::MIN_LEN
+        );
+        Ok(SessionSecret(secret))
+    }
+}
+
+#[cfg(unix)]
+fn write_private(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(path)?.write_all(contents)
+}
+
+#[cfg(not(unix))]
+fn write_private(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    fs::write(path, contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn byte_sizes() -> Result<(), anyhow::Error> {
+        assert_eq!(parse_byte_size("1024")?, 1024);
+        assert_eq!(parse_byte_size("512KiB")?, 512 * 1024);
+        assert_eq!(parse_byte_size("32MiB")?, 32 * 1024 * 1024);
+        assert_eq!(parse_byte_size("1 GiB")?, 1024 * 1024 * 1024);
+        assert!(parse_byte_size("32MB").is_err());
+        assert!(parse_byte_size("MiB").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn cache_control() {
+        let http = HttpConfig::default();
+        assert_eq!(http.cache_control(), "max-age=300");
+        assert_eq!(http.immutable_cache_control(), "max-age=31536000,immutable");
+    }
+}
@@ -1,16 +1,27 @@
-use std::fs;
+use std::ffi::OsString;
 use std::path::PathBuf;
+use std::time::Duration;
+use std::{env, fs};
 
-use clap::Parser;
-use config::{Author, Title};
+use anyhow::Context;
+use chrono_tz::Tz;
+use clap::parser::ValueSource;
+use clap::{CommandFactory, FromArgMatches, Parser};
+use cli::{Command, ConfigCommand, Services};
+use config::{Author, ClientIpHeader, FeedConfig, HttpConfig, ProfileUrls, SessionSecret, Title};
 use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
 use tokio::signal;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
 use url::Url;
 
-use crate::web::App;
+use crate::services::backup::{BackupService, Retention, DB_FILENAME};
+use crate::services::images::ImageSizes;
+use crate::services::notes::Sanitizer;
+use crate::services::sessions::SessionConfig;
+use crate::web::{App, Site};
 
+mod cli;
 mod config;
 mod services;
 #[cfg(test)]
@@ -18,49 +29,268 @@ mod test_server;
 mod web;
 
 #[derive(Debug, Parser)]
+#[clap(args_override_self = true)]
 struct Config {
+    /// An administrative command to run instead of starting the server.
+    #[clap(subcommand)]
+    command: Option<Command>,
+
+    /// A TOML file of settings, named like these flags. Flags and environment variables override
+    /// its settings.
+    #[clap(long, env("CONFIG"), global = true)]
+    config: Option<PathBuf>,
+
     /// The port on which to listen. Binds to 0.0.0.0.
     #[clap(long, default_value = "3000", env("PORT"))]
     port: u16,
 
+    /// A separate port on which to serve Prometheus metrics at `/metrics`. If not provided, metrics
+    /// aren't collected or served.
+    #[clap(long, env("METRICS_PORT"))]
+    metrics_port: Option<u16>,
+
+    /// The header in which a trusted reverse proxy passes the client's IP address (e.g.
+    /// "Fly-Client-IP" or "X-Forwarded-For"). If not provided, the address of the connecting peer
+    /// is used, since clients can set forwarding headers to anything.
+    #[clap(long, env("CLIENT_IP_HEADER"))]
+    client_ip_header: Option<ClientIpHeader>,
+
     /// The base URL of the server.
-    #[clap(long, default_value = "http://localhost:3000", env("BASE_URL"))]
+    #[clap(long, default_value = "http://localhost:3000", env("BASE_URL"), global = true)]
     base_url: Url,
 
     /// The directory in which all persistent data is stored.
-    #[clap(long, default_value = "./data", env("DATA_DIR"))]
+    #[clap(long, default_value = "./data", env("DATA_DIR"), global = true)]
     data_dir: PathBuf,
 
     /// The title of the Yellhole instance.
-    #[clap(long, default_value = "Yellhole", env("TITLE"))]
+    #[clap(long, default_value = "Yellhole", env("TITLE"), global = true)]
     title: Title,
 
     /// The name of the person posting this crap.
-    #[clap(long, default_value = "Luther Blissett", env("AUTHOR"))]
+    #[clap(long, default_value = "Luther Blissett", env("AUTHOR"), global = true)]
     author: Author,
+
+    /// The URLs of the author's profiles on other sites (e.g. Mastodon), separated by commas. Pages
+    /// link to them with `rel="me"` so those sites can verify the link back.
+    #[clap(long, env("PROFILE_URLS"), value_delimiter = ',', global = true)]
+    profile_urls: Vec<Url>,
+
+    /// The IANA time zone (e.g. "America/Denver") in which times are shown and notes are grouped
+    /// into monthly archives.
+    #[clap(long, default_value = "UTC", env("TIMEZONE"), global = true)]
+    timezone: Tz,
+
+    /// Hosts from which iframes in notes are allowed (e.g. "www.youtube-nocookie.com"), separated
+    /// by commas. Iframes from any other host are removed.
+    #[clap(long, env("IFRAME_HOSTS"), value_delimiter = ',', global = true)]
+    iframe_hosts: Vec<String>,
+
+    /// How long to wait for a linked page, and separately for its image, when fetching a preview
+    /// card for a note which is just a link.
+    #[clap(long, default_value = "5s", env("PREVIEW_TIMEOUT"), value_parser = humantime::parse_duration, global = true)]
+    preview_timeout: Duration,
+
+    /// The hex-encoded secret used to sign session cookies. If not provided, a random secret is
+    /// generated and stored in the data directory.
+    #[clap(long, env("SESSION_SECRET"), hide_env_values = true)]
+    session_secret: Option<SessionSecret>,
+
+    /// A previous hex-encoded session secret, session cookies signed with which are still accepted.
+    #[clap(long, env("PREVIOUS_SESSION_SECRET"), hide_env_values = true)]
+    previous_session_secret: Option<SessionSecret>,
+
+    /// How long a session lasts after its last use (e.g. "1day", "12h").
+    #[clap(long, default_value = "1day", env("SESSION_TTL"), value_parser = humantime::parse_duration)]
+    session_ttl: Duration,
+
+    /// A directory in which to periodically write backups. If not provided, no scheduled backups
+    /// are made.
+    #[clap(long, env("BACKUP_DIR"))]
+    backup_dir: Option<PathBuf>,
+
+    /// How often to write scheduled backups (e.g. "1day", "6h").
+    #[clap(long, default_value = "1day", env("BACKUP_INTERVAL"), value_parser = humantime::parse_duration)]
+    backup_interval: Duration,
+
+    /// The number of days for which to keep the most recent scheduled backup.
+    #[clap(long, default_value = "7", env("BACKUP_KEEP_DAILY"))]
+    backup_keep_daily: usize,
+
+    /// The number of weeks for which to keep the most recent scheduled backup.
+    #[clap(long, default_value = "4", env("BACKUP_KEEP_WEEKLY"))]
+    backup_keep_weekly: usize,
+
+    /// The number of notes on the index page.
+    #[clap(long, default_value = "100", env("INDEX_LENGTH"), global = true)]
+    index_length: u16,
+
+    /// The number of notes in the Atom feed.
+    #[clap(long, default_value = "20", env("ATOM_LENGTH"), global = true)]
+    atom_length: u16,
+
+    /// The width, in pixels, of the main version of uploaded images.
+    #[clap(long, default_value = "600", env("MAIN_IMAGE_WIDTH"), global = true)]
+    main_image_width: u32,
+
+    /// The width, in pixels, of image thumbnails.
+    #[clap(long, default_value = "100", env("THUMBNAIL_WIDTH"), global = true)]
+    thumbnail_width: u32,
+
+    /// The maximum size of image uploads and API requests (e.g. "32MiB", "512KiB").
+    #[clap(long, default_value = "32MiB", env("BODY_LIMIT"), value_parser = config::parse_byte_size)]
+    body_limit: usize,
+
+    /// How long clients may cache the index, monthly archives, Atom feed, and API responses.
+    #[clap(long, default_value = "5m", env("CACHE_MAX_AGE"), value_parser = humantime::parse_duration)]
+    cache_max_age: Duration,
+
+    /// How long clients may cache single notes, images, and assets, which never change.
+    #[clap(long, default_value = "365days", env("IMMUTABLE_MAX_AGE"), value_parser = humantime::parse_duration)]
+    immutable_max_age: Duration,
+}
+
+impl Config {
+    /// Parses the command line args and environment variables, filling in any settings set by
+    /// neither from the config file, if any.
+    fn load(args: Vec<OsString>) -> anyhow::Result<Config> {
+        let matches = Config::command().try_get_matches_from(&args)?;
+        let config = Config::from_arg_matches(&matches)?;
+        let Some(path) = &config.config else {
+            return Ok(config);
+        };
+
+        // Insert the file's settings as flags before the actual args, so that later flags
+        // override them, skipping any which were set by flags or environment variables.
+        let command = Config::command();
+        let mut file_args = Vec::new();
+        for (name, value) in config::read_file(path)? {
+            let arg = command
+                .get_arguments()
+                .find(|a| a.get_long() == Some(name.as_str()) && a.get_id() != "config")
+                .with_context(|| format!("unknown setting {name:?} in {}", path.display()))?;
+            if matches!(
+                matches.value_source(arg.get_id().as_str()),
+                Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+            ) {
+                continue;
+            }
+
+            let flag = OsString::from(format!("--{name}"));
+            match value {
+                toml::Value::Boolean(true) => file_args.push(flag),
+                toml::Value::Boolean(false) => {}
+                toml::Value::String(s) => file_args.extend([flag, s.into()]),
+                toml::Value::Integer(_) | toml::Value::Float(_) => {
+                    file_args.extend([flag, value.to_string().into()])
+                }
+                toml::Value::Array(values) => {
+                    let values = values
+                        .iter()
+                        .map(|v| v.as_str().map(str::to_string))
+                        .collect::<Option<Vec<String>>>()
+                        .with_context(|| {
+                            format!("invalid value for {name:?} in {}", path.display())
+                        })?;
+                    file_args.extend([flag, values.join(",").into()]);
+                }
+                _ => anyhow::bail!("invalid value for {name:?} in {}", path.display()),
+            }
+        }
+
+        let args =
+            args.iter().take(1).cloned().chain(file_args).chain(args.iter().skip(1).cloned());
+        Config::try_parse_from(args).map_err(|err| {
+            let err = err.to_string();
+            let msg = err.lines().next().unwrap_or_default().trim_start_matches("error: ");
+            anyhow::anyhow!("invalid setting in {}: {msg}", path.display())
+        })
+    }
+
+    /// Checks that the settings are usable.
+    fn validate(&self) -> anyhow::Result<()> {
+        // Static sites use relative links and can be hosted under a path; the server can't.
+        if !matches!(self.command, Some(Command::ExportStatic { .. })) {
+            anyhow::ensure!(self.base_url.path() == "/", "base URL must not have a path");
+        }
+        anyhow::ensure!(self.base_url.host().is_some(), "base URL must have a host");
+        anyhow::ensure!(self.metrics_port != Some(self.port), "metrics port must differ from port");
+        anyhow::ensure!(!self.session_ttl.is_zero(), "session TTL must be positive");
+        anyhow::ensure!(!self.backup_interval.is_zero(), "backup interval must be positive");
+        anyhow::ensure!(!self.preview_timeout.is_zero(), "preview timeout must be positive");
+        anyhow::ensure!(self.index_length > 0, "index length must be positive");
+        anyhow::ensure!(self.atom_length > 0, "Atom feed length must be positive");
+        for (name, width) in
+            [("main image", self.main_image_width), ("thumbnail", self.thumbnail_width)]
+        {
+            anyhow::ensure!(
+                (1..=10_000).contains(&width),
+                "{name} width must be between 1 and 10000 pixels"
+            );
+        }
+        anyhow::ensure!(self.body_limit >= 1024, "body limit must be at least 1KiB");
+        Ok(())
+    }
+
+    fn feed(&self) -> FeedConfig {
+        FeedConfig { index_length: self.index_length, atom_length: self.atom_length }
+    }
+
+    fn http(&self) -> HttpConfig {
+        HttpConfig {
+            body_limit: self.body_limit,
+            cache_max_age: self.cache_max_age,
+            immutable_max_age: self.immutable_max_age,
+        }
+    }
+
+    fn image_sizes(&self) -> ImageSizes {
+        ImageSizes { main: self.main_image_width, thumbnail: self.thumbnail_width }
+    }
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    // Parse the command line args.
-    let config = Config::parse();
-    anyhow::ensure!(config.base_url.path() == "/", "base URL must not have a path");
-    anyhow::ensure!(config.base_url.host().is_some(), "base URL must have a host");
+    // Parse the command line args, environment variables, and config file.
+    let config = match Config::load(env::args_os().collect()) {
+        Ok(config) => config,
+        Err(err) => match err.downcast::<clap::Error>() {
+            Ok(err) => err.exit(),
+            Err(err) => return Err(err),
+        },
+    };
+    config.validate().context("invalid configuration")?;
+    let (feed, http, image_sizes) = (config.feed(), config.http(), config.image_sizes());
+    let sanitizer = Sanitizer::new(&config.iframe_hosts);
+    if let Some(Command::Config(ConfigCommand::Check)) = config.command {
+        println!("{config:#?}");
+        println!("configuration is valid");
+        return Ok(());
+    }
 
     // Initialize the data directory.
     let data_dir = config.data_dir.canonicalize()?;
     fs::create_dir_all(&data_dir)?;
 
-    // Configure tracing, defaulting to debug levels.
+    // Configure tracing, defaulting to debug levels for the server and warnings for commands.
+    let default_filter = match config.command {
+        Some(_) => "warn",
+        None => "trace,yellhole=debug,sqlx=info,hyper=info,mio=info,tower_http=debug",
+    };
     tracing_subscriber::registry()
-        .with(tracing_subscriber::EnvFilter::new(std::env::var("RUST_LOG").unwrap_or_else(|_| {
-            "trace,yellhole=debug,sqlx=info,hyper=info,mio=info,tower_http=debug".into()
-        })))
-        .with(tracing_subscriber::fmt::layer())
+        .with(tracing_subscriber::EnvFilter::new(
+            std::env::var("RUST_LOG").unwrap_or_else(|_| default_filter.into()),
+        ))
+        .with(tracing_subscriber::fmt::layer().with_writer(std::io::stderr))
         .init();
 
+    // Restore a backup, if requested, before the database is opened.
+    if let Some(Command::Restore { archive, force }) = &config.command {
+        return cli::restore(archive, &data_dir, *force, &mut std::io::stdout().lock()).await;
+    }
+
     // Connect to the DB.
-    let db_path = data_dir.join("yellhole.db");
+    let db_path = data_dir.join(DB_FILENAME);
     tracing::info!(?db_path, "opening database");
     let db_opts = SqliteConnectOptions::new().create_if_missing(true).filename(db_path);
     let db = SqlitePoolOptions::new().connect_with(db_opts).await?;
@@ -69,10 +299,65 @@ async fn main() -> anyhow::Result<()> {
     tracing::info!("running migrations");
     sqlx::migrate!().run(&db).await?;
 
+    // Run an administrative command, if any, instead of the server.
+    if let Some(command) = config.command {
+        let services = Services::new(
+            db,
+            &data_dir,
+            config.base_url,
+            Site::new(config.title, config.author, config.timezone)
+                .with_profile_urls(ProfileUrls(config.profile_urls)),
+            sanitizer,
+            feed,
+            image_sizes,
+        )?
+        .with_preview_timeout(config.preview_timeout);
+        return command.run(&services, &mut std::io::stdout().lock()).await;
+    }
+
+    // Use the configured session secret or load a persisted one.
+    let session_secret = match config.session_secret {
+        Some(secret) => secret,
+        None => SessionSecret::load_or_generate(&data_dir.join("session.key"))?,
+    };
+    let session_config = SessionConfig {
+        secret: session_secret,
+        previous_secret: config.previous_session_secret,
+        ttl: config.session_ttl,
+    };
+
+    // Start writing scheduled backups, if configured.
+    if let Some(backup_dir) = config.backup_dir {
+        let retention =
+            Retention { daily: config.backup_keep_daily, weekly: config.backup_keep_weekly };
+        tracing::info!(?backup_dir, ?retention, "scheduling backups");
+        tokio::spawn(BackupService::new(db.clone(), &data_dir).continuously_back_up(
+            backup_dir,
+            config.backup_interval,
+            retention,
+        ));
+    }
+
     // Spin up an HTTP server and listen for requests.
-    App::new(db, data_dir, config.base_url, config.title, config.author)
-        .serve(&([0, 0, 0, 0], config.port).into(), shutdown_signal())
-        .await
+    App {
+        feed,
+        http,
+        image_sizes,
+        db,
+        data_dir,
+        base_url: config.base_url,
+        title: config.title,
+        author: config.author,
+        profile_urls: ProfileUrls(config.profile_urls),
+        timezone: config.timezone,
+        sanitizer,
+        preview_timeout: config.preview_timeout,
+        session_config,
+        metrics_port: config.metrics_port,
+        client_ip_header: config.client_ip_header,
+    }
+    .serve(&([0, 0, 0, 0], config.port).into(), shutdown_signal())
+    .await
 }
 
 async fn shutdown_signal() {
@@ -104,3 +389,50 @@ async fn shutdown_signal() {
 
     tracing::info!("starting graceful shutdown");
 }
+
+#[cfg(test)]
+mod tests {
+    use tempdir::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn loading_config_files() -> anyhow::Result<()> {
+        let dir = TempDir::new("yellhole-test")?;
+        let path = dir.path().join("yellhole.toml");
+        fs::write(
+            &path,
+            "port = 8080\nindex_length = 50\nbody-limit = \"1MiB\"\n\
+             iframe_hosts = [\"www.youtube-nocookie.com\", \"player.vimeo.com\"]\n\
+             profile_urls = [\"https://social.example/@me\"]\n",
+        )?;
+
+        let args = |extra: &[&str]| {
+            let mut args = vec!["yellhole".into(), "--config".into(), path.clone().into()];
+            args.extend(extra.iter().map(OsString::from));
+            args
+        };
+
+        let config = Config::load(args(&["--port", "9000"]))?;
+        assert_eq!(config.port, 9000);
+        assert_eq!(config.index_length, 50);
+        assert_eq!(config.body_limit, 1024 * 1024);
+        assert_eq!(config.atom_length, 20);
+        assert_eq!(config.iframe_hosts, vec!["www.youtube-nocookie.com", "player.vimeo.com"]);
+        assert_eq!(config.profile_urls, vec!["https://social.example/@me".parse::<Url>()?]);
+        config.validate()?;
+
+        let config = Config::load(args(&["note", "list"]))?;
+        assert_eq!(config.port, 8080);
+        assert!(matches!(config.command, Some(Command::Note(_))));
+
+        fs::write(&path, "bogus = 1\n")?;
+        let err = Config::load(args(&[])).unwrap_err();
+        assert!(err.to_string().starts_with("unknown setting \"bogus\""));
+
+        fs::write(&path, "thumbnail_width = 0\n")?;
+        assert!(Config::load(args(&[]))?.validate().is_err());
+
+        Ok(())
+    }
+}
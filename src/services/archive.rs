@@ -0,0 +1,95 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader, Write};
+use std::path::Path;
+
+use anyhow::Context;
+use chrono::Utc;
+use flate2::read::GzDecoder;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+
+/// The name of the manifest file in backup and export archives.
+pub const MANIFEST_FILENAME: &str = "manifest.json";
+
+/// Appends a file with the given contents to an archive.
+pub fn append_bytes<W: Write>(
+    archive: &mut tar::Builder<W>,
+    path: &str,
+    data: &[u8],
+) -> io::Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(Utc::now().timestamp() as u64);
+    header.set_cksum();
+    archive.append_data(&mut header, path, data)
+}
+
+/// Unpacks a gzipped tarball into `staging`, rejecting entries which would be written outside of
+/// it, and returns its manifest if it's of the given kind and version.
+pub fn unpack<M: DeserializeOwned>(
+    archive: &Path,
+    staging: &Path,
+    kind: &str,
+    version: u32,
+) -> Result<M, anyhow::Error> {
+    let mut tar = tar::Archive::new(GzDecoder::new(BufReader::new(File::open(archive)?)));
+    for entry in tar.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.to_path_buf();
+        anyhow::ensure!(entry.unpack_in(staging)?, "invalid path in archive: {}", path.display());
+    }
+
+    #[derive(Deserialize)]
+    struct Versioned {
+        version: u32,
+    }
+
+    let manifest = fs::read(staging.join(MANIFEST_FILENAME)).context("missing manifest")?;
+    let Versioned { version: actual } =
+        serde_json::from_slice(&manifest).context("invalid manifest")?;
+    anyhow::ensure!(actual == version, "unsupported {kind} version: {actual}");
+    serde_json::from_slice(&manifest).context("invalid manifest")
+}
+
+#[cfg(test)]
+mod tests {
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use tempdir::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn unpacking() -> Result<(), anyhow::Error> {
+        let dir = TempDir::new("yellhole-test")?;
+        let path = dir.path().join("archive.tar.gz");
+        let write = |manifest: &str| -> Result<(), anyhow::Error> {
+            let mut archive =
+                tar::Builder::new(GzEncoder::new(File::create(&path)?, Compression::default()));
+            append_bytes(&mut archive, "notes/a.md", b"Hello.")?;
+            append_bytes(&mut archive, MANIFEST_FILENAME, manifest.as_bytes())?;
+            archive.into_inner()?.finish()?;
+            Ok(())
+        };
+
+        #[derive(Debug, PartialEq, Eq, Deserialize)]
+        struct Manifest {
+            version: u32,
+            name: String,
+        }
+
+        write(r#"{"version": 1, "name": "test"}"#)?;
+        let staging = TempDir::new("yellhole-staging")?;
+        let manifest = unpack::<Manifest>(&path, staging.path(), "test", 1)?;
+        assert_eq!(manifest, Manifest { version: 1, name: "test".into() });
+        assert_eq!(fs::read_to_string(staging.path().join("notes/a.md"))?, "Hello.");
+
+        write(r#"{"version": 2, "name": "test"}"#)?;
+        let staging = TempDir::new("yellhole-staging")?;
+        let err = unpack::<Manifest>(&path, staging.path(), "test", 1).unwrap_err();
+        assert_eq!(err.to_string(), "unsupported test version: 2");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,86 @@
+use std::fmt;
+
+use chrono::NaiveDateTime;
+use sqlx::SqlitePool;
+
+#[derive(Debug, Clone)]
+pub struct AuditService {
+    db: SqlitePool,
+}
+
+impl AuditService {
+    pub fn new(db: SqlitePool) -> AuditService {
+        AuditService { db }
+    }
+
+    /// Records an authentication event.
+    pub async fn record(
+        &self,
+        kind: AuthEventKind,
+        ip_address: Option<&str>,
+        user_agent: Option<&str>,
+    ) -> Result<(), sqlx::Error> {
+        let kind = kind.to_string();
+        tracing::info!(kind, ip_address, user_agent, "recording auth event");
+        sqlx::query!(
+            r"insert into auth_event (kind, ip_address, user_agent) values (?, ?, ?)",
+            kind,
+            ip_address,
+            user_agent,
+        )
+        .execute(&self.db)
+        .await?;
+        Ok(())
+    }
+
+    /// Returns the `n` most recent authentication events, in reverse chronological order.
+    pub async fn most_recent(&self, n: u16) -> Result<Vec<AuthEvent>, sqlx::Error> {
+        sqlx::query_as!(
+            AuthEvent,
+            r#"
+            select kind, ip_address, user_agent, created_at
+            from auth_event
+            order by auth_event_id desc
+            limit ?
+            "#,
+            n
+        )
+        .fetch_all(&self.db)
+        .await
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthEventKind {
+    Registration,
+    LoginSucceeded,
+    LoginFailed,
+    Logout,
+    SessionRevoked,
+    PasskeyRevoked,
+    RecoveryLinkCreated,
+    RecoveryRegistration,
+}
+
+impl fmt::Display for AuthEventKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            AuthEventKind::Registration => "registration",
+            AuthEventKind::LoginSucceeded => "login-succeeded",
+            AuthEventKind::LoginFailed => "login-failed",
+            AuthEventKind::Logout => "logout",
+            AuthEventKind::SessionRevoked => "session-revoked",
+            AuthEventKind::PasskeyRevoked => "passkey-revoked",
+            AuthEventKind::RecoveryLinkCreated => "recovery-link-created",
+            AuthEventKind::RecoveryRegistration => "recovery-registration",
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct AuthEvent {
+    pub kind: String,
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+    pub created_at: NaiveDateTime,
+}
@@ -0,0 +1,430 @@
+use std::cmp::Reverse;
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::Context;
+use chrono::{Datelike, NaiveDateTime, SecondsFormat, Utc};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
+use sqlx::SqlitePool;
+use tokio::{task, time};
+use uuid::Uuid;
+
+use super::archive::{self, MANIFEST_FILENAME};
+use super::images::{IMAGES_DIR, UPLOADS_DIR};
+
+/// The name of the database file in the data directory.
+pub const DB_FILENAME: &str = "yellhole.db";
+
+/// The version of the backup archive format.
+const MANIFEST_VERSION: u32 = 1;
+
+#[derive(Debug, Clone)]
+pub struct BackupService {
+    db: SqlitePool,
+    data_dir: PathBuf,
+}
+
+impl BackupService {
+    pub fn new(db: SqlitePool, data_dir: impl AsRef<Path>) -> BackupService {
+        BackupService { db, data_dir: data_dir.as_ref().to_path_buf() }
+    }
+
+    /// Writes a backup of the database and all images and uploads to the given path as a gzipped
+    /// tarball. Safe to run while the server is running.
+    pub async fn create(&self, path: &Path) -> Result<Manifest, anyhow::Error> {
+        let staging = self.data_dir.join(format!(".backup-{}", Uuid::new_v4()));
+        fs::create_dir_all(&staging)?;
+        let result = self.create_with_staging(path, &staging).await;
+        fs::remove_dir_all(&staging)?;
+        result
+    }
+
+    async fn create_with_staging(
+        &self,
+        path: &Path,
+        staging: &Path,
+    ) -> Result<Manifest, anyhow::Error> {
+        // Snapshot the database first. Images are written to disk before they're added to the
+        // database, so any images added after the snapshot are just harmless extras.
+        let snapshot = staging.join(DB_FILENAME);
+        let snapshot_path = snapshot.to_str().context("invalid data directory path")?;
+        tracing::info!(?path, "backing up database");
+        sqlx::query!(r"vacuum into ?", snapshot_path).execute(&self.db).await?;
+
+        let data_dir = self.data_dir.clone();
+        let path = path.to_path_buf();
+        task::spawn_blocking(move || write_archive(&path, &snapshot, &data_dir)).await?
+    }
+
+    /// Restores the given backup archive into the data directory, verifying its contents first.
+    /// Must not be run while the server is running. If `force` is `false`, refuses to overwrite an
+    /// existing database.
+    pub async fn restore(
+        archive: &Path,
+        data_dir: &Path,
+        force: bool,
+    ) -> Result<Manifest, anyhow::Error> {
+        anyhow::ensure!(
+            force || !data_dir.join(DB_FILENAME).exists(),
+            "{} already contains a database",
+            data_dir.display()
+        );
+
+        let staging = data_dir.join(format!(".restore-{}", Uuid::new_v4()));
+        fs::create_dir_all(&staging)?;
+        let result = restore_with_staging(archive, data_dir, &staging).await;
+        fs::remove_dir_all(&staging)?;
+        result
+    }
+
+    /// Creates a backup in the given directory every `interval`, deleting old backups according to
+    /// the given retention policy.
+    pub async fn continuously_back_up(
+        self,
+        dir: PathBuf,
+        interval: Duration,
+        retention: Retention,
+    ) {
+        let mut interval = time::interval(interval);
+        loop {
+            interval.tick().await;
+            if let Err(err) = self.back_up_to(&dir, &retention).await {
+                tracing::error!(?err, ?dir, "error creating scheduled backup");
+            }
+        }
+    }
+
+    async fn back_up_to(&self, dir: &Path, retention: &Retention) -> Result<(), anyhow::Error> {
+        fs::create_dir_all(dir)?;
+        let name =
+            format!("{BACKUP_PREFIX}{}{BACKUP_SUFFIX}", Utc::now().format(BACKUP_TIME_FORMAT));
+        self.create(&dir.join(name)).await?;
+
+        let mut backups = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if let Some(created_at) = parse_backup_name(&path) {
+                backups.push((path, created_at));
+            }
+        }
+
+        for path in retention.expired(backups) {
+            tracing::info!(?path, "deleting expired backup");
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+}
+
+/// The contents of a backup archive.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    pub version: u32,
+    pub created_at: String,
+    pub files: Vec<ManifestEntry>,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub path: String,
+    pub size: u64,
+    pub sha256: String,
+}
+
+/// How many scheduled backups to keep.
+#[derive(Debug, Clone, Copy)]
+pub struct Retention {
+    /// The number of days for which to keep the most recent backup.
+    pub daily: usize,
+
+    /// The number of weeks for which to keep the most recent backup.
+    pub weekly: usize,
+}
+
+impl Retention {
+    /// Returns the paths of all backups which aren't the most recent backup of one of the last
+    /// `daily` days or `weekly` weeks with backups.
+    fn expired(&self, mut backups: Vec<(PathBuf, NaiveDateTime)>) -> Vec<PathBuf> {
+        backups.sort_by_key(|b| Reverse(b.1));
+
+        let mut days = HashSet::new();
+        let mut weeks = HashSet::new();
+        let mut expired = Vec::new();
+        for (path, created_at) in backups {
+            let date = created_at.date();
+            let week = (date.iso_week().year(), date.iso_week().week());
+            let keep_daily = days.len() < self.daily && days.insert(date);
+            let keep_weekly = weeks.len() < self.weekly && weeks.insert(week);
+            if !keep_daily && !keep_weekly {
+                expired.push(path);
+            }
+        }
+        expired
+    }
+}
+
+const BACKUP_PREFIX: &str = "yellhole-";
+
+const BACKUP_SUFFIX: &str = ".tar.gz";
+
+const BACKUP_TIME_FORMAT: &str = "%Y%m%dT%H%M%SZ";
+
+fn parse_backup_name(path: &Path) -> Option<NaiveDateTime> {
+    let name = path.file_name()?.to_str()?;
+    let timestamp = name.strip_prefix(BACKUP_PREFIX)?.strip_suffix(BACKUP_SUFFIX)?;
+    NaiveDateTime::parse_from_str(timestamp, BACKUP_TIME_FORMAT).ok()
+}
+
+fn write_archive(path: &Path, snapshot: &Path, data_dir: &Path) -> Result<Manifest, anyhow::Error> {
+    // Collect the database snapshot and all images and uploads.
+    let mut files = vec![(DB_FILENAME.to_string(), snapshot.to_path_buf())];
+    for dir in [IMAGES_DIR, UPLOADS_DIR] {
+        list_files(&data_dir.join(dir), Path::new(dir), &mut files)?;
+    }
+
+    // Write the archive to a temporary file and move it into place once it's complete.
+    let partial = path.with_extension("partial");
+    let mut archive = tar::Builder::new(GzEncoder::new(
+        BufWriter::new(File::create(&partial)?),
+        Compression::default(),
+    ));
+    let mut manifest = Manifest {
+        version: MANIFEST_VERSION,
+        created_at: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+        files: Vec::with_capacity(files.len()),
+    };
+    for (name, src) in files {
+        let (size, sha256) = hash_file(&src)?;
+        archive
+            .append_path_with_name(&src, &name)
+            .with_context(|| format!("error archiving {name}"))?;
+        manifest.files.push(ManifestEntry { path: name, size, sha256 });
+    }
+
+    archive::append_bytes(&mut archive, MANIFEST_FILENAME, &serde_json::to_vec_pretty(&manifest)?)?;
+    archive.into_inner()?.finish()?.into_inner().map_err(|err| err.into_error())?.sync_all()?;
+
+    fs::rename(&partial, path)?;
+    tracing::info!(?path, files = manifest.files.len(), "created backup");
+    Ok(manifest)
+}
+
+async fn restore_with_staging(
+    archive: &Path,
+    data_dir: &Path,
+    staging: &Path,
+) -> Result<Manifest, anyhow::Error> {
+    // Unpack and verify the archive's contents.
+    let manifest = {
+        let (archive, staging) = (archive.to_path_buf(), staging.to_path_buf());
+        task::spawn_blocking(move || unpack_archive(&archive, &staging)).await??
+    };
+
+    // Check the database's integrity.
+    let db_opts = SqliteConnectOptions::new().filename(staging.join(DB_FILENAME)).read_only(true);
+    let db = SqlitePoolOptions::new().max_connections(1).connect_with(db_opts).await?;
+    let integrity =
+        sqlx::query_scalar::<_, String>("pragma integrity_check").fetch_one(&db).await?;
+    db.close().await;
+    anyhow::ensure!(integrity == "ok", "database failed integrity check: {integrity}");
+
+    // Replace the database, images, and uploads.
+    for name in [DB_FILENAME, IMAGES_DIR, UPLOADS_DIR] {
+        let (src, dst) = (staging.join(name), data_dir.join(name));
+        if dst.is_dir() {
+            fs::remove_dir_all(&dst)?;
+        } else if dst.exists() {
+            fs::remove_file(&dst)?;
+        }
+        if src.exists() {
+            fs::rename(&src, &dst)?;
+        } else {
+            fs::create_dir_all(&dst)?;
+        }
+    }
+    for suffix in ["-wal", "-shm"] {
+        let path = data_dir.join(format!("{DB_FILENAME}{suffix}"));
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+    }
+
+    tracing::info!(?archive, files = manifest.files.len(), "restored backup");
+    Ok(manifest)
+}
+
+fn unpack_archive(archive: &Path, staging: &Path) -> Result<Manifest, anyhow::Error> {
+    let manifest = archive::unpack::<Manifest>(archive, staging, "backup", MANIFEST_VERSION)?;
+    anyhow::ensure!(
+        manifest.files.iter().any(|f| f.path == DB_FILENAME),
+        "backup does not contain a database"
+    );
+
+    // Check that the unpacked files are exactly those in the manifest.
+    let mut unpacked = Vec::new();
+    list_files(staging, Path::new(""), &mut unpacked)?;
+    let mut unpacked = unpacked
+        .into_iter()
+        .map(|(name, _)| name)
+        .filter(|n| n != MANIFEST_FILENAME)
+        .collect::<Vec<_>>();
+    let mut expected = manifest.files.iter().map(|f| f.path.clone()).collect::<Vec<_>>();
+    unpacked.sort();
+    expected.sort();
+    anyhow::ensure!(unpacked == expected, "archive contents do not match manifest");
+
+    for file in &manifest.files {
+        let (size, sha256) = hash_file(&staging.join(&file.path))?;
+        anyhow::ensure!(
+            size == file.size && sha256 == file.sha256,
+            "checksum mismatch for {}",
+            file.path
+        );
+    }
+
+    Ok(manifest)
+}
+
+/// Recursively lists all files in `dir`, naming them relative to `prefix` with `/` separators.
+fn list_files(dir: &Path, prefix: &Path, files: &mut Vec<(String, PathBuf)>) -> io::Result<()> {
+    if !dir.exists() {
+        return Ok(());
+    }
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = prefix.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            list_files(&entry.path(), &name, files)?;
+        } else {
+            let name =
+                name.components().map(|c| c.as_os_str().to_string_lossy()).collect::<Vec<_>>();
+            files.push((name.join("/"), entry.path()));
+        }
+    }
+    Ok(())
+}
+
+fn hash_file(path: &Path) -> io::Result<(u64, String)> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = [0; 64 * 1024];
+    let mut size = 0;
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        size += n as u64;
+    }
+    Ok((size, hex::encode(hasher.finalize())))
+}
+
+#[cfg(test)]
+mod tests {
+    use flate2::read::GzDecoder;
+    use tempdir::TempDir;
+
+    use crate::services::notes::NoteService;
+
+    use super::*;
+
+    #[sqlx::test]
+    async fn backing_up_and_restoring(db: SqlitePool) -> Result<(), anyhow::Error> {
+        let data_dir = TempDir::new("yellhole-test")?;
+        fs::create_dir_all(data_dir.path().join(IMAGES_DIR))?;
+        fs::write(data_dir.path().join(IMAGES_DIR).join("cat.webp"), b"meow")?;
+
+        let note_id = NoteService::new(db.clone()).create("It's a me, _Mario_.").await?;
+
+        let archive = data_dir.path().join("backup.tar.gz");
+        let manifest = BackupService::new(db, data_dir.path()).create(&archive).await?;
+        assert_eq!(
+            manifest.files.iter().map(|f| f.path.as_str()).collect::<Vec<&str>>(),
+            vec![DB_FILENAME, "images/cat.webp"]
+        );
+
+        let restore_dir = TempDir::new("yellhole-test")?;
+        BackupService::restore(&archive, restore_dir.path(), false).await?;
+        assert_eq!(fs::read(restore_dir.path().join(IMAGES_DIR).join("cat.webp"))?, b"meow");
+        assert!(restore_dir.path().join(UPLOADS_DIR).is_dir());
+
+        let db_opts = SqliteConnectOptions::new().filename(restore_dir.path().join(DB_FILENAME));
+        let restored = SqlitePoolOptions::new().connect_with(db_opts).await?;
+        let note = NoteService::new(restored).by_id(&note_id).await?;
+        assert_eq!(note.map(|n| n.body), Some("It's a me, _Mario_.".to_string()));
+
+        // Don't overwrite an existing database without being forced to.
+        assert!(BackupService::restore(&archive, restore_dir.path(), false).await.is_err());
+        BackupService::restore(&archive, restore_dir.path(), true).await?;
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn rejecting_corrupt_backups(db: SqlitePool) -> Result<(), anyhow::Error> {
+        let data_dir = TempDir::new("yellhole-test")?;
+        let archive = data_dir.path().join("backup.tar.gz");
+        BackupService::new(db, data_dir.path()).create(&archive).await?;
+
+        // Rewrite the archive with a tampered database.
+        let unpacked = TempDir::new("yellhole-test")?;
+        tar::Archive::new(GzDecoder::new(File::open(&archive)?)).unpack(unpacked.path())?;
+        fs::write(unpacked.path().join(DB_FILENAME), b"not a database")?;
+        let mut tampered =
+            tar::Builder::new(GzEncoder::new(File::create(&archive)?, Compression::default()));
+        tampered.append_dir_all("", unpacked.path())?;
+        tampered.into_inner()?.finish()?;
+
+        let restore_dir = TempDir::new("yellhole-test")?;
+        let err = BackupService::restore(&archive, restore_dir.path(), false).await.unwrap_err();
+        assert_eq!(err.to_string(), "checksum mismatch for yellhole.db");
+        assert!(!restore_dir.path().join(DB_FILENAME).exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn retention() {
+        let backup = |s: &str| {
+            let created_at = NaiveDateTime::parse_from_str(s, "%F %T").unwrap();
+            (PathBuf::from(s), created_at)
+        };
+        let backups = vec![
+            backup("2022-11-20 12:00:00"), // Sun, week 46
+            backup("2022-11-20 00:00:00"), // same day
+            backup("2022-11-19 00:00:00"),
+            backup("2022-11-18 00:00:00"),
+            backup("2022-11-13 00:00:00"), // Sun, week 45
+            backup("2022-11-12 00:00:00"),
+            backup("2022-11-06 00:00:00"), // Sun, week 44
+        ];
+
+        let expired = Retention { daily: 2, weekly: 2 }.expired(backups);
+        assert_eq!(
+            expired,
+            vec![
+                PathBuf::from("2022-11-20 00:00:00"),
+                PathBuf::from("2022-11-18 00:00:00"),
+                PathBuf::from("2022-11-12 00:00:00"),
+                PathBuf::from("2022-11-06 00:00:00"),
+            ]
+        );
+    }
+
+    #[test]
+    fn backup_names() {
+        assert_eq!(
+            parse_backup_name(Path::new("/backups/yellhole-20221120T120000Z.tar.gz")),
+            NaiveDateTime::parse_from_str("2022-11-20 12:00:00", "%F %T").ok()
+        );
+        assert_eq!(parse_backup_name(Path::new("/backups/notes.tar.gz")), None);
+    }
+}
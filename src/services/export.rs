@@ -0,0 +1,398 @@
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use chrono::{DateTime, NaiveDateTime, SecondsFormat, Utc};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use mime::Mime;
+use serde::{Deserialize, Serialize};
+use tokio::task;
+use tokio_util::io::ReaderStream;
+use uuid::fmt::Hyphenated;
+use uuid::Uuid;
+
+use super::archive::{self, append_bytes, MANIFEST_FILENAME};
+use super::images::{ImageService, OriginalImage};
+use super::notes::{Note, NoteService};
+
+/// The version of the export archive format.
+const MANIFEST_VERSION: u32 = 1;
+
+/// Exports and imports all notes and images as a gzipped tarball of Markdown files with YAML front
+/// matter, original image files, and a JSON manifest.
+#[derive(Debug, Clone)]
+pub struct ExportService {
+    notes: NoteService,
+    images: ImageService,
+    data_dir: PathBuf,
+}
+
+impl ExportService {
+    pub fn new(
+        notes: NoteService,
+        images: ImageService,
+        data_dir: impl AsRef<Path>,
+    ) -> ExportService {
+        ExportService { notes, images, data_dir: data_dir.as_ref().to_path_buf() }
+    }
+
+    /// Writes an export archive of all notes and images to the given writer.
+    pub async fn export<W>(&self, w: W) -> Result<Manifest, anyhow::Error>
+    where
+        W: Write + Send + 'static,
+    {
+        let notes = self.notes.all().await?;
+        let images = self
+            .images
+            .originals()
+            .await?
+            .into_iter()
+            .map(|image| {
+                let path = self.images.original_path(&image);
+                (image, path)
+            })
+            .collect::<Vec<_>>();
+        task::spawn_blocking(move || write_archive(w, &notes, &images)).await?
+    }
+
+    /// Writes an export archive of all notes and images to the given path.
+    pub async fn export_to(&self, path: &Path) -> Result<Manifest, anyhow::Error> {
+        let partial = path.with_extension("partial");
+        let manifest = self.export(BufWriter::new(File::create(&partial)?)).await?;
+        fs::rename(&partial, path)?;
+        Ok(manifest)
+    }
+
+    /// Writes an export archive of all notes and images to an anonymous temporary file, returning
+    /// the file opened for reading.
+    pub async fn export_to_temp_file(&self) -> Result<tokio::fs::File, anyhow::Error> {
+        let path = self.data_dir.join(format!(".export-{}.tar.gz", Uuid::new_v4()));
+        let result = self.export_to(&path).await;
+        let file = match result {
+            Ok(_) => tokio::fs::File::open(&path).await.map_err(anyhow::Error::from),
+            Err(err) => Err(err),
+        };
+        if path.exists() {
+            fs::remove_file(&path)?;
+        }
+        file
+    }
+
+    /// Imports all notes and images from the given export archive, preserving their IDs and
+    /// timestamps. Notes and images which already exist are skipped.
+    pub async fn import(&self, archive: &Path) -> Result<ImportSummary, anyhow::Error> {
+        let staging = self.data_dir.join(format!(".import-{}", Uuid::new_v4()));
+        fs::create_dir_all(&staging)?;
+        let result = self.import_from_staging(archive, &staging).await;
+        fs::remove_dir_all(&staging)?;
+        result
+    }
+
+    async fn import_from_staging(
+        &self,
+        archive: &Path,
+        staging: &Path,
+    ) -> Result<ImportSummary, anyhow::Error> {
+        let manifest = {
+            let (archive, staging) = (archive.to_path_buf(), staging.to_path_buf());
+            task::spawn_blocking(move || unpack_archive(&archive, &staging)).await??
+        };
+
+        let mut summary = ImportSummary::default();
+        for entry in &manifest.notes {
+            let md = fs::read_to_string(staging.join(&entry.path))
+                .with_context(|| format!("missing note file {}", entry.path))?;
+            let (front_matter, body) =
+                parse_note(&md).with_context(|| format!("invalid note file {}", entry.path))?;
+            if self.notes.import(&front_matter.id, body, &front_matter.created_at).await? {
+                summary.notes_imported += 1;
+            } else {
+                summary.notes_skipped += 1;
+            }
+        }
+
+        for entry in &manifest.images {
+            let file = tokio::fs::File::open(staging.join(&entry.path))
+                .await
+                .with_context(|| format!("missing image file {}", entry.path))?;
+            let content_type = entry.content_type.parse::<Mime>()?;
+            let created_at = parse_timestamp(&entry.created_at)?;
+            let image_id = entry.id.parse::<Uuid>()?.hyphenated();
+            if self
+                .images
+                .import(
+                    image_id,
+                    &entry.original_filename,
+                    &content_type,
+                    created_at,
+                    ReaderStream::new(file),
+                )
+                .await?
+            {
+                summary.images_imported += 1;
+            } else {
+                summary.images_skipped += 1;
+            }
+        }
+
+        tracing::info!(?archive, ?summary, "imported archive");
+        Ok(summary)
+    }
+}
+
+/// The contents of an export archive.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    pub version: u32,
+    pub exported_at: String,
+    pub notes: Vec<NoteEntry>,
+    pub images: Vec<ImageEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NoteEntry {
+    pub id: String,
+    pub created_at: String,
+    pub path: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ImageEntry {
+    pub id: String,
+    pub created_at: String,
+    pub original_filename: String,
+    pub content_type: String,
+    pub path: String,
+}
+
+/// The number of notes and images imported or skipped because they already existed.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ImportSummary {
+    pub notes_imported: usize,
+    pub notes_skipped: usize,
+    pub images_imported: usize,
+    pub images_skipped: usize,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct FrontMatter {
+    id: Hyphenated,
+    created_at: NaiveDateTime,
+}
+
+fn write_archive<W: Write>(
+    w: W,
+    notes: &[Note],
+    images: &[(OriginalImage, PathBuf)],
+) -> Result<Manifest, anyhow::Error> {
+    let mut archive = tar::Builder::new(GzEncoder::new(w, Compression::default()));
+    let mut manifest = Manifest {
+        version: MANIFEST_VERSION,
+        exported_at: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+        notes: Vec::with_capacity(notes.len()),
+        images: Vec::with_capacity(images.len()),
+    };
+
+    for note in notes {
+        let path = format!("notes/{}-{}.md", note.created_at.format("%Y-%m-%d"), note.note_id);
+        append_bytes(&mut archive, &path, format_note(note).as_bytes())?;
+        manifest.notes.push(NoteEntry {
+            id: note.note_id.to_string(),
+            created_at: format_timestamp(&note.created_at),
+            path,
+        });
+    }
+
+    for (image, src) in images {
+        if !src.exists() {
+            tracing::warn!(image_id=%image.image_id, ?src, "missing original image file");
+            continue;
+        }
+        let ext = src.extension().and_then(|s| s.to_str()).unwrap_or("bin");
+        let path = format!("images/{}.{ext}", image.image_id);
+        archive.append_path_with_name(src, &path)?;
+        manifest.images.push(ImageEntry {
+            id: image.image_id.to_string(),
+            created_at: format_timestamp(&image.created_at),
+            original_filename: image.original_filename.clone(),
+            content_type: image.content_type.clone(),
+            path,
+        });
+    }
+
+    append_bytes(&mut archive, MANIFEST_FILENAME, &serde_json::to_vec_pretty(&manifest)?)?;
+    archive.into_inner()?.finish()?.flush()?;
+    Ok(manifest)
+}
+
+fn unpack_archive(archive: &Path, staging: &Path) -> Result<Manifest, anyhow::Error> {
+    archive::unpack(archive, staging, "export", MANIFEST_VERSION)
+}
+
+/// Formats a note as Markdown with YAML front matter.
+fn format_note(note: &Note) -> String {
+    format!(
+        "---\nid: {}\ncreated_at: {}\ntags: []\n---\n{}",
+        note.note_id,
+        format_timestamp(&note.created_at),
+        note.body
+    )
+}
+
+/// Parses a note's front matter and body.
+fn parse_note(md: &str) -> Result<(FrontMatter, &str), anyhow::Error> {
+    let rest = md.strip_prefix("---\n").context("missing front matter")?;
+    let (front_matter, body) = rest.split_once("\n---\n").context("unterminated front matter")?;
+
+    let (mut id, mut created_at) = (None, None);
+    for line in front_matter.lines() {
+        let Some((key, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim().trim_matches(|c| c == '"' || c == '\'');
+        match key.trim() {
+            "id" => id = Some(value.parse::<Uuid>()?.hyphenated()),
+            "created_at" => created_at = Some(parse_timestamp(value)?),
+            _ => {}
+        }
+    }
+
+    let front_matter = FrontMatter {
+        id: id.context("missing id")?,
+        created_at: created_at.context("missing created_at")?,
+    };
+    Ok((front_matter, body))
+}
+
+fn format_timestamp(t: &NaiveDateTime) -> String {
+    DateTime::<Utc>::from_utc(*t, Utc).to_rfc3339_opts(SecondsFormat::AutoSi, true)
+}
+
+fn parse_timestamp(s: &str) -> Result<NaiveDateTime, anyhow::Error> {
+    Ok(DateTime::parse_from_rfc3339(s)?.naive_utc())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::GzDecoder;
+    use sqlx::sqlite::SqlitePoolOptions;
+    use sqlx::SqlitePool;
+    use tempdir::TempDir;
+
+    use super::*;
+
+    #[sqlx::test]
+    async fn round_tripping_notes(db: SqlitePool) -> Result<(), anyhow::Error> {
+        let data_dir = TempDir::new("yellhole-test")?;
+        let export = service(db, data_dir.path())?;
+
+        let note_id = Uuid::new_v4().hyphenated();
+        let created_at = parse_timestamp("2022-11-14T18:22:00Z")?;
+        export.notes.import(&note_id, "It's a me, _Mario_.\n\n---\n\nWahoo!", &created_at).await?;
+
+        let archive = data_dir.path().join("export.tar.gz");
+        let manifest = export.export_to(&archive).await?;
+        assert_eq!(manifest.notes.len(), 1);
+        assert_eq!(manifest.notes[0].path, format!("notes/2022-11-14-{note_id}.md"));
+        assert_eq!(
+            read_entry(&archive, &manifest.notes[0].path)?,
+            format!(
+                "---\nid: {note_id}\ncreated_at: 2022-11-14T18:22:00Z\ntags: []\n---\n\
+                 It's a me, _Mario_.\n\n---\n\nWahoo!"
+            )
+            .into_bytes()
+        );
+
+        // Import into a fresh database.
+        let other_db = SqlitePoolOptions::new().connect("sqlite::memory:").await?;
+        sqlx::migrate!().run(&other_db).await?;
+        let other_dir = TempDir::new("yellhole-test")?;
+        let other = service(other_db, other_dir.path())?;
+
+        let summary = other.import(&archive).await?;
+        assert_eq!(summary, ImportSummary { notes_imported: 1, ..Default::default() });
+
+        let note = other.notes.by_id(&note_id).await?.expect("missing note");
+        assert_eq!(note.body, "It's a me, _Mario_.\n\n---\n\nWahoo!");
+        assert_eq!(note.created_at, created_at);
+
+        // Importing again skips existing notes.
+        let summary = other.import(&archive).await?;
+        assert_eq!(summary, ImportSummary { notes_skipped: 1, ..Default::default() });
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn exporting_images(db: SqlitePool) -> Result<(), anyhow::Error> {
+        let data_dir = TempDir::new("yellhole-test")?;
+        let export = service(db.clone(), data_dir.path())?;
+
+        let image_id = Uuid::new_v4().hyphenated();
+        sqlx::query(
+            r"insert into image (image_id, original_filename, content_type) values (?, ?, ?)",
+        )
+        .bind(image_id.to_string())
+        .bind("cat.jpg")
+        .bind("image/jpeg")
+        .execute(&db)
+        .await?;
+        fs::write(data_dir.path().join("uploads").join(format!("{image_id}.orig.jpeg")), b"meow")?;
+
+        let archive = data_dir.path().join("export.tar.gz");
+        let manifest = export.export_to(&archive).await?;
+        assert_eq!(manifest.images.len(), 1);
+        assert_eq!(manifest.images[0].path, format!("images/{image_id}.jpeg"));
+        assert_eq!(manifest.images[0].original_filename, "cat.jpg");
+        assert_eq!(manifest.images[0].content_type, "image/jpeg");
+        assert_eq!(read_entry(&archive, &manifest.images[0].path)?, b"meow");
+
+        Ok(())
+    }
+
+    #[test]
+    fn parsing_notes() -> Result<(), anyhow::Error> {
+        let (front_matter, body) = parse_note(
+            "---\nid: \"69b124f0-a4fa-40d0-83f4-06bc4213f3ca\"\ncreated_at: 2022-11-14T13:22:00-05:00\n\
+             tags: [mario]\n---\nIt's a me.",
+        )?;
+        assert_eq!(
+            front_matter,
+            FrontMatter {
+                id: "69b124f0-a4fa-40d0-83f4-06bc4213f3ca".parse::<Uuid>()?.hyphenated(),
+                created_at: parse_timestamp("2022-11-14T18:22:00Z")?,
+            }
+        );
+        assert_eq!(body, "It's a me.");
+
+        assert!(parse_note("It's a me.").is_err());
+        assert!(parse_note("---\ntags: []\n---\nIt's a me.").is_err());
+
+        Ok(())
+    }
+
+    fn service(db: SqlitePool, data_dir: &Path) -> Result<ExportService, anyhow::Error> {
+        Ok(ExportService::new(
+            NoteService::new(db.clone()),
+            ImageService::new(db, data_dir)?,
+            data_dir,
+        ))
+    }
+
+    fn read_entry(archive: &Path, path: &str) -> Result<Vec<u8>, anyhow::Error> {
+        let mut tar = tar::Archive::new(GzDecoder::new(File::open(archive)?));
+        for entry in tar.entries()? {
+            let mut entry = entry?;
+            if entry.path()?.to_str() == Some(path) {
+                let mut buf = Vec::new();
+                entry.read_to_end(&mut buf)?;
+                return Ok(buf);
+            }
+        }
+        anyhow::bail!("missing {path}")
+    }
+}
@@ -0,0 +1,195 @@
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::Future;
+use sqlx::SqlitePool;
+use tokio::process::Command;
+use tokio::task::{self, JoinHandle};
+use tokio::{fs, time};
+use uuid::Uuid;
+
+/// How long to wait for the image processor to respond.
+const PROCESSOR_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Checks whether the server's dependencies are usable.
+#[derive(Debug, Clone)]
+pub struct HealthService {
+    db: SqlitePool,
+    data_dir: PathBuf,
+    session_expiry: TaskMonitor,
+}
+
+impl HealthService {
+    pub fn new(db: SqlitePool, data_dir: &Path, session_expiry: TaskMonitor) -> HealthService {
+        HealthService { db, data_dir: data_dir.to_path_buf(), session_expiry }
+    }
+
+    /// Runs all readiness checks, returning the result of each by name.
+    pub async fn check(&self) -> Readiness {
+        let (database, migrations, data_dir, image_processor) = tokio::join!(
+            self.check_database(),
+            self.check_migrations(),
+            self.check_data_dir(),
+            check_image_processor(),
+        );
+        let session_expiry = if self.session_expiry.is_running() {
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!("session expiry task has stopped"))
+        };
+
+        let checks = [
+            ("database", database),
+            ("migrations", migrations),
+            ("data_dir", data_dir),
+            ("image_processor", image_processor),
+            ("session_expiry", session_expiry),
+        ];
+        Readiness(
+            checks
+                .into_iter()
+                .map(|(name, res)| {
+                    let res = res.map_err(|err| {
+                        tracing::warn!(?err, check = name, "readiness check failed");
+                        err.to_string()
+                    });
+                    (name, res)
+                })
+                .collect(),
+        )
+    }
+
+    async fn check_database(&self) -> Result<(), anyhow::Error> {
+        sqlx::query("select 1").execute(&self.db).await?;
+        Ok(())
+    }
+
+    async fn check_migrations(&self) -> Result<(), anyhow::Error> {
+        let applied = sqlx::query_scalar::<_, i64>(
+            r"select version from _sqlx_migrations where success = true",
+        )
+        .fetch_all(&self.db)
+        .await?
+        .into_iter()
+        .collect::<HashSet<i64>>();
+        let pending = sqlx::migrate!()
+            .iter()
+            .filter(|m| !applied.contains(&m.version))
+            .map(|m| m.version.to_string())
+            .collect::<Vec<String>>();
+        anyhow::ensure!(pending.is_empty(), "pending migrations: {}", pending.join(", "));
+        Ok(())
+    }
+
+    async fn check_data_dir(&self) -> Result<(), anyhow::Error> {
+        let path = self.data_dir.join(format!(".readyz-{}", Uuid::new_v4()));
+        fs::write(&path, b"ok").await?;
+        fs::remove_file(&path).await?;
+        Ok(())
+    }
+}
+
+/// Checks that ImageMagick's `convert`, used to process images, can be run.
+async fn check_image_processor() -> Result<(), anyhow::Error> {
+    let status = Command::new("convert")
+        .arg("-version")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .status();
+    let status = time::timeout(PROCESSOR_TIMEOUT, status).await??;
+    anyhow::ensure!(status.success(), "convert -version failed: {status}");
+    Ok(())
+}
+
+/// The results of the readiness checks, by name.
+#[derive(Debug)]
+pub struct Readiness(pub BTreeMap<&'static str, Result<(), String>>);
+
+impl Readiness {
+    /// Returns `true` if every check passed.
+    pub fn is_ready(&self) -> bool {
+        self.0.values().all(Result::is_ok)
+    }
+}
+
+/// Tracks whether a spawned background task is still running.
+#[derive(Debug, Clone)]
+pub struct TaskMonitor {
+    running: Arc<AtomicBool>,
+}
+
+impl TaskMonitor {
+    /// Spawns the given future as a task, returning a monitor for it and its handle.
+    pub fn spawn<F>(fut: F) -> (TaskMonitor, JoinHandle<F::Output>)
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let running = Arc::new(AtomicBool::new(true));
+        let guard = Finished(running.clone());
+        let handle = task::spawn(async move {
+            let _guard = guard;
+            fut.await
+        });
+        (TaskMonitor { running }, handle)
+    }
+
+    /// Returns `true` if the task hasn't returned, panicked, or been cancelled.
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::SeqCst)
+    }
+}
+
+/// Marks a task as finished when dropped.
+struct Finished(Arc<AtomicBool>);
+
+impl Drop for Finished {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[sqlx::test]
+    async fn readiness(db: SqlitePool) -> Result<(), anyhow::Error> {
+        let data_dir = tempdir::TempDir::new("yellhole-test")?;
+        let (expiry, _handle) = TaskMonitor::spawn(futures::future::pending::<()>());
+        let health = HealthService::new(db.clone(), data_dir.path(), expiry);
+
+        let readiness = health.check().await;
+        assert_eq!(readiness.0.get("database"), Some(&Ok(())));
+        assert_eq!(readiness.0.get("migrations"), Some(&Ok(())));
+        assert_eq!(readiness.0.get("data_dir"), Some(&Ok(())));
+        assert_eq!(readiness.0.get("session_expiry"), Some(&Ok(())));
+
+        sqlx::query("delete from _sqlx_migrations where version = 1").execute(&db).await?;
+        let readiness = health.check().await;
+        assert!(!readiness.is_ready());
+        assert_eq!(readiness.0.get("migrations"), Some(&Err("pending migrations: 1".to_string())));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn monitoring_tasks() -> Result<(), anyhow::Error> {
+        let (monitor, handle) = TaskMonitor::spawn(async { 1 });
+        assert_eq!(handle.await?, 1);
+        assert!(!monitor.is_running());
+
+        let (monitor, handle) = TaskMonitor::spawn(futures::future::pending::<()>());
+        assert!(monitor.is_running());
+        handle.abort();
+        assert!(handle.await.is_err());
+        assert!(!monitor.is_running());
+
+        Ok(())
+    }
+}
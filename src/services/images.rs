@@ -1,6 +1,6 @@
 use std::fs;
 use std::path::{Path, PathBuf};
-use std::process::ExitStatus;
+use std::time::Instant;
 
 use anyhow::Context;
 use axum::body::Bytes;
@@ -21,6 +21,20 @@ use uuid::Uuid;
 pub struct ImageService {
     db: SqlitePool,
     data_dir: PathBuf,
+    sizes: ImageSizes,
+}
+
+/// The widths, in pixels, of the processed versions of images.
+#[derive(Debug, Clone, Copy)]
+pub struct ImageSizes {
+    pub main: u32,
+    pub thumbnail: u32,
+}
+
+impl Default for ImageSizes {
+    fn default() -> Self {
+        ImageSizes { main: 600, thumbnail: 100 }
+    }
 }
 
 impl ImageService {
@@ -28,7 +42,12 @@ impl ImageService {
         let data_dir = data_dir.as_ref().to_path_buf();
         fs::create_dir_all(data_dir.join(IMAGES_DIR))?;
         fs::create_dir_all(data_dir.join(UPLOADS_DIR))?;
-        Ok(ImageService { db, data_dir })
+        Ok(ImageService { db, data_dir, sizes: ImageSizes::default() })
+    }
+
+    /// Uses the given widths for new images instead of the defaults.
+    pub fn with_sizes(self, sizes: ImageSizes) -> ImageService {
+        ImageService { sizes, ..self }
     }
 
     /// Returns the `n` most recent images, in reverse chronological order.
@@ -47,6 +66,26 @@ impl ImageService {
         .await
     }
 
+    /// Returns the total number of images.
+    pub async fn count(&self) -> Result<i64, sqlx::Error> {
+        sqlx::query_scalar!(r#"select count(*) as "n: i64" from image"#).fetch_one(&self.db).await
+    }
+
+    /// Returns the image with the given ID, if any.
+    pub async fn by_id(&self, image_id: &Hyphenated) -> Result<Option<Image>, sqlx::Error> {
+        sqlx::query_as!(
+            Image,
+            r#"
+            select image_id as "image_id: Hyphenated", created_at
+            from image
+            where image_id = ?
+            "#,
+            image_id
+        )
+        .fetch_optional(&self.db)
+        .await
+    }
+
     /// Processes the given stream as an image file and adds it to the database. Generates a main
     /// WebP image for displaying in the feed and a thumbnail WebP image for the new note gallery.
     pub async fn add<S, E>(
@@ -61,7 +100,71 @@ impl ImageService {
     {
         // Create a unique ID for the image.
         let image_id = Uuid::new_v4().hyphenated();
+        self.insert(image_id, original_filename, content_type, None, stream).await?;
+        Ok(image_id)
+    }
 
+    /// Adds an image with the given ID and creation timestamp, unless an image with that ID
+    /// already exists. Returns `true` if the image was added.
+    pub async fn import<S, E>(
+        &self,
+        image_id: Hyphenated,
+        original_filename: &str,
+        content_type: &Mime,
+        created_at: NaiveDateTime,
+        stream: S,
+    ) -> Result<bool, anyhow::Error>
+    where
+        S: Stream<Item = Result<Bytes, E>>,
+        E: Into<BoxError>,
+    {
+        if self.by_id(&image_id).await?.is_some() {
+            return Ok(false);
+        }
+        self.insert(image_id, original_filename, content_type, Some(created_at), stream).await?;
+        Ok(true)
+    }
+
+    /// Returns all images with their original filenames and content types, oldest first.
+    pub async fn originals(&self) -> Result<Vec<OriginalImage>, sqlx::Error> {
+        sqlx::query_as!(
+            OriginalImage,
+            r#"
+            select
+              image_id as "image_id: Hyphenated",
+              original_filename,
+              content_type,
+              created_at
+            from image
+            order by created_at
+            "#
+        )
+        .fetch_all(&self.db)
+        .await
+    }
+
+    /// The path of the original uploaded file for the given image.
+    pub fn original_path(&self, image: &OriginalImage) -> PathBuf {
+        let subtype = image.content_type.parse::<Mime>().map(|m| m.subtype().to_string());
+        self.data_dir.join(UPLOADS_DIR).join(format!(
+            "{}.orig.{}",
+            image.image_id,
+            subtype.unwrap_or_default()
+        ))
+    }
+
+    async fn insert<S, E>(
+        &self,
+        image_id: Hyphenated,
+        original_filename: &str,
+        content_type: &Mime,
+        created_at: Option<NaiveDateTime>,
+        stream: S,
+    ) -> Result<(), anyhow::Error>
+    where
+        S: Stream<Item = Result<Bytes, E>>,
+        E: Into<BoxError>,
+    {
         // Stream the image file to the uploads directory.
         let original_path = self
             .data_dir
@@ -69,30 +172,43 @@ impl ImageService {
             .join(format!("{image_id}.orig.{}", content_type.subtype()));
         stream_to_file(stream, &original_path).await.context("error streaming image")?;
 
-        // Generate a 600px-wide main WebP image.
+        // Generate a main WebP image, 600px wide by default.
         let main_path = self.data_dir.join(IMAGES_DIR).join(main_filename(&image_id));
-        let main = process_image(&original_path, &main_path, "600");
+        let main = process_image(&original_path, &main_path, self.sizes.main);
 
-        // Generate a 100px-wide thumbnail WebP image.
+        // Generate a thumbnail WebP image, 100px wide by default.
         let thumbnail_path = self.data_dir.join(IMAGES_DIR).join(thumbnail_filename(&image_id));
-        let thumbnail = process_image(&original_path, &thumbnail_path, "100");
+        let thumbnail = process_image(&original_path, &thumbnail_path, self.sizes.thumbnail);
 
         // Wait for image processing to complete.
-        main.await.context("error generating main image")?;
-        thumbnail.await.context("error generating thumbnail image")?;
+        let started = Instant::now();
+        let processed = async {
+            main.await.context("error generating main image")?;
+            thumbnail.await.context("error generating thumbnail image")
+        }
+        .await;
+        metrics::histogram!("yellhole_image_processing_seconds", started.elapsed());
+        if processed.is_err() {
+            metrics::increment_counter!("yellhole_image_processing_failures_total");
+        }
+        processed?;
 
         // Add image to the database.
         let content_type = content_type.to_string();
         sqlx::query!(
-            r"insert into image (image_id, original_filename, content_type) values (?, ?, ?)",
+            r"
+            insert into image (image_id, original_filename, content_type, created_at)
+            values (?, ?, ?, coalesce(?, current_timestamp))
+            ",
             image_id,
             original_filename,
-            content_type
+            content_type,
+            created_at,
         )
         .execute(&self.db)
         .await?;
 
-        Ok(image_id)
+        Ok(())
     }
 
     pub async fn download(&self, image_url: Url) -> Result<Hyphenated, anyhow::Error> {
@@ -114,9 +230,17 @@ impl ImageService {
     }
 }
 
+#[derive(Debug)]
+pub struct OriginalImage {
+    pub image_id: Hyphenated,
+    pub original_filename: String,
+    pub content_type: String,
+    pub created_at: NaiveDateTime,
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub struct Image {
-    image_id: Hyphenated,
+    pub image_id: Hyphenated,
     pub created_at: NaiveDateTime,
 }
 
@@ -133,7 +257,7 @@ impl Image {
 }
 
 /// The canonical filename of the main version of an image.
-fn main_filename(image_id: &Hyphenated) -> String {
+pub fn main_filename(image_id: &Hyphenated) -> String {
     format!("{}.main.webp", image_id)
 }
 
@@ -161,22 +285,22 @@ where
     Ok(())
 }
 
-async fn process_image<'a>(
-    input: &'a Path,
-    output: &'a Path,
-    geometry: &'static str,
-) -> io::Result<ExitStatus> {
+async fn process_image<'a>(input: &'a Path, output: &'a Path, width: u32) -> io::Result<()> {
     let mut proc = Command::new("convert")
         .arg(input)
         .arg("-auto-orient")
         .arg("-strip")
         .arg("-thumbnail")
-        .arg(geometry)
+        .arg(width.to_string())
         .arg(output)
         .spawn()?;
-    proc.wait().await
+    let status = proc.wait().await?;
+    if !status.success() {
+        return Err(io::Error::other(format!("convert failed: {status}")));
+    }
+    Ok(())
 }
 
-const UPLOADS_DIR: &str = "uploads";
+pub const UPLOADS_DIR: &str = "uploads";
 
-const IMAGES_DIR: &str = "images";
+pub const IMAGES_DIR: &str = "images";
@@ -0,0 +1,228 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use chrono::NaiveDateTime;
+use tokio_util::io::ReaderStream;
+use uuid::fmt::Hyphenated;
+use uuid::Uuid;
+
+use super::images::{Image, ImageService};
+use super::notes::NoteService;
+
+mod mastodon;
+mod tumblr;
+mod twitter;
+
+/// The namespace for the deterministic IDs of imported notes and images, which make imports
+/// idempotent.
+const NAMESPACE: Uuid = Uuid::from_u128(0x5e0b_11ec_8f5a_4c1f_9d2e_7a3b_6c4d_8e9f);
+
+/// Imports posts from other services' archives as notes.
+#[derive(Debug, Clone)]
+pub struct ImportService {
+    notes: NoteService,
+    images: ImageService,
+}
+
+impl ImportService {
+    pub fn new(notes: NoteService, images: ImageService) -> ImportService {
+        ImportService { notes, images }
+    }
+
+    /// Imports public posts from an unpacked Mastodon archive directory containing `outbox.json`.
+    pub async fn mastodon(
+        &self,
+        dir: &Path,
+        opts: &ImportOptions,
+    ) -> Result<ImportSummary, anyhow::Error> {
+        let posts = mastodon::parse(dir).context("error reading Mastodon archive")?;
+        self.import("mastodon", posts, opts).await
+    }
+
+    /// Imports tweets from an unpacked Twitter archive directory containing `data/tweets.js`.
+    pub async fn twitter(
+        &self,
+        dir: &Path,
+        opts: &ImportOptions,
+    ) -> Result<ImportSummary, anyhow::Error> {
+        let posts = twitter::parse(dir).context("error reading Twitter archive")?;
+        self.import("twitter", posts, opts).await
+    }
+
+    /// Imports posts from an unpacked Tumblr export directory containing `posts/html`.
+    pub async fn tumblr(
+        &self,
+        dir: &Path,
+        opts: &ImportOptions,
+    ) -> Result<ImportSummary, anyhow::Error> {
+        let posts = tumblr::parse(dir).context("error reading Tumblr export")?;
+        self.import("tumblr", posts, opts).await
+    }
+
+    async fn import(
+        &self,
+        source: &str,
+        posts: Vec<Post>,
+        opts: &ImportOptions,
+    ) -> Result<ImportSummary, anyhow::Error> {
+        let mut summary = ImportSummary::default();
+        for post in posts {
+            if (opts.skip_replies && post.reply) || (opts.skip_reblogs && post.reblog) {
+                summary.skipped += 1;
+                continue;
+            }
+
+            let note_id = import_id(source, &post.id);
+            if self.notes.by_id(&note_id).await?.is_some() {
+                summary.already_imported += 1;
+                continue;
+            }
+
+            // Import the post's media, replacing inline references or appending them to the body.
+            let mut body = post.body;
+            for (i, media) in post.media.iter().enumerate() {
+                let image_id = import_id(source, &format!("{}/{i}", post.id));
+                let image = match self.import_media(image_id, &media.path, &post.created_at).await {
+                    Ok(image) => image,
+                    Err(err) => {
+                        tracing::warn!(?err, post_id = post.id, path = ?media.path, "unable to import media");
+                        summary.failed_images += 1;
+                        continue;
+                    }
+                };
+                summary.images += 1;
+                match &media.placeholder {
+                    Some(placeholder) => body = body.replace(placeholder, &image.main_src()),
+                    None => body.push_str(&format!("\n\n![]({})", image.main_src())),
+                }
+            }
+
+            self.notes.import(&note_id, body.trim(), &post.created_at).await?;
+            summary.imported += 1;
+        }
+
+        tracing::info!(source, ?summary, "imported posts");
+        Ok(summary)
+    }
+
+    async fn import_media(
+        &self,
+        image_id: Hyphenated,
+        path: &Path,
+        created_at: &NaiveDateTime,
+    ) -> Result<Image, anyhow::Error> {
+        let content_type = mime_guess::from_path(path)
+            .first()
+            .filter(|m| m.type_() == mime::IMAGE)
+            .ok_or_else(|| anyhow::anyhow!("not an image"))?;
+        let original_filename =
+            path.file_name().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+        let file = tokio::fs::File::open(path).await?;
+        self.images
+            .import(
+                image_id,
+                &original_filename,
+                &content_type,
+                *created_at,
+                ReaderStream::new(file),
+            )
+            .await?;
+        Ok(Image { image_id, created_at: *created_at })
+    }
+}
+
+/// Which kinds of posts to skip when importing.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ImportOptions {
+    /// Skip replies to other posts.
+    pub skip_replies: bool,
+
+    /// Skip reblogs, boosts, and retweets.
+    pub skip_reblogs: bool,
+}
+
+/// The results of an import.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ImportSummary {
+    /// The number of posts imported as notes.
+    pub imported: usize,
+
+    /// The number of posts skipped because they were imported previously.
+    pub already_imported: usize,
+
+    /// The number of posts skipped because of the import options.
+    pub skipped: usize,
+
+    /// The number of images imported.
+    pub images: usize,
+
+    /// The number of images which couldn't be imported.
+    pub failed_images: usize,
+}
+
+/// A post parsed from an archive.
+#[derive(Debug, PartialEq, Eq)]
+struct Post {
+    /// The post's ID on the original service.
+    id: String,
+    created_at: NaiveDateTime,
+
+    /// The post's body, as Markdown or HTML.
+    body: String,
+    media: Vec<Media>,
+    reply: bool,
+    reblog: bool,
+}
+
+/// An image attached to a post.
+#[derive(Debug, PartialEq, Eq)]
+struct Media {
+    path: PathBuf,
+
+    /// A string in the post's body to replace with the image's URI. If `None`, the image is
+    /// appended to the body.
+    placeholder: Option<String>,
+}
+
+/// Returns a deterministic ID for a post or media item from the given source.
+fn import_id(source: &str, id: &str) -> Hyphenated {
+    Uuid::new_v5(&NAMESPACE, format!("{source}:{id}").as_bytes()).hyphenated()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use sqlx::SqlitePool;
+    use tempdir::TempDir;
+
+    use super::*;
+
+    #[sqlx::test]
+    async fn idempotent_imports(db: SqlitePool) -> Result<(), anyhow::Error> {
+        let data_dir = TempDir::new("yellhole-test")?;
+        let archive = TempDir::new("yellhole-test")?;
+        fs::write(archive.path().join("outbox.json"), mastodon::tests::OUTBOX)?;
+
+        let notes = NoteService::new(db.clone());
+        let import = ImportService::new(notes.clone(), ImageService::new(db, data_dir.path())?);
+
+        let opts = ImportOptions { skip_replies: true, skip_reblogs: true };
+        let summary = import.mastodon(archive.path(), &opts).await?;
+        assert_eq!(
+            summary,
+            ImportSummary { imported: 1, skipped: 2, failed_images: 1, ..Default::default() }
+        );
+
+        let note = notes.by_id(&import_id("mastodon", "https://example.com/statuses/1")).await?;
+        assert_eq!(note.map(|n| n.body), Some("<p>It&apos;s a me, <em>Mario</em>.</p>".into()));
+
+        let summary = import.mastodon(archive.path(), &ImportOptions::default()).await?;
+        assert_eq!(
+            summary,
+            ImportSummary { imported: 2, already_imported: 1, ..Default::default() }
+        );
+
+        Ok(())
+    }
+}
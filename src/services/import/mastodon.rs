@@ -0,0 +1,228 @@
+use std::fs;
+use std::path::Path;
+
+use chrono::{DateTime, NaiveDateTime};
+use serde::Deserialize;
+
+use super::{Media, Post};
+
+const PUBLIC: &str = "https://www.w3.org/ns/activitystreams#Public";
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Outbox {
+    ordered_items: Vec<Activity>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Activity {
+    id: String,
+    #[serde(rename = "type")]
+    kind: String,
+    published: String,
+    #[serde(default)]
+    to: Vec<String>,
+    #[serde(default)]
+    cc: Vec<String>,
+    object: Object,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Object {
+    Note(Box<Status>),
+    Url(String),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Status {
+    id: String,
+    summary: Option<String>,
+    in_reply_to: Option<String>,
+    #[serde(default)]
+    content: String,
+    #[serde(default)]
+    attachment: Vec<Attachment>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Attachment {
+    media_type: Option<String>,
+    url: String,
+}
+
+/// Parses the public and unlisted statuses and boosts from a Mastodon archive's `outbox.json`.
+/// Followers-only and direct statuses are never imported.
+pub(super) fn parse(dir: &Path) -> Result<Vec<Post>, anyhow::Error> {
+    let outbox = serde_json::from_slice::<Outbox>(&fs::read(dir.join("outbox.json"))?)?;
+
+    let mut posts = Vec::with_capacity(outbox.ordered_items.len());
+    for activity in outbox.ordered_items {
+        if !activity.to.iter().chain(activity.cc.iter()).any(|s| s == PUBLIC) {
+            continue;
+        }
+
+        let created_at = parse_timestamp(&activity.published)?;
+        match (activity.kind.as_str(), activity.object) {
+            ("Create", Object::Note(status)) => {
+                let body = match status.summary.filter(|s| !s.is_empty()) {
+                    Some(summary) => {
+                        format!("<details><summary>{summary}</summary>{}</details>", status.content)
+                    }
+                    None => status.content,
+                };
+                let media = status
+                    .attachment
+                    .into_iter()
+                    .filter(|a| a.media_type.as_deref().unwrap_or("image/").starts_with("image/"))
+                    .map(|a| Media {
+                        path: dir.join(a.url.trim_start_matches('/')),
+                        placeholder: None,
+                    })
+                    .collect();
+                posts.push(Post {
+                    id: status.id,
+                    created_at,
+                    body,
+                    media,
+                    reply: status.in_reply_to.is_some(),
+                    reblog: false,
+                });
+            }
+            ("Announce", Object::Url(url)) => {
+                posts.push(Post {
+                    id: activity.id,
+                    created_at,
+                    body: format!("Boosted <{url}>"),
+                    media: vec![],
+                    reply: false,
+                    reblog: true,
+                });
+            }
+            (kind, _) => tracing::debug!(kind, id = activity.id, "skipping unsupported activity"),
+        }
+    }
+    Ok(posts)
+}
+
+fn parse_timestamp(s: &str) -> Result<NaiveDateTime, anyhow::Error> {
+    Ok(DateTime::parse_from_rfc3339(s)?.naive_utc())
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use tempdir::TempDir;
+
+    use super::*;
+
+    pub const OUTBOX: &str = r##"{
+        "@context": "https://www.w3.org/ns/activitystreams",
+        "id": "outbox.json",
+        "type": "OrderedCollection",
+        "orderedItems": [
+            {
+                "id": "https://example.com/statuses/1/activity",
+                "type": "Create",
+                "published": "2022-11-14T18:22:00Z",
+                "to": ["https://www.w3.org/ns/activitystreams#Public"],
+                "cc": ["https://example.com/followers"],
+                "object": {
+                    "id": "https://example.com/statuses/1",
+                    "type": "Note",
+                    "summary": null,
+                    "inReplyTo": null,
+                    "content": "<p>It&apos;s a me, <em>Mario</em>.</p>",
+                    "attachment": [
+                        {
+                            "type": "Document",
+                            "mediaType": "image/jpeg",
+                            "url": "/media_attachments/files/1/original/mario.jpg"
+                        },
+                        {
+                            "type": "Document",
+                            "mediaType": "video/mp4",
+                            "url": "/media_attachments/files/2/original/mario.mp4"
+                        }
+                    ]
+                }
+            },
+            {
+                "id": "https://example.com/statuses/2/activity",
+                "type": "Create",
+                "published": "2022-11-14T18:23:00Z",
+                "to": ["https://example.com/followers"],
+                "cc": ["https://www.w3.org/ns/activitystreams#Public"],
+                "object": {
+                    "id": "https://example.com/statuses/2",
+                    "type": "Note",
+                    "summary": "spoilers",
+                    "inReplyTo": "https://example.com/statuses/1",
+                    "content": "<p>Luigi did it.</p>"
+                }
+            },
+            {
+                "id": "https://example.com/statuses/3/activity",
+                "type": "Announce",
+                "published": "2022-11-14T18:24:00Z",
+                "to": ["https://www.w3.org/ns/activitystreams#Public"],
+                "object": "https://other.example.com/statuses/99"
+            },
+            {
+                "id": "https://example.com/statuses/4/activity",
+                "type": "Create",
+                "published": "2022-11-14T18:25:00Z",
+                "to": ["https://other.example.com/users/peach"],
+                "object": {
+                    "id": "https://example.com/statuses/4",
+                    "type": "Note",
+                    "content": "<p>Secret.</p>"
+                }
+            }
+        ]
+    }"##;
+
+    #[test]
+    fn parsing_outboxes() -> Result<(), anyhow::Error> {
+        let dir = TempDir::new("yellhole-test")?;
+        fs::write(dir.path().join("outbox.json"), OUTBOX)?;
+
+        let posts = parse(dir.path())?;
+        assert_eq!(
+            posts,
+            vec![
+                Post {
+                    id: "https://example.com/statuses/1".into(),
+                    created_at: parse_timestamp("2022-11-14T18:22:00Z")?,
+                    body: "<p>It&apos;s a me, <em>Mario</em>.</p>".into(),
+                    media: vec![Media {
+                        path: dir.path().join("media_attachments/files/1/original/mario.jpg"),
+                        placeholder: None,
+                    }],
+                    reply: false,
+                    reblog: false,
+                },
+                Post {
+                    id: "https://example.com/statuses/2".into(),
+                    created_at: parse_timestamp("2022-11-14T18:23:00Z")?,
+                    body: "<details><summary>spoilers</summary><p>Luigi did it.</p></details>"
+                        .into(),
+                    media: vec![],
+                    reply: true,
+                    reblog: false,
+                },
+                Post {
+                    id: "https://example.com/statuses/3/activity".into(),
+                    created_at: parse_timestamp("2022-11-14T18:24:00Z")?,
+                    body: "Boosted <https://other.example.com/statuses/99>".into(),
+                    media: vec![],
+                    reply: false,
+                    reblog: true,
+                },
+            ]
+        );
+
+        Ok(())
+    }
+}
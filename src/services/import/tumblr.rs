@@ -0,0 +1,155 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use chrono::NaiveDateTime;
+use scraper::{Html, Selector};
+
+use super::{Media, Post};
+
+/// Parses the posts from a Tumblr export's `posts/html` directory. Tumblr exports timestamps
+/// without a time zone, so they're treated as UTC.
+pub(super) fn parse(dir: &Path) -> Result<Vec<Post>, anyhow::Error> {
+    let html_dir = [dir.join("posts").join("html"), dir.join("html")]
+        .into_iter()
+        .find(|d| d.is_dir())
+        .context("missing posts/html directory")?;
+
+    let mut paths = fs::read_dir(&html_dir)?
+        .map(|e| e.map(|e| e.path()))
+        .collect::<Result<Vec<PathBuf>, _>>()?;
+    paths.retain(|p| p.extension().map(|e| e == "html").unwrap_or(false));
+    paths.sort();
+
+    let mut posts = Vec::with_capacity(paths.len());
+    for path in paths {
+        let id = path.file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+        let html = fs::read_to_string(&path)?;
+        let post = parse_post(id, &html, dir, &html_dir)
+            .with_context(|| format!("error parsing {}", path.display()))?;
+        posts.push(post);
+    }
+    Ok(posts)
+}
+
+fn parse_post(id: String, html: &str, dir: &Path, html_dir: &Path) -> Result<Post, anyhow::Error> {
+    let doc = Html::parse_document(html);
+    let body = doc.select(&selector("body")).next().context("missing body")?;
+    let timestamp = doc
+        .select(&selector("#timestamp"))
+        .next()
+        .context("missing timestamp")?
+        .text()
+        .collect::<String>();
+
+    // Use everything in the body except the footer, which contains the timestamp and tags.
+    let mut content = body.inner_html();
+    if let Some(footer) = doc.select(&selector("#footer")).next() {
+        content = content.replace(&footer.html(), "");
+    }
+
+    // Find local images, which are either relative to the HTML file or in the media directory.
+    let mut media = Vec::new();
+    for img in body.select(&selector("img")) {
+        let Some(src) = img.value().attr("src") else { continue };
+        if src.starts_with("http:") || src.starts_with("https:") {
+            continue;
+        }
+        let mut path = html_dir.join(src);
+        if !path.exists() {
+            if let Some(filename) = Path::new(src).file_name() {
+                path = dir.join("media").join(filename);
+            }
+        }
+        media.push(Media { path, placeholder: Some(src.to_string()) });
+    }
+
+    Ok(Post {
+        id,
+        created_at: parse_timestamp(&timestamp)?,
+        body: content.trim().to_string(),
+        media,
+        reply: false,
+        reblog: doc.select(&selector("a.tumblr_blog")).next().is_some(),
+    })
+}
+
+/// Parses timestamps like `November 14th, 2022 6:22pm`.
+fn parse_timestamp(s: &str) -> Result<NaiveDateTime, anyhow::Error> {
+    let s = s
+        .split_whitespace()
+        .map(|w| match w.strip_suffix(',') {
+            Some(day) if day.starts_with(|c: char| c.is_ascii_digit()) => {
+                format!("{},", day.trim_end_matches(|c: char| c.is_ascii_alphabetic()))
+            }
+            _ => w.to_string(),
+        })
+        .collect::<Vec<String>>()
+        .join(" ");
+    NaiveDateTime::parse_from_str(&s, "%B %d, %Y %I:%M%p")
+        .with_context(|| format!("invalid timestamp: {s}"))
+}
+
+fn selector(s: &str) -> Selector {
+    Selector::parse(s).expect("invalid selector")
+}
+
+#[cfg(test)]
+mod tests {
+    use tempdir::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn parsing_posts() -> Result<(), anyhow::Error> {
+        let dir = TempDir::new("yellhole-test")?;
+        let html_dir = dir.path().join("posts").join("html");
+        fs::create_dir_all(&html_dir)?;
+        fs::write(
+            html_dir.join("1001.html"),
+            r#"<!DOCTYPE HTML><html><head><meta charset="utf-8"></head><body>
+            <p>It's a me, <em>Mario</em>.</p><img src="../../media/1001.jpg">
+            <div id="footer"><span id="timestamp"> November 14th, 2022 6:22pm </span>
+            <span class="tag">mario</span></div></body></html>"#,
+        )?;
+        fs::write(
+            html_dir.join("1002.html"),
+            r#"<!DOCTYPE HTML><html><head><meta charset="utf-8"></head><body>
+            <p><a class="tumblr_blog" href="https://luigi.tumblr.com/post/1">luigi</a>:</p>
+            <blockquote><p>Wahoo!</p></blockquote>
+            <div id="footer"><span id="timestamp"> November 1st, 2022 9:05am </span></div>
+            </body></html>"#,
+        )?;
+
+        let posts = parse(dir.path())?;
+        assert_eq!(
+            posts,
+            vec![
+                Post {
+                    id: "1001".into(),
+                    created_at: NaiveDateTime::parse_from_str("2022-11-14 18:22", "%F %R")?,
+                    body: r#"<p>It's a me, <em>Mario</em>.</p><img src="../../media/1001.jpg">"#
+                        .into(),
+                    media: vec![Media {
+                        path: dir.path().join("media").join("1001.jpg"),
+                        placeholder: Some("../../media/1001.jpg".into()),
+                    }],
+                    reply: false,
+                    reblog: false,
+                },
+                Post {
+                    id: "1002".into(),
+                    created_at: NaiveDateTime::parse_from_str("2022-11-01 09:05", "%F %R")?,
+                    body: "<p><a class=\"tumblr_blog\" href=\"https://luigi.tumblr.com/post/1\">\
+                           luigi</a>:</p>\n            <blockquote><p>Wahoo!</p></blockquote>"
+                        .into(),
+                    media: vec![],
+                    reply: false,
+                    reblog: true,
+                },
+            ]
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,188 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::Context;
+use chrono::{DateTime, NaiveDateTime};
+use serde::Deserialize;
+
+use super::{Media, Post};
+
+#[derive(Debug, Deserialize)]
+struct Item {
+    tweet: Tweet,
+}
+
+#[derive(Debug, Deserialize)]
+struct Tweet {
+    id_str: String,
+    created_at: String,
+    full_text: String,
+    in_reply_to_status_id_str: Option<String>,
+    #[serde(default)]
+    entities: Entities,
+    #[serde(default)]
+    extended_entities: Entities,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct Entities {
+    #[serde(default)]
+    urls: Vec<UrlEntity>,
+    #[serde(default)]
+    media: Vec<MediaEntity>,
+}
+
+#[derive(Debug, Deserialize)]
+struct UrlEntity {
+    url: String,
+    expanded_url: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct MediaEntity {
+    url: String,
+    media_url_https: String,
+    #[serde(rename = "type")]
+    kind: String,
+}
+
+/// Parses the tweets from a Twitter archive's `data/tweets.js` (or `data/tweet.js`, in older
+/// archives).
+pub(super) fn parse(dir: &Path) -> Result<Vec<Post>, anyhow::Error> {
+    let data = dir.join("data");
+    let (js, media_dir) = if data.join("tweets.js").exists() {
+        (fs::read_to_string(data.join("tweets.js"))?, data.join("tweets_media"))
+    } else {
+        (fs::read_to_string(data.join("tweet.js"))?, data.join("tweet_media"))
+    };
+
+    // The file is a JS assignment of a JSON array, e.g. `window.YTD.tweets.part0 = [...]`.
+    let (_, json) = js.split_once('=').context("invalid tweets.js")?;
+    let items = serde_json::from_str::<Vec<Item>>(json.trim())?;
+
+    let mut posts = Vec::with_capacity(items.len());
+    for Item { tweet } in items {
+        // Expand t.co links and remove links to attached media.
+        let mut body = tweet.full_text;
+        for url in &tweet.entities.urls {
+            body = body.replace(&url.url, &url.expanded_url);
+        }
+
+        let mut media = Vec::new();
+        for m in &tweet.extended_entities.media {
+            body = body.replace(&m.url, "");
+            if m.kind != "photo" {
+                continue;
+            }
+            let filename = m.media_url_https.rsplit('/').next().unwrap_or_default();
+            media.push(Media {
+                path: media_dir.join(format!("{}-{filename}", tweet.id_str)),
+                placeholder: None,
+            });
+        }
+
+        posts.push(Post {
+            reblog: body.starts_with("RT @"),
+            id: tweet.id_str,
+            created_at: parse_timestamp(&tweet.created_at)?,
+            body: body.trim().to_string(),
+            media,
+            reply: tweet.in_reply_to_status_id_str.is_some(),
+        });
+    }
+    Ok(posts)
+}
+
+fn parse_timestamp(s: &str) -> Result<NaiveDateTime, anyhow::Error> {
+    Ok(DateTime::parse_from_str(s, "%a %b %d %H:%M:%S %z %Y")?.naive_utc())
+}
+
+#[cfg(test)]
+mod tests {
+    use tempdir::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn parsing_tweets() -> Result<(), anyhow::Error> {
+        let dir = TempDir::new("yellhole-test")?;
+        fs::create_dir_all(dir.path().join("data"))?;
+        fs::write(
+            dir.path().join("data/tweets.js"),
+            r#"window.YTD.tweets.part0 = [
+                {
+                    "tweet": {
+                        "id_str": "1",
+                        "created_at": "Mon Nov 14 18:22:00 +0000 2022",
+                        "full_text": "It's a me, Mario. https://t.co/abc https://t.co/img",
+                        "entities": {
+                            "urls": [
+                                { "url": "https://t.co/abc", "expanded_url": "https://mario.example.com" }
+                            ]
+                        },
+                        "extended_entities": {
+                            "media": [
+                                {
+                                    "url": "https://t.co/img",
+                                    "media_url_https": "https://pbs.twimg.com/media/Mario.jpg",
+                                    "type": "photo"
+                                }
+                            ]
+                        }
+                    }
+                },
+                {
+                    "tweet": {
+                        "id_str": "2",
+                        "created_at": "Mon Nov 14 13:23:00 -0500 2022",
+                        "full_text": "@luigi wahoo",
+                        "in_reply_to_status_id_str": "99"
+                    }
+                },
+                {
+                    "tweet": {
+                        "id_str": "3",
+                        "created_at": "Mon Nov 14 18:24:00 +0000 2022",
+                        "full_text": "RT @peach: Cake."
+                    }
+                }
+            ]"#,
+        )?;
+
+        let posts = parse(dir.path())?;
+        assert_eq!(
+            posts,
+            vec![
+                Post {
+                    id: "1".into(),
+                    created_at: parse_timestamp("Mon Nov 14 18:22:00 +0000 2022")?,
+                    body: "It's a me, Mario. https://mario.example.com".into(),
+                    media: vec![Media {
+                        path: dir.path().join("data/tweets_media/1-Mario.jpg"),
+                        placeholder: None,
+                    }],
+                    reply: false,
+                    reblog: false,
+                },
+                Post {
+                    id: "2".into(),
+                    created_at: parse_timestamp("Mon Nov 14 18:23:00 +0000 2022")?,
+                    body: "@luigi wahoo".into(),
+                    media: vec![],
+                    reply: true,
+                    reblog: false,
+                },
+                Post {
+                    id: "3".into(),
+                    created_at: parse_timestamp("Mon Nov 14 18:24:00 +0000 2022")?,
+                    body: "RT @peach: Cake.".into(),
+                    media: vec![],
+                    reply: false,
+                    reblog: true,
+                },
+            ]
+        );
+
+        Ok(())
+    }
+}
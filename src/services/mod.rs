@@ -1,4 +1,13 @@
+pub mod archive;
+pub mod audit;
+pub mod backup;
+pub mod export;
+pub mod health;
 pub mod images;
+pub mod import;
 pub mod notes;
+pub mod oembed;
 pub mod passkeys;
+pub mod previews;
 pub mod sessions;
+pub mod tokens;
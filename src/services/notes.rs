@@ -1,69 +1,205 @@
+use std::collections::{BTreeSet, HashMap};
 use std::ops::Range;
+use std::sync::{Arc, OnceLock};
 
-use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc};
-use pulldown_cmark::{Event, HeadingLevel, Parser, Tag};
+use chrono::{DateTime, Datelike, Duration, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use pulldown_cmark::escape::escape_html;
+use pulldown_cmark::{CodeBlockKind, CowStr, Event, HeadingLevel, Options, Parser, Tag};
 use sqlx::SqlitePool;
+use syntect::html::{ClassStyle, ClassedHTMLGenerator};
+use syntect::parsing::SyntaxSet;
+use syntect::util::LinesWithEndings;
+use url::Url;
 use uuid::fmt::Hyphenated;
 use uuid::Uuid;
 
+use super::oembed::Embed;
+use super::previews::LinkPreview;
+
+/// The version of the Markdown renderer. Bump this whenever rendering changes so that notes' cached
+/// HTML is re-rendered.
+const RENDERER_VERSION: u32 = 2;
+
 #[derive(Debug, Clone)]
 pub struct NoteService {
     db: SqlitePool,
+    sanitizer: Sanitizer,
 }
 
 impl NoteService {
     pub fn new(db: SqlitePool) -> NoteService {
-        NoteService { db }
+        NoteService { db, sanitizer: Sanitizer::default() }
+    }
+
+    /// Sanitizes notes' rendered HTML with the given sanitizer instead of the default one.
+    pub fn with_sanitizer(self, sanitizer: Sanitizer) -> NoteService {
+        NoteService { sanitizer, ..self }
     }
 
     pub async fn create(&self, body: &str) -> Result<Hyphenated, sqlx::Error> {
         let note_id = Uuid::new_v4().hyphenated();
-        sqlx::query!(r"insert into note (note_id, body) values (?, ?)", note_id, body)
-            .execute(&self.db)
-            .await?;
+        let html = render(&note_id, body, &self.sanitizer);
+        let renderer_version = self.renderer_version();
+        sqlx::query!(
+            r"insert into note (note_id, body, html, renderer_version) values (?, ?, ?, ?)",
+            note_id,
+            body,
+            html,
+            renderer_version
+        )
+        .execute(&self.db)
+        .await?;
         Ok(note_id)
     }
 
+    /// Adds a note with the given ID and creation timestamp, unless a note with that ID already
+    /// exists. Returns `true` if the note was added.
+    pub async fn import(
+        &self,
+        note_id: &Hyphenated,
+        body: &str,
+        created_at: &NaiveDateTime,
+    ) -> Result<bool, sqlx::Error> {
+        let html = render(note_id, body, &self.sanitizer);
+        let renderer_version = self.renderer_version();
+        let res = sqlx::query!(
+            r"
+            insert into note (note_id, body, created_at, html, renderer_version)
+            values (?, ?, ?, ?, ?)
+            on conflict (note_id) do nothing
+            ",
+            note_id,
+            body,
+            created_at,
+            html,
+            renderer_version
+        )
+        .execute(&self.db)
+        .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    /// Deletes the note with the given ID, returning `true` if it existed.
+    pub async fn delete(&self, note_id: &Hyphenated) -> Result<bool, sqlx::Error> {
+        tracing::info!(%note_id, "deleting note");
+        let res =
+            sqlx::query!(r"delete from note where note_id = ?", note_id).execute(&self.db).await?;
+        Ok(res.rows_affected() > 0)
+    }
+
     pub async fn by_id(&self, note_id: &Hyphenated) -> Result<Option<Note>, sqlx::Error> {
-        sqlx::query_as!(
-            Note,
+        let row = sqlx::query_as!(
+            NoteRow,
             r#"
-            select note_id as "note_id: Hyphenated", body, created_at
-            from note
+            select note_id as "note_id: Hyphenated", body, created_at, html, renderer_version,
+                   preview_url, preview_title, preview_description, preview_site_name,
+                   preview_image_id as "preview_image_id: Hyphenated", preview_embed_src,
+                   preview_embed_width, preview_embed_height
+            from note_with_preview
             where note_id = ?
             "#,
             note_id
         )
         .fetch_optional(&self.db)
-        .await
+        .await?;
+        match row {
+            Some(row) => Ok(Some(self.hydrate(row).await?)),
+            None => Ok(None),
+        }
     }
 
     pub async fn most_recent(&self, n: u16) -> Result<Vec<Note>, sqlx::Error> {
-        sqlx::query_as!(
-            Note,
+        let rows = sqlx::query_as!(
+            NoteRow,
             r#"
-            select note_id as "note_id: Hyphenated", body, created_at
-            from note
+            select note_id as "note_id: Hyphenated", body, created_at, html, renderer_version,
+                   preview_url, preview_title, preview_description, preview_site_name,
+                   preview_image_id as "preview_image_id: Hyphenated", preview_embed_src,
+                   preview_embed_width, preview_embed_height
+            from note_with_preview
             order by created_at desc
             limit ?
             "#,
             n
         )
         .fetch_all(&self.db)
-        .await
+        .await?;
+        self.hydrate_all(rows).await
+    }
+
+    /// Returns the total number of notes.
+    pub async fn count(&self) -> Result<i64, sqlx::Error> {
+        sqlx::query_scalar!(r#"select count(*) as "n: i64" from note"#).fetch_one(&self.db).await
     }
 
+    /// Returns all notes, oldest first.
+    pub async fn all(&self) -> Result<Vec<Note>, sqlx::Error> {
+        let rows = sqlx::query_as!(
+            NoteRow,
+            r#"
+            select note_id as "note_id: Hyphenated", body, created_at, html, renderer_version,
+                   preview_url, preview_title, preview_description, preview_site_name,
+                   preview_image_id as "preview_image_id: Hyphenated", preview_embed_src,
+                   preview_embed_width, preview_embed_height
+            from note_with_preview
+            order by created_at
+            "#
+        )
+        .fetch_all(&self.db)
+        .await?;
+        self.hydrate_all(rows).await
+    }
+
+    /// Returns the `n` most recent notes created before the given cursor, if any, in reverse
+    /// chronological order.
+    pub async fn before(
+        &self,
+        cursor: Option<&(NaiveDateTime, Hyphenated)>,
+        n: u16,
+    ) -> Result<Vec<Note>, sqlx::Error> {
+        let created_at = cursor.map(|c| c.0);
+        let note_id = cursor.map(|c| c.1);
+        let rows = sqlx::query_as!(
+            NoteRow,
+            r#"
+            select note_id as "note_id!: Hyphenated", body as "body!", created_at as "created_at!",
+                   html, renderer_version,
+                   preview_url, preview_title, preview_description, preview_site_name,
+                   preview_image_id as "preview_image_id: Hyphenated", preview_embed_src,
+                   preview_embed_width, preview_embed_height
+            from note_with_preview
+            where ? is null or (created_at, note_id) < (?, ?)
+            order by created_at desc, note_id desc
+            limit ?
+            "#,
+            created_at,
+            created_at,
+            note_id,
+            n
+        )
+        .fetch_all(&self.db)
+        .await?;
+        self.hydrate_all(rows).await
+    }
+
+    /// Returns the notes created in the given range of dates in the given time zone, newest first.
     pub async fn date_range(
         &self,
         range: Range<NaiveDate>,
+        tz: Tz,
     ) -> Result<Option<Vec<Note>>, sqlx::Error> {
-        let start = local_date_to_utc(&range.start);
-        let end = local_date_to_utc(&range.end);
-        sqlx::query_as!(
-            Note,
+        // Bind naive timestamps so they compare correctly with the stored ones.
+        let start = local_date_to_utc(&range.start, tz).naive_utc();
+        let end = local_date_to_utc(&range.end, tz).naive_utc();
+        let rows = sqlx::query_as!(
+            NoteRow,
             r#"
-            select note_id as "note_id: Hyphenated", body, created_at
-            from note
+            select note_id as "note_id: Hyphenated", body, created_at, html, renderer_version,
+                   preview_url, preview_title, preview_description, preview_site_name,
+                   preview_image_id as "preview_image_id: Hyphenated", preview_embed_src,
+                   preview_embed_width, preview_embed_height
+            from note_with_preview
             where created_at >= ? and created_at < ?
             order by created_at desc
             "#,
@@ -71,29 +207,241 @@ impl NoteService {
             end,
         )
         .fetch_all(&self.db)
-        .await
-        .map(Some)
+        .await?;
+        self.hydrate_all(rows).await.map(Some)
+    }
+
+    /// Returns the first days of the closest months, in the given time zone, with notes created
+    /// after and before the given range of dates, if any.
+    pub async fn adjacent_months(
+        &self,
+        range: Range<NaiveDate>,
+        tz: Tz,
+    ) -> Result<(Option<NaiveDate>, Option<NaiveDate>), sqlx::Error> {
+        let start = local_date_to_utc(&range.start, tz).naive_utc();
+        let end = local_date_to_utc(&range.end, tz).naive_utc();
+        let newer = sqlx::query_scalar!(
+            r#"select min(created_at) as "created_at?: NaiveDateTime" from note where created_at >= ?"#,
+            end
+        )
+        .fetch_one(&self.db)
+        .await?;
+        let older = sqlx::query_scalar!(
+            r#"select max(created_at) as "created_at?: NaiveDateTime" from note where created_at < ?"#,
+            start
+        )
+        .fetch_one(&self.db)
+        .await?;
+        let month = |t: NaiveDateTime| tz.from_utc_datetime(&t).date_naive().with_day(1);
+        Ok((newer.and_then(month), older.and_then(month)))
+    }
+
+    /// The version of the renderer and sanitizer configuration used to render notes' HTML.
+    fn renderer_version(&self) -> String {
+        format!("{RENDERER_VERSION}:{}", self.sanitizer.iframe_hosts.join(","))
+    }
+
+    /// Returns the note with its cached HTML, first re-rendering and storing it if it's missing or
+    /// was rendered by a different renderer version.
+    async fn hydrate(&self, row: NoteRow) -> Result<Note, sqlx::Error> {
+        let renderer_version = self.renderer_version();
+        let html = match row.html {
+            Some(html) if row.renderer_version.as_ref() == Some(&renderer_version) => html,
+            _ => {
+                tracing::debug!(note_id = %row.note_id, "re-rendering note");
+                let html = render(&row.note_id, &row.body, &self.sanitizer);
+                sqlx::query!(
+                    r"update note set html = ?, renderer_version = ? where note_id = ?",
+                    html,
+                    renderer_version,
+                    row.note_id
+                )
+                .execute(&self.db)
+                .await?;
+                html
+            }
+        };
+        let preview = match (row.preview_url, row.preview_title) {
+            (Some(url), Some(title)) => Some(LinkPreview {
+                url,
+                title,
+                description: row.preview_description,
+                site_name: row.preview_site_name,
+                image_id: row.preview_image_id,
+                embed: match (
+                    row.preview_embed_src,
+                    row.preview_embed_width,
+                    row.preview_embed_height,
+                ) {
+                    (Some(src), Some(width), Some(height))
+                        if self.sanitizer.allows_iframe(&src) =>
+                    {
+                        Some(Embed { src, width, height })
+                    }
+                    _ => None,
+                },
+            }),
+            _ => None,
+        };
+        Ok(Note { note_id: row.note_id, body: row.body, created_at: row.created_at, html, preview })
+    }
+
+    async fn hydrate_all(&self, rows: Vec<NoteRow>) -> Result<Vec<Note>, sqlx::Error> {
+        let mut notes = Vec::with_capacity(rows.len());
+        for row in rows {
+            notes.push(self.hydrate(row).await?);
+        }
+        Ok(notes)
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Note {
     pub note_id: Hyphenated,
     pub body: String,
     pub created_at: NaiveDateTime,
+    /// The note's body, rendered as sanitized HTML.
+    pub html: String,
+    /// A card previewing the note's link, if it's just a link.
+    pub preview: Option<LinkPreview>,
+}
+
+/// A note as stored, with its cached HTML and the renderer version which produced it, if any, and
+/// its link preview, if any.
+struct NoteRow {
+    note_id: Hyphenated,
+    body: String,
+    created_at: NaiveDateTime,
+    html: Option<String>,
+    renderer_version: Option<String>,
+    preview_url: Option<String>,
+    preview_title: Option<String>,
+    preview_description: Option<String>,
+    preview_site_name: Option<String>,
+    preview_image_id: Option<Hyphenated>,
+    preview_embed_src: Option<String>,
+    preview_embed_width: Option<i64>,
+    preview_embed_height: Option<i64>,
+}
+
+/// An allow-list HTML sanitizer for rendered notes, which removes scripts, event handlers,
+/// `javascript:` URLs, and the like. Iframes are only allowed from the given hosts, via HTTPS. Only
+/// the IDs and classes the renderer uses are kept, so raw HTML can't clobber the page's scripts or
+/// other notes' anchors.
+#[derive(Debug, Clone)]
+pub struct Sanitizer {
+    builder: Arc<ammonia::Builder<'static>>,
+    iframe_hosts: Vec<String>,
 }
 
-impl Note {
-    pub fn to_html(&self) -> String {
-        render_markdown(&self.body)
+impl Sanitizer {
+    pub fn new(iframe_hosts: &[String]) -> Sanitizer {
+        let iframe_hosts = iframe_hosts
+            .iter()
+            .map(|h| h.to_ascii_lowercase())
+            .collect::<BTreeSet<String>>()
+            .into_iter()
+            .collect::<Vec<String>>();
+        let allowed_hosts = iframe_hosts.clone();
+
+        let mut builder = ammonia::Builder::default();
+        builder
+            // Keep heading and footnote anchors and the classes of highlighted code.
+            .add_generic_attributes(&["id", "class"])
+            // Keep task list checkboxes.
+            .add_tags(&["input"])
+            .add_tag_attributes("input", &["checked", "disabled"])
+            .add_tag_attribute_values("input", "type", &["checkbox"]);
+        if !iframe_hosts.is_empty() {
+            builder.add_tags(&["iframe"]).add_tag_attributes(
+                "iframe",
+                &["src", "width", "height", "title", "allow", "allowfullscreen", "frameborder"],
+            );
+        }
+        builder.attribute_filter(move |element, attribute, value| match (element, attribute) {
+            ("iframe", "src") => allows_iframe(&allowed_hosts, value).then(|| value.into()),
+            (_, "id") => is_anchor_id(value).then(|| value.into()),
+            (_, "class") => {
+                let classes = value.split_whitespace().filter(|c| is_rendered_class(c));
+                let classes = classes.collect::<Vec<&str>>();
+                (!classes.is_empty()).then(|| classes.join(" ").into())
+            }
+            _ => Some(value.into()),
+        });
+        Sanitizer { builder: Arc::new(builder), iframe_hosts }
+    }
+
+    /// Returns whether an iframe may load the given URL.
+    pub fn allows_iframe(&self, src: &str) -> bool {
+        allows_iframe(&self.iframe_hosts, src)
+    }
+
+    pub fn clean(&self, html: &str) -> String {
+        self.builder.clean(html).to_string()
     }
 }
 
-fn local_date_to_utc(d: &NaiveDate) -> DateTime<Utc> {
-    Local.from_local_datetime(&d.and_time(NaiveTime::default())).unwrap().with_timezone(&Utc)
+impl Default for Sanitizer {
+    fn default() -> Self {
+        Sanitizer::new(&[])
+    }
+}
+
+fn allows_iframe(hosts: &[String], src: &str) -> bool {
+    Url::parse(src).is_ok_and(|url| {
+        url.scheme() == "https"
+            && url.host_str().is_some_and(|host| hosts.iter().any(|h| h == host))
+    })
+}
+
+/// Returns `true` if `id` is one of the renderer's heading or footnote anchors, which are prefixed
+/// with the note's ID.
+fn is_anchor_id(id: &str) -> bool {
+    match (id.get(..36), id.get(36..)) {
+        (Some(note_id), Some(rest)) => Uuid::try_parse(note_id).is_ok() && rest.starts_with('-'),
+        _ => false,
+    }
+}
+
+/// Returns `true` if `class` is one the renderer uses, for highlighted code or footnotes.
+fn is_rendered_class(class: &str) -> bool {
+    class.starts_with(HIGHLIGHT_CLASS_PREFIX)
+        || class.starts_with("language-")
+        || matches!(
+            class,
+            "footnote-reference" | "footnote-definition" | "footnote-definition-label"
+        )
+}
+
+/// Returns the instant the given date starts in the given time zone.
+fn local_date_to_utc(d: &NaiveDate, tz: Tz) -> DateTime<Utc> {
+    // Some zones skip midnight when daylight saving time starts, starting the day an hour later.
+    let midnight = d.and_time(NaiveTime::default());
+    tz.from_local_datetime(&midnight)
+        .earliest()
+        .or_else(|| tz.from_local_datetime(&(midnight + Duration::hours(1))).earliest())
+        .expect("invalid local date")
+        .with_timezone(&Utc)
 }
 
-fn render_markdown(md: &str) -> String {
+/// Returns the first `n` characters of `s`, ending with an ellipsis if it's longer.
+pub fn excerpt(s: &str, n: usize) -> String {
+    if s.chars().count() <= n {
+        s.to_string()
+    } else {
+        let s = s.chars().take(n - 1).collect::<String>();
+        format!("{}…", s.trim_end())
+    }
+}
+
+/// Renders a note's Markdown body as sanitized HTML.
+fn render(note_id: &Hyphenated, body: &str, sanitizer: &Sanitizer) -> String {
+    sanitizer.clean(&render_markdown(body, &note_id.to_string()))
+}
+
+/// Renders Markdown as HTML, prefixing heading and footnote IDs with `id_prefix` so they're unique
+/// when several notes are on the same page.
+fn render_markdown(md: &str, id_prefix: &str) -> String {
     // Downgrade note headings to avoid having multiple H1s.
     fn downgrade_header(level: HeadingLevel) -> Option<HeadingLevel> {
         match level {
@@ -106,16 +454,37 @@ fn render_markdown(md: &str) -> String {
         }
     }
 
-    // Parse the note body as Markdown, downgrading headers.
-    let parser = Parser::new(md).map(|e| match e {
-        Event::Start(Tag::Heading(level, frag, classes)) => match downgrade_header(level) {
-            Some(level) => Event::Start(Tag::Heading(level, frag, classes)),
-            None => Event::Start(Tag::Strong),
-        },
+    // Parse the note body as Markdown with GitHub-style extensions.
+    let options = Options::ENABLE_TABLES
+        | Options::ENABLE_FOOTNOTES
+        | Options::ENABLE_STRIKETHROUGH
+        | Options::ENABLE_TASKLISTS
+        | Options::ENABLE_SMART_PUNCTUATION;
+    let events = highlight_code_blocks(Parser::new_ext(md, options));
+    let heading_ids = heading_ids(&events, id_prefix);
+    let mut heading_ids = heading_ids.iter().map(String::as_str);
+    let footnote_id = |label: &str| CowStr::from(format!("{id_prefix}-fn-{label}"));
+
+    // Downgrade headers, give them anchor IDs, and prefix footnote IDs.
+    let parser = events.into_iter().map(|e| match e {
+        Event::Start(Tag::Heading(level, _, classes)) => {
+            let id = heading_ids.next();
+            match downgrade_header(level) {
+                Some(level) => Event::Start(Tag::Heading(level, id, classes)),
+                None => Event::Start(Tag::Strong),
+            }
+        }
         Event::End(Tag::Heading(level, frag, classes)) => match downgrade_header(level) {
             Some(level) => Event::End(Tag::Heading(level, frag, classes)),
             None => Event::End(Tag::Strong),
         },
+        Event::FootnoteReference(label) => Event::FootnoteReference(footnote_id(&label)),
+        Event::Start(Tag::FootnoteDefinition(label)) => {
+            Event::Start(Tag::FootnoteDefinition(footnote_id(&label)))
+        }
+        Event::End(Tag::FootnoteDefinition(label)) => {
+            Event::End(Tag::FootnoteDefinition(footnote_id(&label)))
+        }
         e => e,
     });
 
@@ -125,16 +494,123 @@ fn render_markdown(md: &str) -> String {
     out
 }
 
+/// The prefix for the CSS classes of highlighted code, styled by `assets/css/highlight.css`.
+const HIGHLIGHT_CLASS_PREFIX: &str = "hl-";
+
+const HIGHLIGHT_CLASS_STYLE: ClassStyle =
+    ClassStyle::SpacedPrefixed { prefix: HIGHLIGHT_CLASS_PREFIX };
+
+/// Replaces fenced code blocks in a known language with HTML, highlighted using CSS classes.
+fn highlight_code_blocks<'a>(events: impl Iterator<Item = Event<'a>>) -> Vec<Event<'a>> {
+    static SYNTAXES: OnceLock<SyntaxSet> = OnceLock::new();
+    let syntaxes = SYNTAXES.get_or_init(SyntaxSet::load_defaults_newlines);
+
+    let mut out = Vec::new();
+    let mut block = None::<(Event, Vec<Event>)>;
+    for e in events {
+        match (e, &mut block) {
+            (e @ Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(_))), None) => {
+                block = Some((e, Vec::new()));
+            }
+            (Event::End(Tag::CodeBlock(CodeBlockKind::Fenced(info))), Some(_)) => {
+                let (start, contents) = block.take().expect("missing code block");
+                let lang = info.split_whitespace().next().unwrap_or_default();
+                let code = contents
+                    .iter()
+                    .map(|e| match e {
+                        Event::Text(s) => s.as_ref(),
+                        _ => "",
+                    })
+                    .collect::<String>();
+                match highlight(syntaxes, lang, &code) {
+                    Some(html) => out.push(Event::Html(html.into())),
+                    None => {
+                        out.push(start);
+                        out.extend(contents);
+                        out.push(Event::End(Tag::CodeBlock(CodeBlockKind::Fenced(info))));
+                    }
+                }
+            }
+            (e, Some((_, contents))) => contents.push(e),
+            (e, None) => out.push(e),
+        }
+    }
+    out
+}
+
+/// Highlights `code` as the language with the given name or file extension, if it's known.
+fn highlight(syntaxes: &SyntaxSet, lang: &str, code: &str) -> Option<String> {
+    let syntax = syntaxes.find_syntax_by_token(lang)?;
+    let mut html =
+        ClassedHTMLGenerator::new_with_class_style(syntax, syntaxes, HIGHLIGHT_CLASS_STYLE);
+    for line in LinesWithEndings::from(code) {
+        if let Err(err) = html.parse_html_for_line_which_includes_newline(line) {
+            tracing::warn!(%err, lang, "error highlighting code");
+            return None;
+        }
+    }
+    let mut out = String::from("<pre><code class=\"language-");
+    escape_html(&mut out, lang).ok()?;
+    out.push_str("\">");
+    out.push_str(&html.finalize());
+    out.push_str("</code></pre>\n");
+    Some(out)
+}
+
+/// Returns an anchor ID for each heading, in order, made from its text. Duplicate IDs get a
+/// numeric suffix.
+fn heading_ids(events: &[Event], id_prefix: &str) -> Vec<String> {
+    let mut ids = Vec::new();
+    let mut seen = HashMap::<String, usize>::new();
+    let mut text = None::<String>;
+    for e in events {
+        match e {
+            Event::Start(Tag::Heading(..)) => text = Some(String::new()),
+            Event::Text(s) | Event::Code(s) => {
+                if let Some(text) = &mut text {
+                    text.push_str(s);
+                }
+            }
+            Event::End(Tag::Heading(..)) => {
+                let mut slug = slugify(&text.take().unwrap_or_default());
+                let n = seen.entry(slug.clone()).or_default();
+                if *n > 0 {
+                    slug = format!("{slug}-{n}");
+                }
+                *n += 1;
+                ids.push(format!("{id_prefix}-{slug}"));
+            }
+            _ => {}
+        }
+    }
+    ids
+}
+
+/// Lowercases the alphanumeric characters of `s`, joining runs of them with hyphens.
+fn slugify(s: &str) -> String {
+    let slug = s
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(str::to_lowercase)
+        .collect::<Vec<String>>()
+        .join("-");
+    if slug.is_empty() {
+        "section".into()
+    } else {
+        slug
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use syntect::highlighting::ThemeSet;
+    use syntect::html::css_for_theme_with_class_style;
     use uuid::Uuid;
 
     use super::*;
     #[test]
     fn render_markdown() {
-        let note = Note {
-            note_id: Uuid::new_v4().hyphenated(),
-            body: r#"
+        let body = r#"
 # This is a heading.
 ## This is a subheading.
 ### This is a sub-sub-heading.
@@ -142,21 +618,266 @@ mod tests {
 ##### This is a nitpick.
 ###### Unclear.
             "#
-            .trim()
-            .into(),
-            created_at: Local::now().naive_local(),
-        };
+        .trim();
 
+        let prefix = "00000000-0000-0000-0000-000000000000";
         assert_eq!(
-            r#"
-<h2>This is a heading.</h2>
-<h3>This is a subheading.</h3>
-<h4>This is a sub-sub-heading.</h4>
-<h5>This is a section heading?</h5>
-<h6>This is a nitpick.</h6>
+            format!(
+                r#"
+<h2 id="{prefix}-this-is-a-heading">This is a heading.</h2>
+<h3 id="{prefix}-this-is-a-subheading">This is a subheading.</h3>
+<h4 id="{prefix}-this-is-a-sub-sub-heading">This is a sub-sub-heading.</h4>
+<h5 id="{prefix}-this-is-a-section-heading">This is a section heading?</h5>
+<h6 id="{prefix}-this-is-a-nitpick">This is a nitpick.</h6>
 <strong>Unclear.</strong>"#
-                .trim(),
-            note.to_html()
+            )
+            .trim(),
+            render(&Uuid::nil().hyphenated(), body, &Sanitizer::default())
         );
     }
+
+    #[test]
+    fn render_markdown_extensions() {
+        let html = super::render_markdown(
+            r#"
+| Plumber | Color |
+|---------|-------|
+| Mario   | Red   |
+
+~~Bowser~~ is "nice" -- sometimes...
+
+- [x] Save the princess
+- [ ] Eat a mushroom
+            "#
+            .trim(),
+            "n",
+        );
+
+        assert_eq!(
+            html,
+            "<table><thead><tr><th>Plumber</th><th>Color</th></tr></thead><tbody>\n\
+             <tr><td>Mario</td><td>Red</td></tr>\n\
+             </tbody></table>\n\
+             <p><del>Bowser</del> is \u{201c}nice\u{201d} \u{2013} sometimes\u{2026}</p>\n\
+             <ul>\n\
+             <li><input disabled=\"\" type=\"checkbox\" checked=\"\"/>\nSave the princess</li>\n\
+             <li><input disabled=\"\" type=\"checkbox\"/>\nEat a mushroom</li>\n\
+             </ul>\n"
+        );
+    }
+
+    #[test]
+    fn render_markdown_ids() {
+        let md = "# Wahoo\n\nIt's a me.[^1]\n\n# Wahoo\n\n[^1]: Mario.";
+        let first = super::render_markdown(md, "a");
+        let second = super::render_markdown(md, "b");
+
+        // Footnotes and headings are prefixed, and duplicate headings are numbered.
+        assert!(first.contains(r##"<h2 id="a-wahoo">Wahoo</h2>"##));
+        assert!(first.contains(r##"<h2 id="a-wahoo-1">Wahoo</h2>"##));
+        assert!(first.contains(r##"<a href="#a-fn-1">1</a>"##));
+        assert!(first.contains(r##"<div class="footnote-definition" id="a-fn-1">"##));
+        assert!(second.contains(r##"<a href="#b-fn-1">1</a>"##));
+        assert!(second.contains(r##"<div class="footnote-definition" id="b-fn-1">"##));
+    }
+
+    #[test]
+    fn highlighting_code() {
+        let html = super::render_markdown("```rust\nfn main() {}\n```\n\n```nope\n<wat>\n```", "n");
+        assert!(html
+            .starts_with(r#"<pre><code class="language-rust"><span class="hl-source hl-rust">"#));
+        assert!(html.contains(r#"<span class="hl-storage hl-type hl-function hl-rust">fn</span>"#));
+        assert!(html.ends_with("<pre><code class=\"language-nope\">&lt;wat&gt;\n</code></pre>\n"));
+    }
+
+    #[test]
+    fn highlight_stylesheet() -> Result<(), anyhow::Error> {
+        let themes = ThemeSet::load_defaults();
+        let css = |name: &str| {
+            css_for_theme_with_class_style(&themes.themes[name], HIGHLIGHT_CLASS_STYLE)
+        };
+        let css = format!(
+            "{}\n@media (prefers-color-scheme: dark) {{\n{}}}\n",
+            css("InspiredGitHub")?,
+            css("base16-ocean.dark")?
+        );
+
+        // Regenerate the stylesheet with `UPDATE_HIGHLIGHT_CSS=1 cargo test`.
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/assets/css/highlight.css");
+        if std::env::var_os("UPDATE_HIGHLIGHT_CSS").is_some() {
+            std::fs::write(path, &css)?;
+        }
+        assert_eq!(std::fs::read_to_string(path)?, css, "highlight.css is out of date");
+
+        Ok(())
+    }
+
+    #[test]
+    fn sanitizing_html() {
+        let sanitizer = Sanitizer::default();
+        let note = |body: &str| render(&Uuid::nil().hyphenated(), body, &sanitizer);
+
+        let html = note("Hi.<script>alert('pwned')</script>");
+        assert_eq!(html, "<p>Hi.</p>\n");
+
+        let html = note(r#"<img src="/images/a.webp" onerror="alert(1)">"#);
+        assert_eq!(html, r#"<img src="/images/a.webp">"#);
+
+        let html = note("[click](javascript:alert(1)) <a href=\"JavaScript:alert(1)\">me</a>");
+        assert_eq!(html, "<p><a rel=\"noopener noreferrer\">click</a> <a rel=\"noopener noreferrer\">me</a></p>\n");
+
+        // Task lists, anchors, and highlighting survive.
+        let html = note("# Hi\n\n- [x] done\n\n```rust\nfn x() {}\n```");
+        assert!(html.contains(r#"<h2 id="00000000-0000-0000-0000-000000000000-hi">"#));
+        assert!(html.contains(r#"<input disabled="" type="checkbox" checked="">"#));
+        assert!(html.contains(r#"<span class="hl-source hl-rust">"#));
+    }
+
+    #[test]
+    fn sanitizing_ids() {
+        let note_id = Uuid::nil().hyphenated();
+        let note = |body: &str| render(&note_id, body, &Sanitizer::default());
+
+        // Only heading and footnote anchors prefixed with a note ID are kept.
+        let html = note("# Hi\n\nWahoo.[^1]\n\n[^1]: Yes.");
+        assert!(html.contains(r#"<h2 id="00000000-0000-0000-0000-000000000000-hi">"#));
+        assert!(html.contains(r#"id="00000000-0000-0000-0000-000000000000-fn-1""#));
+
+        let html = note(r#"<p id="embed">x</p><img id="location" src="/a.webp"><p id="hi">y</p>"#);
+        assert_eq!(html, r#"<p>x</p><img src="/a.webp"><p>y</p>"#);
+    }
+
+    #[test]
+    fn sanitizing_classes() {
+        let note_id = Uuid::nil().hyphenated();
+        let note = |body: &str| render(&note_id, body, &Sanitizer::default());
+
+        // Only the classes of highlighted code and footnotes are kept.
+        let html = note("```rust\nfn x() {}\n```\n\nWahoo.[^1]\n\n[^1]: Yes.");
+        assert!(
+            html.contains(r#"<pre><code class="language-rust"><span class="hl-source hl-rust">"#)
+        );
+        assert!(html.contains(r#"<sup class="footnote-reference">"#));
+        assert!(html.contains(r#"<div class="footnote-definition""#));
+
+        let html = note(r#"<p class="link-preview hl-source">x</p><p class="admin">y</p>"#);
+        assert_eq!(html, r#"<p class="hl-source">x</p><p>y</p>"#);
+    }
+
+    #[test]
+    fn sanitizing_iframes() {
+        let body = r#"<iframe src="https://www.youtube-nocookie.com/embed/x" width="560"></iframe>
+<iframe src="https://evil.example.com/embed/x"></iframe>
+<iframe src="http://www.youtube-nocookie.com/embed/x"></iframe>"#;
+        let note_id = Uuid::nil().hyphenated();
+
+        let html = render(&note_id, body, &Sanitizer::default());
+        assert!(!html.contains("iframe"));
+
+        let html = render(&note_id, body, &Sanitizer::new(&["www.YouTube-nocookie.com".into()]));
+        assert_eq!(
+            html,
+            "<iframe src=\"https://www.youtube-nocookie.com/embed/x\" width=\"560\"></iframe>\n\
+             <iframe></iframe>\n<iframe></iframe>"
+        );
+    }
+
+    #[test]
+    fn excerpts() {
+        assert_eq!(excerpt("hello", 10), "hello");
+        assert_eq!(excerpt("hello, world", 6), "hello…");
+        assert_eq!(excerpt("hello, world", 7), "hello,…");
+        assert_eq!(excerpt("hello world", 7), "hello…");
+    }
+
+    #[test]
+    fn slugs() {
+        assert_eq!(slugify("It’s a me, Mario!"), "it-s-a-me-mario");
+        assert_eq!(slugify("Über `code`"), "über-code");
+        assert_eq!(slugify("???"), "section");
+    }
+
+    #[test]
+    fn local_dates_across_dst() {
+        let utc = |s: &str| NaiveDateTime::parse_from_str(s, "%F %R").unwrap();
+        let start =
+            |s: &str, tz: Tz| local_date_to_utc(&s.parse::<NaiveDate>().unwrap(), tz).naive_utc();
+
+        // New York is UTC-4 in summer and UTC-5 in winter.
+        let ny = chrono_tz::America::New_York;
+        assert_eq!(start("2022-11-01", ny), utc("2022-11-01 04:00"));
+        assert_eq!(start("2022-11-06", ny), utc("2022-11-06 04:00"));
+        assert_eq!(start("2022-11-07", ny), utc("2022-11-07 05:00"));
+        assert_eq!(start("2022-12-01", ny), utc("2022-12-01 05:00"));
+
+        // Santiago skipped from midnight to 1am when DST started in 2021.
+        let santiago = chrono_tz::America::Santiago;
+        assert_eq!(start("2021-09-04", santiago), utc("2021-09-04 04:00"));
+        assert_eq!(start("2021-09-05", santiago), utc("2021-09-05 04:00"));
+        assert_eq!(start("2021-09-06", santiago), utc("2021-09-06 03:00"));
+    }
+
+    #[sqlx::test]
+    async fn date_ranges_across_dst(db: SqlitePool) -> Result<(), anyhow::Error> {
+        let notes = NoteService::new(db);
+
+        // 11:30pm on Oct 31st and Nov 30th in New York, in UTC.
+        for (id, created_at) in [(1, "2022-11-01 03:30"), (2, "2022-12-01 04:30")] {
+            let note_id = Uuid::from_u128(id).hyphenated();
+            let created_at = NaiveDateTime::parse_from_str(created_at, "%F %R")?;
+            notes.import(&note_id, &note_id.to_string(), &created_at).await?;
+        }
+
+        let november = |tz| {
+            let notes = notes.clone();
+            async move {
+                let start = NaiveDate::from_ymd_opt(2022, 11, 1).unwrap();
+                let end = NaiveDate::from_ymd_opt(2022, 12, 1).unwrap();
+                let notes = notes.date_range(start..end, tz).await?.unwrap_or_default();
+                Ok::<_, sqlx::Error>(notes.into_iter().map(|n| n.body).collect::<Vec<String>>())
+            }
+        };
+        assert_eq!(
+            november(chrono_tz::America::New_York).await?,
+            vec!["00000000-0000-0000-0000-000000000002"]
+        );
+        assert_eq!(november(chrono_tz::UTC).await?, vec!["00000000-0000-0000-0000-000000000001"]);
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn caching_html(db: SqlitePool) -> Result<(), anyhow::Error> {
+        let notes = NoteService::new(db.clone());
+        let body = r#"It's a me, <iframe src="https://example.com/"></iframe>"#;
+        let note_id = notes.create(body).await?;
+
+        // Notes are rendered when they're created.
+        let (html, version) =
+            sqlx::query_as::<_, (String, String)>("select html, renderer_version from note")
+                .fetch_one(&db)
+                .await?;
+        assert_eq!(html, "<p>It\u{2019}s a me, </p>\n");
+        assert_eq!(version, format!("{RENDERER_VERSION}:"));
+
+        // Stale HTML is re-rendered and stored when read.
+        sqlx::query("update note set html = 'stale', renderer_version = '0:'").execute(&db).await?;
+        assert_eq!(notes.by_id(&note_id).await?.unwrap().html, "<p>It\u{2019}s a me, </p>\n");
+        let html = sqlx::query_scalar::<_, String>("select html from note").fetch_one(&db).await?;
+        assert_eq!(html, "<p>It\u{2019}s a me, </p>\n");
+
+        // Changing the sanitizer's configuration re-renders notes, too.
+        let notes = notes.with_sanitizer(Sanitizer::new(&["example.com".into()]));
+        let note = notes.most_recent(1).await?.pop().unwrap();
+        assert_eq!(
+            note.html,
+            "<p>It\u{2019}s a me, <iframe src=\"https://example.com/\"></iframe></p>\n"
+        );
+        let version = sqlx::query_scalar::<_, String>("select renderer_version from note")
+            .fetch_one(&db)
+            .await?;
+        assert_eq!(version, format!("{RENDERER_VERSION}:example.com"));
+
+        Ok(())
+    }
 }
@@ -0,0 +1,201 @@
+use anyhow::Context;
+use scraper::{Html, Selector};
+use serde::Deserialize;
+use serde_json::Value;
+use sqlx::SqlitePool;
+use url::Url;
+
+/// How long to cache oEmbed responses which don't say, in seconds.
+const DEFAULT_CACHE_AGE: i64 = 24 * 60 * 60;
+
+/// An embedded player, loaded only when clicked so that visitors don't load anything from the
+/// provider until they choose to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Embed {
+    /// The HTTPS URL of the provider's iframe.
+    pub src: String,
+    pub width: i64,
+    pub height: i64,
+}
+
+/// The parts of an oEmbed response used for preview cards.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct OEmbed {
+    pub title: Option<String>,
+    pub provider_name: Option<String>,
+    pub thumbnail_url: Option<Url>,
+    pub embed: Option<Embed>,
+}
+
+/// Returns the URL of the page's JSON oEmbed endpoint, if it advertises one.
+pub fn discover(doc: &Html, base: &Url) -> Option<Url> {
+    let selector = Selector::parse(r#"link[rel~="alternate"][type="application/json+oembed"]"#)
+        .expect("invalid selector");
+    let href = doc.select(&selector).find_map(|el| el.value().attr("href"))?;
+    base.join(href.trim()).ok().filter(|u| matches!(u.scheme(), "http" | "https"))
+}
+
+/// Returns the response of the given oEmbed endpoint, from the cache if it hasn't expired.
+pub async fn fetch(
+    db: &SqlitePool,
+    client: &reqwest::Client,
+    endpoint: &Url,
+) -> Result<OEmbed, anyhow::Error> {
+    let url = endpoint.to_string();
+    let cached = sqlx::query_scalar!(
+        r"select response from oembed where url = ? and expires_at > current_timestamp",
+        url
+    )
+    .fetch_optional(db)
+    .await?;
+    if let Some(response) = cached {
+        return Ok(parse(&response)?.1);
+    }
+
+    let response = client
+        .get(endpoint.clone())
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())
+        .context("error fetching oEmbed response")?
+        .text()
+        .await
+        .context("error reading oEmbed response")?;
+    let (cache_age, oembed) = parse(&response)?;
+
+    let expires_in = format!("+{} seconds", cache_age.unwrap_or(DEFAULT_CACHE_AGE).max(0));
+    sqlx::query!(
+        r"
+        insert into oembed (url, response, expires_at)
+        values (?, ?, datetime('now', ?))
+        on conflict (url) do update
+        set response = excluded.response, fetched_at = current_timestamp,
+            expires_at = excluded.expires_at
+        ",
+        url,
+        response,
+        expires_in,
+    )
+    .execute(db)
+    .await?;
+    Ok(oembed)
+}
+
+/// The fields of an oEmbed response, some of which providers send as either numbers or strings.
+#[derive(Debug, Deserialize)]
+struct Response {
+    #[serde(rename = "type")]
+    kind: String,
+    title: Option<String>,
+    provider_name: Option<String>,
+    thumbnail_url: Option<String>,
+    html: Option<String>,
+    width: Option<Value>,
+    height: Option<Value>,
+    cache_age: Option<Value>,
+}
+
+/// Parses an oEmbed response, returning its cache age in seconds, if any. Only players which are
+/// an iframe loaded via HTTPS are embedded.
+fn parse(json: &str) -> Result<(Option<i64>, OEmbed), anyhow::Error> {
+    let response = serde_json::from_str::<Response>(json).context("invalid oEmbed response")?;
+    let embed = match (response.kind.as_str(), &response.html) {
+        ("video" | "rich", Some(html)) => {
+            let fragment = Html::parse_fragment(html);
+            let iframe = Selector::parse("iframe").expect("invalid selector");
+            fragment
+                .select(&iframe)
+                .next()
+                .and_then(|el| el.value().attr("src"))
+                .and_then(|src| Url::parse(src).ok())
+                .filter(|src| src.scheme() == "https")
+                .map(|src| Embed {
+                    src: src.to_string(),
+                    width: response.width.as_ref().and_then(integer).unwrap_or(560),
+                    height: response.height.as_ref().and_then(integer).unwrap_or(315),
+                })
+        }
+        _ => None,
+    };
+    let oembed = OEmbed {
+        title: response.title.filter(|t| !t.trim().is_empty()),
+        provider_name: response.provider_name.filter(|p| !p.trim().is_empty()),
+        thumbnail_url: response
+            .thumbnail_url
+            .and_then(|u| Url::parse(&u).ok())
+            .filter(|u| matches!(u.scheme(), "http" | "https")),
+        embed,
+    };
+    Ok((response.cache_age.as_ref().and_then(integer), oembed))
+}
+
+fn integer(v: &Value) -> Option<i64> {
+    match v {
+        Value::Number(n) => n.as_i64().or_else(|| n.as_f64().map(|f| f as i64)),
+        Value::String(s) => s.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn discovering_endpoints() {
+        let base = "https://example.com/watch?v=1".parse::<Url>().unwrap();
+        let doc = Html::parse_document(
+            r#"<link rel="alternate" type="application/json+oembed" href="/oembed?url=x&amp;format=json">
+            <link rel="alternate" type="text/xml+oembed" href="/oembed?format=xml">"#,
+        );
+        assert_eq!(
+            discover(&doc, &base).map(|u| u.to_string()).as_deref(),
+            Some("https://example.com/oembed?url=x&format=json")
+        );
+        assert_eq!(discover(&Html::parse_document("<title>Nope</title>"), &base), None);
+    }
+
+    #[test]
+    fn parsing_responses() -> Result<(), anyhow::Error> {
+        let (cache_age, oembed) = parse(
+            r#"{
+                "type": "video",
+                "version": "1.0",
+                "title": "Wahoo",
+                "provider_name": "PipeTube",
+                "thumbnail_url": "https://img.example.com/1.jpg",
+                "html": "<iframe width=\"480\" src=\"https://player.example.com/embed/1\"></iframe>",
+                "width": 480,
+                "height": "270",
+                "cache_age": "3600"
+            }"#,
+        )?;
+        assert_eq!(cache_age, Some(3600));
+        assert_eq!(
+            oembed,
+            OEmbed {
+                title: Some("Wahoo".into()),
+                provider_name: Some("PipeTube".into()),
+                thumbnail_url: Some("https://img.example.com/1.jpg".parse()?),
+                embed: Some(Embed {
+                    src: "https://player.example.com/embed/1".into(),
+                    width: 480,
+                    height: 270
+                }),
+            }
+        );
+
+        // Scripts and plain HTTP iframes aren't embedded.
+        let (_, oembed) = parse(
+            r#"{"type": "rich", "html": "<blockquote>Hi</blockquote><script src=\"https://x.example/w.js\"></script>"}"#,
+        )?;
+        assert_eq!(oembed.embed, None);
+        let (_, oembed) =
+            parse(r#"{"type": "video", "html": "<iframe src=\"http://player.example.com/1\">"}"#)?;
+        assert_eq!(oembed.embed, None);
+
+        assert!(parse("<html>").is_err());
+
+        Ok(())
+    }
+}
@@ -1,3 +1,6 @@
+use std::time::Duration;
+
+use chrono::NaiveDateTime;
 use constant_time_eq::constant_time_eq;
 use p256::ecdsa::signature::Verifier;
 use p256::ecdsa::{Signature, VerifyingKey};
@@ -31,6 +34,58 @@ impl PasskeyService {
             .map(|r| r.n > 0)
     }
 
+    /// Returns all registered passkeys, most recently registered first.
+    pub async fn all(&self) -> Result<Vec<Passkey>, sqlx::Error> {
+        sqlx::query_as!(
+            Passkey,
+            r"select passkey_id, created_at from passkey order by created_at desc, passkey_id"
+        )
+        .fetch_all(&self.db)
+        .await
+    }
+
+    /// Revokes the passkey with the given ID, returning `true` if it existed.
+    pub async fn revoke(&self, passkey_id: &[u8]) -> Result<bool, sqlx::Error> {
+        tracing::info!(passkey_id = base64::encode(passkey_id), "revoking passkey");
+        let res = sqlx::query!(r"delete from passkey where passkey_id = ?", passkey_id)
+            .execute(&self.db)
+            .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    /// Creates a single-use recovery token which allows registering a new passkey without being
+    /// logged in, valid for the given duration. Returns the secret token value, which is only stored
+    /// as a hash.
+    pub async fn create_recovery_token(&self, ttl: Duration) -> Result<String, sqlx::Error> {
+        let token = hex::encode(thread_rng().gen::<[u8; 32]>());
+        let token_hash = Sha256::new().chain_update(&token).finalize().to_vec();
+        let expires_in = format!("+{} seconds", ttl.as_secs());
+        sqlx::query!(
+            r"insert into recovery_token (token_hash, expires_at) values (?, datetime('now', ?))",
+            token_hash,
+            expires_in,
+        )
+        .execute(&self.db)
+        .await?;
+        tracing::warn!(?ttl, "created passkey recovery token");
+        Ok(token)
+    }
+
+    /// Returns `true` if the given recovery token exists and hasn't expired.
+    pub async fn recovery_token_is_valid(&self, token: &str) -> Result<bool, sqlx::Error> {
+        let token_hash = Sha256::new().chain_update(token).finalize().to_vec();
+        sqlx::query!(
+            r"
+            select count(1) as n from recovery_token
+            where token_hash = ? and expires_at > current_timestamp
+            ",
+            token_hash
+        )
+        .fetch_one(&self.db)
+        .await
+        .map(|r| r.n > 0)
+    }
+
     pub async fn start_registration(
         &self,
         username: &str,
@@ -48,6 +103,55 @@ impl PasskeyService {
         &self,
         resp: RegistrationResponse,
     ) -> Result<(), anyhow::Error> {
+        let passkey_id = self.validate_registration(&resp)?;
+
+        // Insert the passkey ID and DER-encoded public key into the database.
+        sqlx::query!(
+            r"insert into passkey (passkey_id, public_key_spki) values (?, ?)",
+            passkey_id,
+            resp.public_key,
+        )
+        .execute(&self.db)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Registers a new passkey using the given recovery token, which is only redeemed if the passkey
+    /// is registered. Returns `false` if the token doesn't exist or has expired.
+    pub async fn finish_recovery_registration(
+        &self,
+        token: &str,
+        resp: RegistrationResponse,
+    ) -> Result<bool, anyhow::Error> {
+        let passkey_id = self.validate_registration(&resp)?;
+
+        let mut tx = self.db.begin().await?;
+        let token_hash = Sha256::new().chain_update(token).finalize().to_vec();
+        let res = sqlx::query!(
+            r"delete from recovery_token where token_hash = ? and expires_at > current_timestamp",
+            token_hash
+        )
+        .execute(&mut tx)
+        .await?;
+        if res.rows_affected() == 0 {
+            return Ok(false);
+        }
+
+        sqlx::query!(
+            r"insert into passkey (passkey_id, public_key_spki) values (?, ?)",
+            passkey_id,
+            resp.public_key,
+        )
+        .execute(&mut tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(true)
+    }
+
+    /// Validates a registration response, returning the new passkey's ID.
+    fn validate_registration(&self, resp: &RegistrationResponse) -> Result<Vec<u8>, anyhow::Error> {
         // Try decoding the P-256 public key from its DER encoding.
         VerifyingKey::from_public_key_der(&resp.public_key)?;
 
@@ -62,19 +166,8 @@ impl PasskeyService {
         }
 
         // Decode and validate the authenticator data.
-        let passkey_id = parse_authenticator_data(&resp.authenticator_data, &self.rp_id)?
-            .ok_or_else(|| anyhow::anyhow!("missing passkey id"))?;
-
-        // Insert the passkey ID and DER-encoded public key into the database.
-        sqlx::query!(
-            r"insert into passkey (passkey_id, public_key_spki) values (?, ?)",
-            passkey_id,
-            resp.public_key,
-        )
-        .execute(&self.db)
-        .await?;
-
-        Ok(())
+        parse_authenticator_data(&resp.authenticator_data, &self.rp_id)?
+            .ok_or_else(|| anyhow::anyhow!("missing passkey id"))
     }
 
     pub async fn start_authentication(&self) -> Result<AuthenticationChallenge, sqlx::Error> {
@@ -151,6 +244,12 @@ impl PasskeyService {
     }
 }
 
+#[derive(Debug)]
+pub struct Passkey {
+    pub passkey_id: Vec<u8>,
+    pub created_at: NaiveDateTime,
+}
+
 #[serde_as]
 #[derive(Debug, Serialize, Deserialize)]
 pub struct RegistrationChallenge {
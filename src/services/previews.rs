@@ -0,0 +1,585 @@
+use std::time::Duration;
+
+use anyhow::Context;
+use pulldown_cmark::escape::{escape_href, escape_html};
+use pulldown_cmark::{Event, Parser, Tag};
+use reqwest::header;
+use scraper::{Html, Selector};
+use sqlx::SqlitePool;
+use url::Url;
+use uuid::fmt::Hyphenated;
+
+use super::images::{main_filename, ImageService, IMAGES_DIR};
+use super::notes::{excerpt, Sanitizer};
+use super::oembed::{self, Embed, OEmbed};
+
+/// How long to wait for a linked page, and separately for its preview image, by default.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The most of a linked page which is read looking for its metadata.
+const MAX_PAGE_SIZE: usize = 1024 * 1024;
+
+/// The longest description shown on a card, in characters.
+const MAX_DESCRIPTION_LEN: usize = 280;
+
+/// Fetches and stores preview cards for notes which are just a link, using the linked page's
+/// OpenGraph or Twitter Card metadata. If the page advertises an oEmbed endpoint with a player,
+/// the card embeds it, as long as the sanitizer allows iframes from the player's host.
+#[derive(Debug, Clone)]
+pub struct PreviewService {
+    db: SqlitePool,
+    images: ImageService,
+    sanitizer: Sanitizer,
+    timeout: Duration,
+}
+
+impl PreviewService {
+    pub fn new(db: SqlitePool, images: ImageService) -> PreviewService {
+        PreviewService { db, images, sanitizer: Sanitizer::default(), timeout: FETCH_TIMEOUT }
+    }
+
+    /// Only embeds players from hosts the given sanitizer allows iframes from.
+    pub fn with_sanitizer(self, sanitizer: Sanitizer) -> PreviewService {
+        PreviewService { sanitizer, ..self }
+    }
+
+    /// Waits at most `timeout` for linked pages and their images instead of the default.
+    pub fn with_timeout(self, timeout: Duration) -> PreviewService {
+        PreviewService { timeout, ..self }
+    }
+
+    /// Fetches a preview card for the standalone link in the note's body, if any, replacing the
+    /// note's existing card. If the body has no standalone link or the page has no title, the
+    /// existing card is removed. If the page can't be fetched, the existing card is kept.
+    pub async fn refresh(
+        &self,
+        note_id: &Hyphenated,
+        body: &str,
+    ) -> Result<Option<LinkPreview>, anyhow::Error> {
+        let preview = match standalone_link(body) {
+            Some(url) => self.fetch(&url).await?,
+            None => None,
+        };
+        let Some(preview) = preview else {
+            self.remove(note_id).await?;
+            return Ok(None);
+        };
+
+        tracing::info!(%note_id, url = %preview.url, "storing link preview");
+        let embed_src = preview.embed.as_ref().map(|e| &e.src);
+        let embed_width = preview.embed.as_ref().map(|e| e.width);
+        let embed_height = preview.embed.as_ref().map(|e| e.height);
+        sqlx::query!(
+            r"
+            insert into link_preview (
+                note_id, url, title, description, site_name, image_id,
+                embed_src, embed_width, embed_height
+            )
+            values (?, ?, ?, ?, ?, ?, ?, ?, ?)
+            on conflict (note_id) do update
+            set url = excluded.url, title = excluded.title, description = excluded.description,
+                site_name = excluded.site_name, image_id = excluded.image_id,
+                embed_src = excluded.embed_src, embed_width = excluded.embed_width,
+                embed_height = excluded.embed_height, fetched_at = current_timestamp
+            ",
+            note_id,
+            preview.url,
+            preview.title,
+            preview.description,
+            preview.site_name,
+            preview.image_id,
+            embed_src,
+            embed_width,
+            embed_height,
+        )
+        .execute(&self.db)
+        .await?;
+        Ok(Some(preview))
+    }
+
+    /// Removes the note's preview card, returning `true` if it had one.
+    pub async fn remove(&self, note_id: &Hyphenated) -> Result<bool, sqlx::Error> {
+        let res = sqlx::query!(r"delete from link_preview where note_id = ?", note_id)
+            .execute(&self.db)
+            .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    async fn fetch(&self, url: &Url) -> Result<Option<LinkPreview>, anyhow::Error> {
+        let client = reqwest::Client::builder()
+            .timeout(self.timeout)
+            .user_agent(concat!("Yellhole/", env!("CARGO_PKG_VERSION")))
+            .build()?;
+
+        // Read the start of the linked page, if it's HTML.
+        let mut resp = client
+            .get(url.clone())
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .context("error fetching linked page")?;
+        let is_html = resp
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .map(|ct| ct.starts_with("text/html") || ct.starts_with("application/xhtml+xml"))
+            .unwrap_or(false);
+        if !is_html {
+            return Ok(None);
+        }
+        let page_url = resp.url().clone();
+        let mut page = Vec::new();
+        while let Some(chunk) = resp.chunk().await.context("error reading linked page")? {
+            page.extend_from_slice(&chunk);
+            if page.len() >= MAX_PAGE_SIZE {
+                break;
+            }
+        }
+
+        let metadata = Metadata::parse(&String::from_utf8_lossy(&page), &page_url);
+
+        // Use the page's oEmbed response, if any, for a player and a better thumbnail.
+        let oembed = match &metadata.oembed {
+            Some(endpoint) => match oembed::fetch(&self.db, &client, endpoint).await {
+                Ok(oembed) => oembed,
+                Err(err) => {
+                    tracing::warn!(?err, %endpoint, "unable to fetch oEmbed response");
+                    OEmbed::default()
+                }
+            },
+            None => OEmbed::default(),
+        };
+        let Some(title) = metadata.title.or(oembed.title) else {
+            return Ok(None);
+        };
+
+        // Download the preview image, if any, but don't let a bad image prevent the card.
+        let image_id = match oembed.thumbnail_url.or(metadata.image) {
+            Some(image_url) => {
+                match tokio::time::timeout(self.timeout, self.images.download(image_url)).await {
+                    Ok(Ok(image_id)) => Some(image_id),
+                    Ok(Err(err)) => {
+                        tracing::warn!(?err, "unable to download preview image");
+                        None
+                    }
+                    Err(_) => {
+                        tracing::warn!("timed out downloading preview image");
+                        None
+                    }
+                }
+            }
+            None => None,
+        };
+
+        Ok(Some(LinkPreview {
+            url: url.to_string(),
+            title,
+            description: metadata.description,
+            site_name: metadata.site_name.or(oembed.provider_name),
+            image_id,
+            embed: oembed.embed.filter(|embed| self.sanitizer.allows_iframe(&embed.src)),
+        }))
+    }
+}
+
+/// A card previewing a linked page.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LinkPreview {
+    pub url: String,
+    pub title: String,
+    pub description: Option<String>,
+    pub site_name: Option<String>,
+    pub image_id: Option<Hyphenated>,
+    pub embed: Option<Embed>,
+}
+
+impl LinkPreview {
+    /// The URI of the main version of the card's image, if it has one.
+    pub fn image_src(&self) -> Option<String> {
+        self.image_id.map(|id| format!("/{}/{}", IMAGES_DIR, main_filename(&id)))
+    }
+
+    /// Renders the card as HTML. Players are rendered as a link to the page which
+    /// `assets/js/embed.js` replaces with the player when clicked.
+    pub fn to_html(&self) -> String {
+        let mut href = String::new();
+        escape_href(&mut href, &self.url).expect("error escaping URL");
+        let img = |src: String| format!("<img src=\"{src}\" alt=\"\">");
+
+        let mut out = String::from("<figure class=\"link-preview\">\n");
+        match &self.embed {
+            Some(embed) => {
+                let mut src = String::new();
+                escape_href(&mut src, &embed.src).expect("error escaping URL");
+                out.push_str(&format!(
+                    "<a href=\"{href}\" data-embed-src=\"{src}\" data-embed-width=\"{}\" \
+                     data-embed-height=\"{}\">",
+                    embed.width, embed.height
+                ));
+                if let Some(src) = self.image_src() {
+                    out.push_str(&img(src));
+                    out.push_str("<br>");
+                }
+                out.push_str("\u{25b6} Load player from ");
+                let host = Url::parse(&embed.src).ok();
+                let host = host.as_ref().and_then(|u| u.host_str()).unwrap_or_default();
+                escape_html(&mut out, host).expect("error escaping host");
+                out.push_str("</a>\n");
+            }
+            None => {
+                if let Some(src) = self.image_src() {
+                    out.push_str(&format!("<a href=\"{href}\">{}</a>\n", img(src)));
+                }
+            }
+        }
+        out.push_str(&format!("<figcaption><a href=\"{href}\"><strong>"));
+        escape_html(&mut out, &self.title).expect("error escaping title");
+        out.push_str("</strong></a>");
+        if let Some(site_name) = &self.site_name {
+            out.push_str("<br><small>");
+            escape_html(&mut out, site_name).expect("error escaping site name");
+            out.push_str("</small>");
+        }
+        if let Some(description) = &self.description {
+            out.push_str("<br>");
+            escape_html(&mut out, description).expect("error escaping description");
+        }
+        out.push_str("</figcaption>\n</figure>\n");
+        out
+    }
+}
+
+/// Returns the URL of the first paragraph which is just an HTTP(S) link, if any.
+pub fn standalone_link(md: &str) -> Option<Url> {
+    let mut paragraph = None::<Vec<Event>>;
+    for e in Parser::new(md) {
+        match e {
+            Event::Start(Tag::Paragraph) => paragraph = Some(Vec::new()),
+            Event::End(Tag::Paragraph) => {
+                let events = paragraph.take().unwrap_or_default();
+                let links =
+                    events.iter().filter(|e| matches!(e, Event::End(Tag::Link(..)))).count();
+                let href = match (events.first(), events.last()) {
+                    // A link, e.g. `<https://example.com>` or `[Example](https://example.com)`.
+                    (
+                        Some(Event::Start(Tag::Link(_, dest, _))),
+                        Some(Event::End(Tag::Link(..))),
+                    ) if links == 1 => dest.to_string(),
+                    // A bare URL, which may have been parsed as several runs of text.
+                    _ if events.iter().all(|e| matches!(e, Event::Text(_))) => events
+                        .iter()
+                        .map(|e| match e {
+                            Event::Text(s) => s.as_ref(),
+                            _ => "",
+                        })
+                        .collect::<String>()
+                        .trim()
+                        .to_string(),
+                    _ => continue,
+                };
+                let url = Url::parse(&href).ok().filter(|u| matches!(u.scheme(), "http" | "https"));
+                if url.is_some() {
+                    return url;
+                }
+            }
+            e => {
+                if let Some(paragraph) = &mut paragraph {
+                    paragraph.push(e);
+                }
+            }
+        }
+    }
+    None
+}
+
+/// The parts of a page's metadata shown on its card.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Metadata {
+    title: Option<String>,
+    description: Option<String>,
+    site_name: Option<String>,
+    image: Option<Url>,
+    oembed: Option<Url>,
+}
+
+impl Metadata {
+    /// Parses the page's OpenGraph and Twitter Card metadata, falling back to its `<title>` and
+    /// description, and finds its oEmbed endpoint, if any. Relative URLs are resolved against
+    /// `base`.
+    fn parse(html: &str, base: &Url) -> Metadata {
+        let doc = Html::parse_document(html);
+        let metas = doc.select(&selector("meta")).collect::<Vec<_>>();
+        let meta = |names: &[&str]| {
+            names.iter().find_map(|name| {
+                metas
+                    .iter()
+                    .find(|el| {
+                        let key = el.value().attr("property").or_else(|| el.value().attr("name"));
+                        key.map(|k| k.eq_ignore_ascii_case(name)).unwrap_or(false)
+                    })
+                    .and_then(|el| el.value().attr("content"))
+                    .and_then(non_empty)
+            })
+        };
+
+        let title = meta(&["og:title", "twitter:title"]).or_else(|| {
+            doc.select(&selector("title"))
+                .next()
+                .and_then(|t| non_empty(&t.text().collect::<String>()))
+        });
+        let description = meta(&["og:description", "twitter:description", "description"])
+            .map(|d| excerpt(&d, MAX_DESCRIPTION_LEN));
+        let image =
+            meta(&["og:image:secure_url", "og:image", "twitter:image", "twitter:image:src"])
+                .and_then(|src| base.join(&src).ok())
+                .filter(|u| matches!(u.scheme(), "http" | "https"));
+
+        let site_name = meta(&["og:site_name"]);
+        let oembed = oembed::discover(&doc, base);
+        Metadata { title, description, site_name, image, oembed }
+    }
+}
+
+fn non_empty(s: &str) -> Option<String> {
+    let s = s.split_whitespace().collect::<Vec<&str>>().join(" ");
+    (!s.is_empty()).then_some(s)
+}
+
+fn selector(s: &str) -> Selector {
+    Selector::parse(s).expect("invalid selector")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use axum::response::Html as HtmlResponse;
+    use axum::routing::get;
+    use axum::Router;
+    use tempdir::TempDir;
+
+    use crate::services::notes::NoteService;
+    use crate::test_server::TestServer;
+
+    use super::*;
+
+    #[test]
+    fn standalone_links() {
+        let link = |md: &str| standalone_link(md).map(|u| u.to_string());
+        assert_eq!(link("https://example.com/a_b_c"), Some("https://example.com/a_b_c".into()));
+        assert_eq!(link("Look:\n\n<https://example.com>"), Some("https://example.com/".into()));
+        assert_eq!(link("[Wahoo](https://example.com/x)"), Some("https://example.com/x".into()));
+        assert_eq!(link("See https://example.com."), None);
+        assert_eq!(link("[a](https://a.example) and [b](https://b.example)"), None);
+        assert_eq!(link("<mailto:mario@example.com>"), None);
+        assert_eq!(link("It's a me, _Mario_."), None);
+    }
+
+    #[test]
+    fn parsing_metadata() {
+        let base = "https://example.com/posts/1".parse::<Url>().unwrap();
+        let metadata = Metadata::parse(
+            r#"<html><head>
+            <title>Fallback</title>
+            <meta property="og:title" content=" It's a me ">
+            <meta name="twitter:description" content="Mario's   blog">
+            <meta property="og:site_name" content="Mushroom Kingdom">
+            <meta property="og:image" content="/mario.png">
+            </head></html>"#,
+            &base,
+        );
+        assert_eq!(
+            metadata,
+            Metadata {
+                title: Some("It's a me".into()),
+                description: Some("Mario's blog".into()),
+                site_name: Some("Mushroom Kingdom".into()),
+                image: Some("https://example.com/mario.png".parse().unwrap()),
+                oembed: None,
+            }
+        );
+
+        let metadata = Metadata::parse(
+            &format!(
+                r#"<title>Luigi</title><meta name="description" content="{}">"#,
+                "wahoo ".repeat(100)
+            ),
+            &base,
+        );
+        assert_eq!(metadata.title.as_deref(), Some("Luigi"));
+        let description = metadata.description.unwrap();
+        assert_eq!(description.chars().count(), MAX_DESCRIPTION_LEN);
+        assert!(description.starts_with("wahoo wahoo") && description.ends_with(" wah…"));
+        assert_eq!(metadata.image, None);
+    }
+
+    #[test]
+    fn rendering_cards() {
+        let preview = LinkPreview {
+            url: "https://example.com/?a=1&b=2".into(),
+            title: "<Mario>".into(),
+            description: Some("It's a me.".into()),
+            site_name: None,
+            image_id: Some(uuid::Uuid::nil().hyphenated()),
+            embed: None,
+        };
+        assert_eq!(
+            preview.to_html(),
+            "<figure class=\"link-preview\">\n\
+             <a href=\"https://example.com/?a=1&amp;b=2\"><img src=\"/images/00000000-0000-0000-0000-000000000000.main.webp\" alt=\"\"></a>\n\
+             <figcaption><a href=\"https://example.com/?a=1&amp;b=2\"><strong>&lt;Mario&gt;</strong></a><br>It's a me.</figcaption>\n\
+             </figure>\n"
+        );
+
+        let preview = LinkPreview {
+            image_id: None,
+            embed: Some(Embed {
+                src: "https://player.example.com/embed/1?a=1&b=2".into(),
+                width: 480,
+                height: 270,
+            }),
+            ..preview
+        };
+        assert_eq!(
+            preview.to_html(),
+            "<figure class=\"link-preview\">\n\
+             <a href=\"https://example.com/?a=1&amp;b=2\" data-embed-src=\"https://player.example.com/embed/1?a=1&amp;b=2\" data-embed-width=\"480\" data-embed-height=\"270\">\u{25b6} Load player from player.example.com</a>\n\
+             <figcaption><a href=\"https://example.com/?a=1&amp;b=2\"><strong>&lt;Mario&gt;</strong></a><br>It's a me.</figcaption>\n\
+             </figure>\n"
+        );
+    }
+
+    #[sqlx::test]
+    async fn embedding_players(db: SqlitePool) -> Result<(), anyhow::Error> {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let oembed_hits = hits.clone();
+        let provider = TestServer::new(
+            Router::new()
+                .route(
+                    "/watch",
+                    get(|| async {
+                        HtmlResponse(
+                            r#"<link rel="alternate" type="application/json+oembed"
+                                href="/oembed?url=%2Fwatch&amp;format=json">"#,
+                        )
+                    }),
+                )
+                .route(
+                    "/oembed",
+                    get(move || {
+                        oembed_hits.fetch_add(1, Ordering::SeqCst);
+                        async {
+                            axum::Json(serde_json::json!({
+                                "type": "video",
+                                "version": "1.0",
+                                "title": "Wahoo",
+                                "provider_name": "PipeTube",
+                                "html": "<iframe src=\"https://player.example.com/embed/1\"></iframe>",
+                                "width": 480,
+                                "height": 270,
+                            }))
+                        }
+                    }),
+                ),
+        )?;
+        let temp_dir = TempDir::new("yellhole-test")?;
+        let sanitizer = Sanitizer::new(&["player.example.com".into()]);
+        let notes = NoteService::new(db.clone()).with_sanitizer(sanitizer.clone());
+        let previews =
+            PreviewService::new(db.clone(), ImageService::new(db.clone(), temp_dir.path())?)
+                .with_sanitizer(sanitizer);
+
+        let body = provider.url().join("watch")?.to_string();
+        let note_id = notes.create(&body).await?;
+        let preview = previews.refresh(&note_id, &body).await?.expect("missing preview");
+        assert_eq!(preview.title, "Wahoo");
+        assert_eq!(preview.site_name.as_deref(), Some("PipeTube"));
+        assert_eq!(
+            preview.embed,
+            Some(Embed {
+                src: "https://player.example.com/embed/1".into(),
+                width: 480,
+                height: 270
+            })
+        );
+        assert_eq!(notes.by_id(&note_id).await?.expect("missing note").preview, Some(preview));
+
+        // The oEmbed response is cached.
+        previews.refresh(&note_id, &body).await?;
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+        let cached =
+            sqlx::query_scalar::<_, String>("select url from oembed").fetch_all(&db).await?;
+        assert_eq!(
+            cached,
+            vec![provider.url().join("oembed?url=%2Fwatch&format=json")?.to_string()]
+        );
+
+        // Players from hosts which aren't allowed iframes are left off, even if already stored.
+        let notes = NoteService::new(db.clone());
+        let stored = notes.by_id(&note_id).await?.expect("missing note").preview;
+        assert_eq!(stored.as_ref().map(|p| p.title.as_str()), Some("Wahoo"));
+        assert_eq!(stored.and_then(|p| p.embed), None);
+
+        let previews =
+            PreviewService::new(db.clone(), ImageService::new(db.clone(), temp_dir.path())?);
+        let preview = previews.refresh(&note_id, &body).await?.expect("missing preview");
+        assert_eq!(preview.title, "Wahoo");
+        assert_eq!(preview.embed, None);
+        assert!(!preview.to_html().contains("data-embed-src"));
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn refreshing_and_removing(db: SqlitePool) -> Result<(), anyhow::Error> {
+        let site = TestServer::new(Router::new().route(
+            "/page",
+            get(|| async {
+                HtmlResponse(
+                    r#"<meta property="og:title" content="Wahoo">
+                    <meta property="og:site_name" content="Mario's Blog">"#,
+                )
+            }),
+        ))?;
+        let temp_dir = TempDir::new("yellhole-test")?;
+        let notes = NoteService::new(db.clone());
+        let previews = PreviewService::new(db.clone(), ImageService::new(db, temp_dir.path())?);
+
+        let body = format!("<{}>", site.url().join("page")?);
+        let note_id = notes.create(&body).await?;
+        let preview = previews.refresh(&note_id, &body).await?.expect("missing preview");
+        assert_eq!(preview.title, "Wahoo");
+        assert_eq!(preview.site_name.as_deref(), Some("Mario's Blog"));
+        assert_eq!(preview.description, None);
+
+        let note = notes.by_id(&note_id).await?.expect("missing note");
+        assert_eq!(note.preview, Some(preview));
+
+        assert!(previews.remove(&note_id).await?);
+        assert!(!previews.remove(&note_id).await?);
+        assert_eq!(notes.by_id(&note_id).await?.expect("missing note").preview, None);
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn timing_out(db: SqlitePool) -> Result<(), anyhow::Error> {
+        let site = TestServer::new(Router::new().route(
+            "/slow",
+            get(|| async {
+                tokio::time::sleep(Duration::from_secs(10)).await;
+                HtmlResponse("<title>Too late</title>")
+            }),
+        ))?;
+        let temp_dir = TempDir::new("yellhole-test")?;
+        let previews = PreviewService::new(db.clone(), ImageService::new(db, temp_dir.path())?)
+            .with_timeout(Duration::from_millis(100));
+
+        let note_id = uuid::Uuid::new_v4().hyphenated();
+        let body = site.url().join("slow")?.to_string();
+        assert!(previews.refresh(&note_id, &body).await.is_err());
+
+        Ok(())
+    }
+}
@@ -3,27 +3,122 @@ use std::time::Duration;
 use axum::async_trait;
 use axum_sessions::async_session::{Result, Session, SessionStore};
 use axum_sessions::{SameSite, SessionLayer};
+use chrono::NaiveDateTime;
+use constant_time_eq::constant_time_eq;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
 use sqlx::SqlitePool;
 use tokio::{task, time};
 use url::Url;
 
+use super::health::TaskMonitor;
+use crate::config::SessionSecret;
+
+/// The name of the session cookie.
+pub const COOKIE_NAME: &str = "yellhole";
+
+/// Configuration for session cookies.
+#[derive(Debug, Clone)]
+pub struct SessionConfig {
+    /// The secret used to sign new session cookies.
+    pub secret: SessionSecret,
+
+    /// A previous secret, session cookies signed with which are still accepted.
+    pub previous_secret: Option<SessionSecret>,
+
+    /// How long a session lasts after its last use.
+    pub ttl: Duration,
+}
+
 #[derive(Debug, Clone)]
 pub struct SessionService {
     db: SqlitePool,
+    ttl: Duration,
 }
 
 impl SessionService {
     pub fn new(
         db: &SqlitePool,
         base_url: &Url,
-    ) -> (SessionLayer<SessionService>, task::JoinHandle<anyhow::Result<()>>) {
-        let store = SessionService { db: db.clone() };
-        let session_expiry = task::spawn(store.clone().continuously_delete_expired());
-        let session_layer = SessionLayer::new(store, &[69; 64])
-            .with_cookie_name("yellhole")
+        config: &SessionConfig,
+    ) -> (
+        SessionService,
+        SessionLayer<SessionService>,
+        TaskMonitor,
+        task::JoinHandle<anyhow::Result<()>>,
+    ) {
+        let store = SessionService::store(db, config.ttl);
+        let (expiry_monitor, session_expiry) =
+            TaskMonitor::spawn(store.clone().continuously_delete_expired());
+        let session_layer = SessionLayer::new(store.clone(), &config.secret.0)
+            .with_cookie_name(COOKIE_NAME)
             .with_same_site_policy(SameSite::Strict)
+            .with_session_ttl(Some(config.ttl))
             .with_secure(base_url.scheme() == "https");
-        (session_layer, session_expiry)
+        (store, session_layer, expiry_monitor, session_expiry)
+    }
+
+    /// Creates a session store without a session layer or expiry task.
+    pub fn store(db: &SqlitePool, ttl: Duration) -> SessionService {
+        SessionService { db: db.clone(), ttl }
+    }
+
+    /// Returns all authenticated sessions, most recently active first.
+    pub async fn authenticated(&self) -> Result<Vec<ActiveSession>> {
+        let rows = sqlx::query!(
+            r#"
+            select
+              session_id,
+              as_json,
+              created_at,
+              coalesce(updated_at, created_at) as "updated_at!: NaiveDateTime"
+            from session
+            order by 4 desc
+            "#
+        )
+        .fetch_all(&self.db)
+        .await?;
+
+        let mut sessions = Vec::with_capacity(rows.len());
+        for r in rows {
+            let session = serde_json::from_str::<Session>(&r.as_json)?;
+            if session.is_expired() || !session.get::<bool>("authenticated").unwrap_or(false) {
+                continue;
+            }
+            sessions.push(ActiveSession {
+                session_id: r.session_id,
+                created_at: r.created_at,
+                updated_at: r.updated_at,
+                user_agent: session.get("user_agent"),
+                ip_address: session.get("ip_address"),
+            });
+        }
+        Ok(sessions)
+    }
+
+    /// Revokes the session with the given ID.
+    pub async fn revoke(&self, session_id: &str) -> Result<()> {
+        tracing::info!(session_id, "revoking session");
+        sqlx::query!(r"delete from session where session_id = ?", session_id)
+            .execute(&self.db)
+            .await?;
+        Ok(())
+    }
+
+    /// Revokes all sessions except the one with the given ID.
+    pub async fn revoke_all_except(&self, session_id: &str) -> Result<()> {
+        tracing::info!(session_id, "revoking all other sessions");
+        sqlx::query!(r"delete from session where session_id != ?", session_id)
+            .execute(&self.db)
+            .await?;
+        Ok(())
+    }
+
+    /// Revokes all sessions, returning the number of sessions revoked.
+    pub async fn revoke_all(&self) -> Result<u64> {
+        tracing::info!("revoking all sessions");
+        let res = sqlx::query!(r"delete from session").execute(&self.db).await?;
+        Ok(res.rows_affected())
     }
 
     pub async fn continuously_delete_expired(self) -> Result<()> {
@@ -37,13 +132,52 @@ impl SessionService {
 
     async fn delete_expired(&self) -> Result<()> {
         tracing::trace!("destroying expired sessions");
-        sqlx::query!(r"delete from session where updated_at < date('now', '-1 day')")
-            .execute(&self.db)
-            .await?;
+        let ttl = format!("-{} seconds", self.ttl.as_secs());
+        sqlx::query!(
+            r"delete from session where coalesce(updated_at, created_at) < datetime('now', ?)",
+            ttl
+        )
+        .execute(&self.db)
+        .await?;
         Ok(())
     }
 }
 
+/// If the given signed cookie value was signed with the `previous` secret, returns the cookie value
+/// re-signed with the `current` secret. Otherwise, returns `None`.
+pub fn resign_cookie(
+    signed: &str,
+    current: &SessionSecret,
+    previous: &SessionSecret,
+) -> Option<String> {
+    if signed.len() < SIGNATURE_LEN || !signed.is_char_boundary(SIGNATURE_LEN) {
+        return None;
+    }
+    let (signature, value) = signed.split_at(SIGNATURE_LEN);
+    constant_time_eq(signature.as_bytes(), sign(value, previous).as_bytes())
+        .then(|| format!("{}{}", sign(value, current), value))
+}
+
+/// The length of a Base64-encoded HMAC-SHA-256 signature.
+const SIGNATURE_LEN: usize = 44;
+
+/// Signs the cookie value the same way the session layer does: HMAC-SHA-256 using the first 32
+/// bytes of the secret as the key, Base64-encoded.
+fn sign(value: &str, secret: &SessionSecret) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(&secret.0[..32]).expect("good key");
+    mac.update(value.as_bytes());
+    base64::encode(mac.finalize().into_bytes())
+}
+
+#[derive(Debug)]
+pub struct ActiveSession {
+    pub session_id: String,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+}
+
 #[async_trait]
 impl SessionStore for SessionService {
     async fn load_session(&self, cookie_value: String) -> Result<Option<Session>> {
@@ -94,3 +228,22 @@ impl SessionStore for SessionService {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resigning_cookies() {
+        let current = SessionSecret::generate();
+        let previous = SessionSecret::generate();
+        let value = "cookie-value";
+
+        let old = format!("{}{}", sign(value, &previous), value);
+        let new = resign_cookie(&old, &current, &previous).expect("should have re-signed");
+        assert_eq!(new, format!("{}{}", sign(value, &current), value));
+
+        assert_eq!(None, resign_cookie(&new, &current, &previous));
+        assert_eq!(None, resign_cookie("short", &current, &previous));
+    }
+}
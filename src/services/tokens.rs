@@ -0,0 +1,157 @@
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::NaiveDateTime;
+use rand::{thread_rng, Rng};
+use sha2::{Digest, Sha256};
+use sqlx::SqlitePool;
+use uuid::fmt::Hyphenated;
+use uuid::Uuid;
+
+#[derive(Debug, Clone)]
+pub struct TokenService {
+    db: SqlitePool,
+}
+
+impl TokenService {
+    pub fn new(db: SqlitePool) -> TokenService {
+        TokenService { db }
+    }
+
+    /// Creates a new API token with the given scopes, optionally expiring after the given number of
+    /// days. Returns the secret token value, which is only stored as a hash.
+    pub async fn create(
+        &self,
+        name: &str,
+        scopes: &[Scope],
+        expires_in_days: Option<u32>,
+    ) -> Result<String, sqlx::Error> {
+        let api_token_id = Uuid::new_v4().hyphenated();
+        let token = format!("{TOKEN_PREFIX}{}", hex::encode(thread_rng().gen::<[u8; 32]>()));
+        let token_hash = hash(&token);
+        let scopes = scopes.iter().map(Scope::to_string).collect::<Vec<String>>().join(" ");
+        let expires_in = expires_in_days.map(|d| format!("+{d} days"));
+        sqlx::query!(
+            r"
+            insert into api_token (api_token_id, name, token_hash, scopes, expires_at)
+            values (?, ?, ?, ?, datetime('now', ?))
+            ",
+            api_token_id,
+            name,
+            token_hash,
+            scopes,
+            expires_in,
+        )
+        .execute(&self.db)
+        .await?;
+        Ok(token)
+    }
+
+    /// Returns the scopes of the given token, if it exists and hasn't expired.
+    pub async fn authenticate(&self, token: &str) -> Result<Option<Vec<Scope>>, sqlx::Error> {
+        let token_hash = hash(token);
+        let scopes = sqlx::query!(
+            r#"
+            update api_token
+            set last_used_at = current_timestamp
+            where token_hash = ? and (expires_at is null or expires_at > current_timestamp)
+            returning scopes as "scopes!"
+            "#,
+            token_hash,
+        )
+        .fetch_optional(&self.db)
+        .await?
+        .map(|r| parse_scopes(&r.scopes));
+        Ok(scopes)
+    }
+
+    /// Returns all API tokens, newest first.
+    pub async fn all(&self) -> Result<Vec<ApiToken>, sqlx::Error> {
+        Ok(sqlx::query!(
+            r#"
+            select
+              api_token_id as "api_token_id: Hyphenated",
+              name,
+              scopes,
+              expires_at,
+              last_used_at,
+              created_at
+            from api_token
+            order by created_at desc
+            "#
+        )
+        .fetch_all(&self.db)
+        .await?
+        .into_iter()
+        .map(|r| ApiToken {
+            api_token_id: r.api_token_id,
+            name: r.name,
+            scopes: parse_scopes(&r.scopes),
+            expires_at: r.expires_at,
+            last_used_at: r.last_used_at,
+            created_at: r.created_at,
+        })
+        .collect())
+    }
+
+    /// Revokes the API token with the given ID.
+    pub async fn revoke(&self, api_token_id: &Hyphenated) -> Result<(), sqlx::Error> {
+        tracing::info!(%api_token_id, "revoking API token");
+        sqlx::query!(r"delete from api_token where api_token_id = ?", api_token_id)
+            .execute(&self.db)
+            .await?;
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct ApiToken {
+    pub api_token_id: Hyphenated,
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    pub expires_at: Option<NaiveDateTime>,
+    pub last_used_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+}
+
+/// A permission granted to an API token.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    NotesWrite,
+    ImagesWrite,
+}
+
+impl Scope {
+    pub const ALL: [Scope; 2] = [Scope::NotesWrite, Scope::ImagesWrite];
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Scope::NotesWrite => "notes:write",
+            Scope::ImagesWrite => "images:write",
+        })
+    }
+}
+
+impl FromStr for Scope {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Scope::ALL
+            .into_iter()
+            .find(|scope| scope.to_string() == s)
+            .ok_or_else(|| anyhow::anyhow!("unknown scope: {s:?}"))
+    }
+}
+
+/// The prefix of all API tokens, to make them easy to recognize.
+const TOKEN_PREFIX: &str = "yh_";
+
+fn hash(token: &str) -> Vec<u8> {
+    Sha256::new().chain_update(token.as_bytes()).finalize().to_vec()
+}
+
+fn parse_scopes(scopes: &str) -> Vec<Scope> {
+    scopes.split_whitespace().filter_map(|s| s.parse().ok()).collect()
+}
@@ -14,7 +14,11 @@ impl TestServer {
         let addr = listener.local_addr()?;
 
         tokio::spawn(async move {
-            axum::Server::from_tcp(listener).unwrap().serve(app.into_make_service()).await.unwrap();
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+                .await
+                .unwrap();
         });
 
         Ok(TestServer {
@@ -23,6 +27,11 @@ impl TestServer {
         })
     }
 
+    /// The base URL of the server.
+    pub fn url(&self) -> &Url {
+        &self.url
+    }
+
     pub fn get(&self, path: &str) -> RequestBuilder {
         self.client.get(self.url.join(path).unwrap())
     }
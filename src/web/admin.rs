@@ -1,44 +1,73 @@
 use askama::Template;
+use axum::body::StreamBody;
 use axum::extract::{DefaultBodyLimit, Multipart};
-use axum::http::StatusCode;
-use axum::response::Redirect;
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Redirect};
 use axum::routing::{get, post};
 use axum::{Extension, Form, Router};
+use axum_sessions::extractors::{ReadableSession, WritableSession};
+use chrono::Utc;
 use serde::Deserialize;
+use tokio_util::io::ReaderStream;
 use tower::ServiceBuilder;
 use tower_http::limit::RequestBodyLimitLayer;
 use url::Url;
+use uuid::Uuid;
 
+use crate::config::HttpConfig;
+use crate::services::audit::{AuditService, AuthEvent, AuthEventKind};
+use crate::services::export::ExportService;
 use crate::services::images::{Image, ImageService};
-use crate::services::notes::NoteService;
+use crate::services::notes::{Note, NoteService};
+use crate::services::previews::{standalone_link, PreviewService};
+use crate::services::sessions::{ActiveSession, SessionService};
+use crate::services::tokens::{ApiToken, Scope, TokenService};
 
-use super::Page;
+use super::auth::record;
+use super::csrf::CsrfToken;
+use super::{filters, ClientInfo, Page, Site};
 
-pub fn router() -> Router {
+pub fn router(http: &HttpConfig) -> Router {
     Router::new()
         .route("/admin/new", get(new_page))
         .route("/admin/new-note", post(create_note))
         .route("/admin/upload-images", post(upload_images))
         .route("/admin/download-image", post(download_image))
+        .route("/admin/previews", get(previews_page))
+        .route("/admin/previews/refresh", post(refresh_preview))
+        .route("/admin/previews/remove", post(remove_preview))
+        .route("/admin/sessions", get(sessions_page))
+        .route("/admin/sessions/revoke", post(revoke_session))
+        .route("/admin/sessions/revoke-others", post(revoke_other_sessions))
+        .route("/admin/audit", get(audit_page))
+        .route("/admin/tokens", get(tokens_page).post(create_token))
+        .route("/admin/tokens/revoke", post(revoke_token))
+        .route("/admin/export", get(export))
         .layer(
             ServiceBuilder::new()
                 .layer(DefaultBodyLimit::disable())
-                .layer(RequestBodyLimitLayer::new(32 * 1024 * 1024)),
+                .layer(RequestBodyLimitLayer::new(http.body_limit)),
         )
 }
 
 #[derive(Debug, Template)]
 #[template(path = "new.html")]
 struct NewPage {
+    site: Site,
+    csrf_token: String,
     images: Vec<Image>,
 }
 
-async fn new_page(images: Extension<ImageService>) -> Result<Page<NewPage>, StatusCode> {
+async fn new_page(
+    site: Site,
+    CsrfToken(csrf_token): CsrfToken,
+    images: Extension<ImageService>,
+) -> Result<Page<NewPage>, StatusCode> {
     let images = images.most_recent(10).await.map_err(|err| {
         tracing::warn!(%err, "unable to query recent images");
         StatusCode::INTERNAL_SERVER_ERROR
     })?;
-    Ok(Page(NewPage { images }))
+    Ok(Page(NewPage { site, csrf_token, images }))
 }
 
 #[derive(Debug, Deserialize)]
@@ -48,12 +77,16 @@ struct NewNote {
 
 async fn create_note(
     notes: Extension<NoteService>,
+    previews: Extension<PreviewService>,
     Form(new_note): Form<NewNote>,
 ) -> Result<Redirect, StatusCode> {
     let note_id = notes.create(&new_note.body).await.map_err(|err| {
         tracing::warn!(%err, "error inserting note");
         StatusCode::INTERNAL_SERVER_ERROR
     })?;
+    if let Err(err) = previews.refresh(&note_id, &new_note.body).await {
+        tracing::warn!(?err, %note_id, "unable to fetch link preview");
+    }
     Ok(Redirect::to(&format!("/note/{note_id}")))
 }
 
@@ -99,6 +132,239 @@ async fn download_image(
     Ok(Redirect::to("/admin/new"))
 }
 
+#[derive(Debug, Template)]
+#[template(path = "previews.html")]
+struct PreviewsPage {
+    site: Site,
+    csrf_token: String,
+    notes: Vec<Note>,
+}
+
+async fn previews_page(
+    site: Site,
+    CsrfToken(csrf_token): CsrfToken,
+    notes: Extension<NoteService>,
+) -> Result<Page<PreviewsPage>, StatusCode> {
+    let mut notes = notes.most_recent(100).await.map_err(|err| {
+        tracing::warn!(%err, "unable to query recent notes");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    notes.retain(|n| n.preview.is_some() || standalone_link(&n.body).is_some());
+    Ok(Page(PreviewsPage { site, csrf_token, notes }))
+}
+
+#[derive(Debug, Deserialize)]
+struct PreviewForm {
+    note_id: String,
+}
+
+async fn refresh_preview(
+    notes: Extension<NoteService>,
+    previews: Extension<PreviewService>,
+    Form(form): Form<PreviewForm>,
+) -> Result<Redirect, StatusCode> {
+    let note_id = form.note_id.parse::<Uuid>().map_err(|_| StatusCode::BAD_REQUEST)?;
+    let note = notes
+        .by_id(note_id.as_hyphenated())
+        .await
+        .map_err(|err| {
+            tracing::warn!(%err, "unable to query note");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    previews.refresh(&note.note_id, &note.body).await.map_err(|err| {
+        tracing::warn!(?err, note_id = %note.note_id, "unable to fetch link preview");
+        StatusCode::BAD_GATEWAY
+    })?;
+    Ok(Redirect::to("/admin/previews"))
+}
+
+async fn remove_preview(
+    previews: Extension<PreviewService>,
+    Form(form): Form<PreviewForm>,
+) -> Result<Redirect, StatusCode> {
+    let note_id = form.note_id.parse::<Uuid>().map_err(|_| StatusCode::BAD_REQUEST)?;
+    previews.remove(note_id.as_hyphenated()).await.map_err(|err| {
+        tracing::warn!(%err, "unable to remove link preview");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    Ok(Redirect::to("/admin/previews"))
+}
+
+async fn export(export: Extension<ExportService>) -> Result<impl IntoResponse, StatusCode> {
+    let file = export.export_to_temp_file().await.map_err(|err| {
+        tracing::warn!(?err, "unable to export notes and images");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    let filename = format!("yellhole-export-{}.tar.gz", Utc::now().format("%Y%m%d"));
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/gzip".to_string()),
+            (header::CONTENT_DISPOSITION, format!("attachment; filename=\"{filename}\"")),
+        ],
+        StreamBody::new(ReaderStream::new(file)),
+    ))
+}
+
+#[derive(Debug, Template)]
+#[template(path = "sessions.html")]
+struct SessionsPage {
+    site: Site,
+    csrf_token: String,
+    current_session_id: String,
+    sessions: Vec<ActiveSession>,
+}
+
+async fn sessions_page(
+    site: Site,
+    CsrfToken(csrf_token): CsrfToken,
+    sessions: Extension<SessionService>,
+    session: ReadableSession,
+) -> Result<Page<SessionsPage>, StatusCode> {
+    let sessions = sessions.authenticated().await.map_err(|err| {
+        tracing::warn!(%err, "unable to query active sessions");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    Ok(Page(SessionsPage { site, csrf_token, current_session_id: session.id().into(), sessions }))
+}
+
+#[derive(Debug, Deserialize)]
+struct RevokeSession {
+    session_id: String,
+}
+
+async fn revoke_session(
+    sessions: Extension<SessionService>,
+    audit: Extension<AuditService>,
+    client: ClientInfo,
+    mut session: WritableSession,
+    Form(revoke): Form<RevokeSession>,
+) -> Result<Redirect, StatusCode> {
+    // Revoking the current session is just logging out.
+    if revoke.session_id == session.id() {
+        record(&audit, AuthEventKind::Logout, &client).await?;
+        session.destroy();
+        return Ok(Redirect::to("/login"));
+    }
+
+    sessions.revoke(&revoke.session_id).await.map_err(|err| {
+        tracing::warn!(%err, "unable to revoke session");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    record(&audit, AuthEventKind::SessionRevoked, &client).await?;
+
+    Ok(Redirect::to("/admin/sessions"))
+}
+
+async fn revoke_other_sessions(
+    sessions: Extension<SessionService>,
+    audit: Extension<AuditService>,
+    client: ClientInfo,
+    session: ReadableSession,
+) -> Result<Redirect, StatusCode> {
+    sessions.revoke_all_except(session.id()).await.map_err(|err| {
+        tracing::warn!(%err, "unable to revoke other sessions");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    record(&audit, AuthEventKind::SessionRevoked, &client).await?;
+
+    Ok(Redirect::to("/admin/sessions"))
+}
+
+#[derive(Debug, Template)]
+#[template(path = "audit.html")]
+struct AuditPage {
+    site: Site,
+    events: Vec<AuthEvent>,
+}
+
+async fn audit_page(
+    site: Site,
+    audit: Extension<AuditService>,
+) -> Result<Page<AuditPage>, StatusCode> {
+    let events = audit.most_recent(100).await.map_err(|err| {
+        tracing::warn!(%err, "unable to query auth events");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    Ok(Page(AuditPage { site, events }))
+}
+
+#[derive(Debug, Template)]
+#[template(path = "tokens.html")]
+struct TokensPage {
+    site: Site,
+    csrf_token: String,
+    new_token: Option<String>,
+    tokens: Vec<ApiToken>,
+}
+
+async fn tokens_page(
+    site: Site,
+    CsrfToken(csrf_token): CsrfToken,
+    tokens: Extension<TokenService>,
+) -> Result<Page<TokensPage>, StatusCode> {
+    let tokens = tokens.all().await.map_err(|err| {
+        tracing::warn!(%err, "unable to query API tokens");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    Ok(Page(TokensPage { site, csrf_token, new_token: None, tokens }))
+}
+
+#[derive(Debug, Deserialize)]
+struct NewToken {
+    name: String,
+    notes_write: Option<String>,
+    images_write: Option<String>,
+    expires_in_days: String,
+}
+
+async fn create_token(
+    site: Site,
+    CsrfToken(csrf_token): CsrfToken,
+    tokens: Extension<TokenService>,
+    Form(new_token): Form<NewToken>,
+) -> Result<Page<TokensPage>, StatusCode> {
+    let expires_in_days = match new_token.expires_in_days.trim() {
+        "" => None,
+        days => Some(days.parse::<u32>().map_err(|_| StatusCode::BAD_REQUEST)?),
+    };
+    let scopes = [
+        new_token.notes_write.map(|_| Scope::NotesWrite),
+        new_token.images_write.map(|_| Scope::ImagesWrite),
+    ]
+    .into_iter()
+    .flatten()
+    .collect::<Vec<Scope>>();
+
+    let token = tokens.create(&new_token.name, &scopes, expires_in_days).await.map_err(|err| {
+        tracing::warn!(%err, "unable to create API token");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let tokens = tokens.all().await.map_err(|err| {
+        tracing::warn!(%err, "unable to query API tokens");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    Ok(Page(TokensPage { site, csrf_token, new_token: Some(token), tokens }))
+}
+
+#[derive(Debug, Deserialize)]
+struct RevokeToken {
+    api_token_id: String,
+}
+
+async fn revoke_token(
+    tokens: Extension<TokenService>,
+    Form(revoke): Form<RevokeToken>,
+) -> Result<Redirect, StatusCode> {
+    let api_token_id = revoke.api_token_id.parse::<Uuid>().map_err(|_| StatusCode::BAD_REQUEST)?;
+    tokens.revoke(api_token_id.as_hyphenated()).await.map_err(|err| {
+        tracing::warn!(%err, "unable to revoke API token");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    Ok(Redirect::to("/admin/tokens"))
+}
+
 #[cfg(test)]
 mod tests {
     use axum::http;
@@ -106,9 +372,9 @@ mod tests {
     use sqlx::SqlitePool;
     use tempdir::TempDir;
     use tokio::fs;
-    use uuid::Uuid;
 
-    use crate::config::{Author, Title};
+    use crate::config::{Author, SessionSecret, Title};
+    use crate::services::sessions::SessionConfig;
     use crate::test_server::TestServer;
 
     use super::*;
@@ -146,6 +412,157 @@ mod tests {
         Ok(())
     }
 
+    #[sqlx::test]
+    async fn managing_link_previews(db: SqlitePool) -> Result<(), anyhow::Error> {
+        let temp_dir = TempDir::new("yellhole-test")?;
+        let (_, notes, app) = app(&db, &temp_dir)?;
+        let ts = TestServer::new(app)?;
+        let linked = TestServer::new(
+            Router::new()
+                .route("/", get(|| async { axum::response::Html("<title>It's a me</title>") })),
+        )?;
+
+        // Notes which are just a link get a card when they're posted.
+        let resp =
+            ts.post("/admin/new-note").form(&[("body", linked.url().as_str())]).send().await?;
+        assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+        let note = notes.most_recent(1).await?.pop().expect("missing note");
+        assert_eq!(note.preview.map(|p| p.title).as_deref(), Some("It's a me"));
+
+        let body = ts.get("/admin/previews").send().await?.text().await?;
+        assert!(body.contains("<strong>It's a me</strong>"));
+
+        let form = [("note_id", note.note_id.to_string())];
+        let resp = ts.post("/admin/previews/remove").form(&form).send().await?;
+        assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+        assert_eq!(notes.by_id(&note.note_id).await?.expect("missing note").preview, None);
+
+        let resp = ts.post("/admin/previews/refresh").form(&form).send().await?;
+        assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+        assert!(notes.by_id(&note.note_id).await?.expect("missing note").preview.is_some());
+
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("notes"))]
+    async fn exporting(db: SqlitePool) -> Result<(), anyhow::Error> {
+        let temp_dir = TempDir::new("yellhole-test")?;
+        let (_, _, app) = app(&db, &temp_dir)?;
+        let ts = TestServer::new(app)?;
+
+        let resp = ts.get("/admin/export").send().await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers().get(http::header::CONTENT_TYPE),
+            Some(&http::HeaderValue::from_static("application/gzip"))
+        );
+
+        let archive = resp.bytes().await?;
+        let mut tar = tar::Archive::new(flate2::read::GzDecoder::new(archive.as_ref()));
+        let mut paths = tar
+            .entries()?
+            .map(|e| Ok(e?.path()?.to_string_lossy().to_string()))
+            .collect::<Result<Vec<String>, anyhow::Error>>()?;
+        paths.sort();
+        assert_eq!(
+            paths,
+            vec![
+                "manifest.json",
+                "notes/2022-09-07-b0a2170c-5e91-42ad-aa1b-dabc3c6ea5b9.md",
+                "notes/2022-10-14-c1449d6c-6b5b-4ce4-a4d7-98853562fbf1.md",
+                "notes/2022-11-14-69b124f0-a4fa-40d0-83f4-06bc4213f3ca.md",
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("sessions"))]
+    async fn listing_sessions(db: SqlitePool) -> Result<(), anyhow::Error> {
+        let temp_dir = TempDir::new("yellhole-test")?;
+        let (_, _, app) = app(&db, &temp_dir)?;
+        let ts = TestServer::new(app)?;
+
+        let resp = ts.get("/admin/sessions").send().await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let body = resp.text().await?;
+        assert!(body.contains("Mozilla/5.0 (Garfield)"));
+        assert!(body.contains("10.0.0.2"));
+
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("sessions"))]
+    async fn revoking_a_session(db: SqlitePool) -> Result<(), anyhow::Error> {
+        let temp_dir = TempDir::new("yellhole-test")?;
+        let (_, _, app) = app(&db, &temp_dir)?;
+        let ts = TestServer::new(app)?;
+        let sessions =
+            SessionService::new(&db, &"http://example.com".parse()?, &session_config()).0;
+
+        let resp = ts
+            .post("/admin/sessions/revoke")
+            .form(&[("session_id", "8ysN4jo+KSyCpb6hLWuHSEOvdOrtqzN/5yiYPSzw6iM=")])
+            .send()
+            .await?;
+        assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+
+        let active = sessions.authenticated().await?;
+        assert_eq!(1, active.len());
+        assert_eq!(active[0].user_agent.as_deref(), Some("Mozilla/5.0 (Garfield)"));
+
+        let resp = ts.post("/admin/sessions/revoke-others").send().await?;
+        assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+
+        assert!(sessions.authenticated().await?.is_empty());
+
+        let resp = ts.get("/admin/audit").send().await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let body = resp.text().await?;
+        assert_eq!(2, body.matches("session-revoked").count());
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn creating_and_revoking_tokens(db: SqlitePool) -> Result<(), anyhow::Error> {
+        let temp_dir = TempDir::new("yellhole-test")?;
+        let (_, _, app) = app(&db, &temp_dir)?;
+        let ts = TestServer::new(app)?;
+        let tokens = TokenService::new(db.clone());
+
+        let resp = ts
+            .post("/admin/tokens")
+            .form(&[("name", "CI"), ("notes_write", "on"), ("expires_in_days", "30")])
+            .send()
+            .await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let body = resp.text().await?;
+        let token = body
+            .split(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+            .find(|s| s.starts_with("yh_"))
+            .expect("missing token");
+        assert_eq!(tokens.authenticate(token).await?, Some(vec![Scope::NotesWrite]));
+
+        let all = tokens.all().await?;
+        assert_eq!(1, all.len());
+        assert_eq!("CI", all[0].name);
+        assert!(all[0].expires_at.is_some());
+
+        let resp = ts
+            .post("/admin/tokens/revoke")
+            .form(&[("api_token_id", all[0].api_token_id.to_string())])
+            .send()
+            .await?;
+        assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+        assert_eq!(tokens.authenticate(token).await?, None);
+
+        Ok(())
+    }
+
     #[sqlx::test]
     async fn uploading_an_image(db: SqlitePool) -> Result<(), anyhow::Error> {
         let temp_dir = TempDir::new("yellhole-test")?;
@@ -185,21 +602,38 @@ mod tests {
         Ok(())
     }
 
+    fn session_config() -> SessionConfig {
+        SessionConfig {
+            secret: SessionSecret::generate(),
+            previous_secret: None,
+            ttl: std::time::Duration::from_secs(24 * 60 * 60),
+        }
+    }
+
     fn app(
         db: &SqlitePool,
         temp_dir: &TempDir,
     ) -> Result<(ImageService, NoteService, Router), anyhow::Error> {
+        let base_url = "http://example.com".parse::<Url>()?;
         let images = ImageService::new(db.clone(), temp_dir)?;
         let notes = NoteService::new(db.clone());
+        let (sessions, session_layer, _, _) = SessionService::new(db, &base_url, &session_config());
         Ok((
             images.clone(),
             notes.clone(),
-            router()
+            router(&HttpConfig::default())
+                .layer(session_layer)
+                .layer(Extension(ExportService::new(notes.clone(), images.clone(), temp_dir)))
+                .layer(Extension(PreviewService::new(db.clone(), images.clone())))
                 .layer(Extension(images))
                 .layer(Extension(notes))
-                .layer(Extension("http://example.com".parse::<Url>().unwrap()))
+                .layer(Extension(sessions))
+                .layer(Extension(AuditService::new(db.clone())))
+                .layer(Extension(TokenService::new(db.clone())))
+                .layer(Extension(base_url))
                 .layer(Extension(Author("Mr Magoo".into())))
-                .layer(Extension(Title("Yellhole".into()))),
+                .layer(Extension(Title("Yellhole".into())))
+                .layer(Extension(chrono_tz::UTC)),
         ))
     }
 }
@@ -0,0 +1,608 @@
+use axum::extract::{BodyStream, DefaultBodyLimit, FromRequest, Path, Query, RequestParts};
+use axum::http::{self, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Extension, Json, Router};
+use axum_sessions::SessionHandle;
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use tower::ServiceBuilder;
+use tower_http::limit::RequestBodyLimitLayer;
+use tower_http::set_header::SetResponseHeaderLayer;
+use url::Url;
+use uuid::fmt::Hyphenated;
+use uuid::Uuid;
+
+use super::csrf;
+use crate::config::HttpConfig;
+use crate::services::images::{Image, ImageService};
+use crate::services::notes::{Note, NoteService};
+use crate::services::previews::PreviewService;
+use crate::services::tokens::{Scope, TokenService};
+
+/// The public, read-only API.
+pub fn public_router(http: &HttpConfig) -> Router {
+    Router::new()
+        .route("/api/v1/notes", get(list_notes))
+        .route("/api/v1/notes/range", get(notes_in_range))
+        .route("/api/v1/notes/:note_id", get(single_note))
+        .route("/api/v1/images", get(list_images))
+        .route("/api/v1/openapi.json", get(openapi))
+        .layer(SetResponseHeaderLayer::overriding(
+            http::header::CACHE_CONTROL,
+            http.cache_control(),
+        ))
+}
+
+/// The authenticated, write API.
+pub fn router(http: &HttpConfig) -> Router {
+    Router::new()
+        .route("/api/v1/notes", post(create_note))
+        .route("/api/v1/images", post(upload_image))
+        .layer(
+            ServiceBuilder::new()
+                .layer(DefaultBodyLimit::disable())
+                .layer(RequestBodyLimitLayer::new(http.body_limit)),
+        )
+}
+
+/// Authenticates API requests, either with an `Authorization: Bearer` API token or with an
+/// authenticated session and a matching `X-CSRF-Token` header. Authenticated sessions are granted
+/// all scopes.
+#[derive(Debug)]
+pub struct ApiAuth(Vec<Scope>);
+
+impl ApiAuth {
+    pub fn require(&self, scope: Scope) -> Result<(), ApiError> {
+        self.0
+            .contains(&scope)
+            .then_some(())
+            .ok_or_else(|| ApiError::new(StatusCode::FORBIDDEN, format!("{scope} scope required")))
+    }
+}
+
+#[axum::async_trait]
+impl<B> FromRequest<B> for ApiAuth
+where
+    B: Send,
+{
+    type Rejection = ApiError;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        if let Some(authorization) = req.headers().get(http::header::AUTHORIZATION) {
+            let token = authorization
+                .to_str()
+                .ok()
+                .and_then(|s| s.strip_prefix("Bearer "))
+                .ok_or_else(|| ApiError::unauthorized("invalid authorization header"))?;
+            let tokens = req.extensions().get::<TokenService>().ok_or_else(ApiError::internal)?;
+            let scopes = tokens.authenticate(token.trim()).await.map_err(|err| {
+                tracing::warn!(%err, "unable to authenticate API token");
+                ApiError::internal()
+            })?;
+            return scopes.map(ApiAuth).ok_or_else(|| ApiError::unauthorized("invalid API token"));
+        }
+
+        if let Some(session) = req.extensions().get::<SessionHandle>() {
+            let session = session.read().await;
+            if session.get::<bool>("authenticated").unwrap_or(false)
+                && csrf::header_matches(req.headers(), &session)
+            {
+                return Ok(ApiAuth(Scope::ALL.to_vec()));
+            }
+        }
+
+        Err(ApiError::unauthorized("authentication required"))
+    }
+}
+
+/// An API error, serialized as a JSON object.
+#[derive(Debug)]
+pub struct ApiError {
+    status: StatusCode,
+    message: String,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, message: impl Into<String>) -> ApiError {
+        ApiError { status, message: message.into() }
+    }
+
+    pub fn unauthorized(message: &str) -> ApiError {
+        ApiError::new(StatusCode::UNAUTHORIZED, message)
+    }
+
+    pub fn internal() -> ApiError {
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "internal server error")
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        #[derive(Serialize)]
+        struct Body {
+            error: String,
+        }
+
+        (self.status, Json(Body { error: self.message })).into_response()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ApiNote {
+    note_id: String,
+    body: String,
+    html: String,
+    created_at: String,
+    url: Url,
+}
+
+impl ApiNote {
+    fn new(note: &Note, base_url: &Url) -> ApiNote {
+        ApiNote {
+            note_id: note.note_id.to_string(),
+            body: note.body.clone(),
+            html: note.html.clone(),
+            created_at: to_rfc3339(&note.created_at),
+            url: base_url.join(&format!("note/{}", note.note_id)).expect("invalid URL"),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct NotePage {
+    notes: Vec<ApiNote>,
+    next_cursor: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ListOpts {
+    cursor: Option<String>,
+    limit: Option<u16>,
+}
+
+async fn list_notes(
+    notes: Extension<NoteService>,
+    base_url: Extension<Url>,
+    opts: Query<ListOpts>,
+) -> Result<Json<NotePage>, ApiError> {
+    let limit = opts.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    let cursor = opts
+        .cursor
+        .as_deref()
+        .map(decode_cursor)
+        .transpose()
+        .map_err(|_| ApiError::new(StatusCode::BAD_REQUEST, "invalid cursor"))?;
+
+    let notes = notes.before(cursor.as_ref(), limit).await.map_err(|err| {
+        tracing::warn!(?err, "error querying notes");
+        ApiError::internal()
+    })?;
+
+    let next_cursor = (notes.len() == limit as usize)
+        .then(|| notes.last().map(|n| encode_cursor(&n.created_at, &n.note_id)))
+        .flatten();
+    let notes = notes.iter().map(|n| ApiNote::new(n, &base_url)).collect();
+    Ok(Json(NotePage { notes, next_cursor }))
+}
+
+#[derive(Debug, Deserialize)]
+struct RangeOpts {
+    start: String,
+    end: String,
+}
+
+async fn notes_in_range(
+    notes: Extension<NoteService>,
+    Extension(tz): Extension<Tz>,
+    base_url: Extension<Url>,
+    opts: Query<RangeOpts>,
+) -> Result<Json<NotePage>, ApiError> {
+    let parse = |s: &str| {
+        s.parse::<NaiveDate>().map_err(|_| {
+            ApiError::new(StatusCode::BAD_REQUEST, "start and end must be YYYY-MM-DD dates")
+        })
+    };
+    let (start, end) = (parse(&opts.start)?, parse(&opts.end)?);
+
+    let notes = notes
+        .date_range(start..end, tz)
+        .await
+        .map_err(|err| {
+            tracing::warn!(?err, %start, %end, "error querying notes by date range");
+            ApiError::internal()
+        })?
+        .unwrap_or_default();
+
+    let notes = notes.iter().map(|n| ApiNote::new(n, &base_url)).collect();
+    Ok(Json(NotePage { notes, next_cursor: None }))
+}
+
+async fn single_note(
+    notes: Extension<NoteService>,
+    base_url: Extension<Url>,
+    Path(note_id): Path<String>,
+) -> Result<Json<ApiNote>, ApiError> {
+    let not_found = || ApiError::new(StatusCode::NOT_FOUND, "note not found");
+    let note_id = note_id.parse::<Uuid>().map_err(|_| not_found())?;
+    let note = notes
+        .by_id(note_id.as_hyphenated())
+        .await
+        .map_err(|err| {
+            tracing::warn!(?err, %note_id, "error querying note by id");
+            ApiError::internal()
+        })?
+        .ok_or_else(not_found)?;
+    Ok(Json(ApiNote::new(&note, &base_url)))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ApiImage {
+    image_id: String,
+    src: Url,
+    thumbnail_src: Url,
+    created_at: String,
+}
+
+impl ApiImage {
+    fn new(image: &Image, base_url: &Url) -> ApiImage {
+        ApiImage {
+            image_id: image.image_id.to_string(),
+            src: base_url.join(&image.main_src()).expect("invalid URL"),
+            thumbnail_src: base_url.join(&image.thumbnail_src()).expect("invalid URL"),
+            created_at: to_rfc3339(&image.created_at),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ImageList {
+    images: Vec<ApiImage>,
+}
+
+async fn list_images(
+    images: Extension<ImageService>,
+    base_url: Extension<Url>,
+    opts: Query<ListOpts>,
+) -> Result<Json<ImageList>, ApiError> {
+    let limit = opts.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    let images = images.most_recent(limit).await.map_err(|err| {
+        tracing::warn!(?err, "error querying images");
+        ApiError::internal()
+    })?;
+    Ok(Json(ImageList { images: images.iter().map(|i| ApiImage::new(i, &base_url)).collect() }))
+}
+
+async fn openapi() -> impl IntoResponse {
+    ([(http::header::CONTENT_TYPE, http::HeaderValue::from_static("application/json"))], OPENAPI)
+}
+
+static OPENAPI: &str = include_str!("openapi.json");
+
+const DEFAULT_LIMIT: u16 = 20;
+
+const MAX_LIMIT: u16 = 100;
+
+fn to_rfc3339(t: &NaiveDateTime) -> String {
+    DateTime::<Utc>::from_utc(*t, Utc).to_rfc3339()
+}
+
+/// Encodes a pagination cursor as an opaque, URL-safe string.
+fn encode_cursor(created_at: &NaiveDateTime, note_id: &Hyphenated) -> String {
+    base64::encode_config(
+        format!("{}|{}", created_at.format(CURSOR_TIME_FORMAT), note_id),
+        base64::URL_SAFE_NO_PAD,
+    )
+}
+
+fn decode_cursor(cursor: &str) -> Result<(NaiveDateTime, Hyphenated), anyhow::Error> {
+    let cursor = String::from_utf8(base64::decode_config(cursor, base64::URL_SAFE_NO_PAD)?)?;
+    let (created_at, note_id) =
+        cursor.split_once('|').ok_or_else(|| anyhow::anyhow!("invalid cursor"))?;
+    Ok((
+        NaiveDateTime::parse_from_str(created_at, CURSOR_TIME_FORMAT)?,
+        note_id.parse::<Uuid>()?.hyphenated(),
+    ))
+}
+
+const CURSOR_TIME_FORMAT: &str = "%F %T%.f";
+
+#[derive(Debug, Deserialize)]
+struct NewNote {
+    body: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CreatedNote {
+    note_id: String,
+    url: Url,
+}
+
+async fn create_note(
+    auth: ApiAuth,
+    notes: Extension<NoteService>,
+    previews: Extension<PreviewService>,
+    base_url: Extension<Url>,
+    Json(new_note): Json<NewNote>,
+) -> Result<(StatusCode, Json<CreatedNote>), ApiError> {
+    auth.require(Scope::NotesWrite)?;
+
+    if new_note.body.trim().is_empty() {
+        return Err(ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, "note body is empty"));
+    }
+
+    let note_id = notes.create(&new_note.body).await.map_err(|err| {
+        tracing::warn!(%err, "error inserting note");
+        ApiError::internal()
+    })?;
+    if let Err(err) = previews.refresh(&note_id, &new_note.body).await {
+        tracing::warn!(?err, %note_id, "unable to fetch link preview");
+    }
+
+    let url = base_url.join(&format!("note/{note_id}")).expect("invalid URL");
+    Ok((StatusCode::CREATED, Json(CreatedNote { note_id: note_id.to_string(), url })))
+}
+
+#[derive(Debug, Deserialize)]
+struct UploadOpts {
+    filename: Option<String>,
+}
+
+async fn upload_image(
+    auth: ApiAuth,
+    images: Extension<ImageService>,
+    base_url: Extension<Url>,
+    opts: Query<UploadOpts>,
+    headers: http::HeaderMap,
+    body: BodyStream,
+) -> Result<(StatusCode, Json<ApiImage>), ApiError> {
+    auth.require(Scope::ImagesWrite)?;
+
+    let content_type = headers
+        .get(http::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|s| s.parse::<mime::Mime>().ok())
+        .filter(|ct| ct.type_() == mime::IMAGE)
+        .ok_or_else(|| {
+            ApiError::new(StatusCode::UNSUPPORTED_MEDIA_TYPE, "Content-Type must be an image type")
+        })?;
+
+    let original_filename = opts.filename.as_deref().unwrap_or("none");
+    let image_id = images.add(original_filename, &content_type, body).await.map_err(|err| {
+        tracing::warn!(%err, "unable to add image");
+        ApiError::internal()
+    })?;
+    let image = images
+        .by_id(&image_id)
+        .await
+        .map_err(|err| {
+            tracing::warn!(%err, "unable to query image");
+            ApiError::internal()
+        })?
+        .ok_or_else(ApiError::internal)?;
+
+    Ok((StatusCode::CREATED, Json(ApiImage::new(&image, &base_url))))
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::SqlitePool;
+    use tempdir::TempDir;
+    use tokio::fs;
+    use uuid::Uuid;
+
+    use crate::test_server::TestServer;
+
+    use super::*;
+
+    #[sqlx::test(fixtures("notes"))]
+    async fn paginating_notes(db: SqlitePool) -> Result<(), anyhow::Error> {
+        let temp_dir = TempDir::new("yellhole-test")?;
+        let (_, _, app) = app(&db, &temp_dir)?;
+        let ts = TestServer::new(app)?;
+
+        let resp = ts.get("/api/v1/notes?limit=2").send().await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let page = resp.json::<NotePage>().await?;
+        assert_eq!(
+            page.notes.iter().map(|n| n.note_id.as_str()).collect::<Vec<&str>>(),
+            vec!["69b124f0-a4fa-40d0-83f4-06bc4213f3ca", "c1449d6c-6b5b-4ce4-a4d7-98853562fbf1"]
+        );
+        assert_eq!(page.notes[0].html, "<p>It\u{2019}s a me, <em>Mario</em>.</p>\n");
+        assert_eq!(page.notes[0].created_at, "2022-11-14T18:22:00+00:00");
+
+        let cursor = page.next_cursor.expect("missing cursor");
+        let resp = ts.get(&format!("/api/v1/notes?limit=2&cursor={cursor}")).send().await?;
+        let page = resp.json::<NotePage>().await?;
+        assert_eq!(
+            page.notes.iter().map(|n| n.note_id.as_str()).collect::<Vec<&str>>(),
+            vec!["b0a2170c-5e91-42ad-aa1b-dabc3c6ea5b9"]
+        );
+        assert_eq!(page.next_cursor, None);
+
+        let resp = ts.get("/api/v1/notes?cursor=nope").send().await?;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("notes"))]
+    async fn notes_by_date_range(db: SqlitePool) -> Result<(), anyhow::Error> {
+        let temp_dir = TempDir::new("yellhole-test")?;
+        let (_, _, app) = app(&db, &temp_dir)?;
+        let ts = TestServer::new(app)?;
+
+        let resp = ts.get("/api/v1/notes/range?start=2022-10-01&end=2022-11-01").send().await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let page = resp.json::<NotePage>().await?;
+        assert_eq!(1, page.notes.len());
+        assert_eq!(page.notes[0].body, "# Hello, it is a header.\n\n## A Subheader");
+
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("notes"))]
+    async fn single_note(db: SqlitePool) -> Result<(), anyhow::Error> {
+        let temp_dir = TempDir::new("yellhole-test")?;
+        let (_, _, app) = app(&db, &temp_dir)?;
+        let ts = TestServer::new(app)?;
+
+        let resp = ts.get("/api/v1/notes/c1449d6c-6b5b-4ce4-a4d7-98853562fbf1").send().await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let note = resp.json::<ApiNote>().await?;
+        assert_eq!(
+            note.html,
+            "<h2 id=\"c1449d6c-6b5b-4ce4-a4d7-98853562fbf1-hello-it-is-a-header\">\
+             Hello, it is a header.</h2>\n\
+             <h3 id=\"c1449d6c-6b5b-4ce4-a4d7-98853562fbf1-a-subheader\">A Subheader</h3>\n"
+        );
+        assert_eq!(
+            note.url.as_str(),
+            "http://example.com/note/c1449d6c-6b5b-4ce4-a4d7-98853562fbf1"
+        );
+
+        let resp = ts.get("/api/v1/notes/37c615b0-bb55-424d-a813-69e14ca5c20c").send().await?;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("images"))]
+    async fn listing_images(db: SqlitePool) -> Result<(), anyhow::Error> {
+        let temp_dir = TempDir::new("yellhole-test")?;
+        let (_, _, app) = app(&db, &temp_dir)?;
+        let ts = TestServer::new(app)?;
+
+        let resp = ts.get("/api/v1/images?limit=2").send().await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let images = resp.json::<ImageList>().await?;
+        assert_eq!(
+            images.images.iter().map(|i| i.src.as_str()).collect::<Vec<&str>>(),
+            vec![
+                "http://example.com/images/4c89cfef-9031-49c0-8b91-2578c0e227f3.main.webp",
+                "http://example.com/images/7963d8bc-9cf8-4459-a593-b6d49b94b541.main.webp",
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn openapi_document() -> Result<(), anyhow::Error> {
+        let ts = TestServer::new(public_router(&HttpConfig::default()))?;
+
+        let resp = ts.get("/api/v1/openapi.json").send().await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let doc = resp.json::<serde_json::Value>().await?;
+        assert_eq!(doc["openapi"], "3.0.3");
+        assert!(doc["paths"]["/api/v1/notes/{noteId}"].is_object());
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn creating_a_note(db: SqlitePool) -> Result<(), anyhow::Error> {
+        let temp_dir = TempDir::new("yellhole-test")?;
+        let (notes, tokens, app) = app(&db, &temp_dir)?;
+        let ts = TestServer::new(app)?;
+        let token = tokens.create("test", &[Scope::NotesWrite], None).await?;
+
+        let resp = ts
+            .post("/api/v1/notes")
+            .bearer_auth(&token)
+            .json(&serde_json::json!({"body": "This is a note."}))
+            .send()
+            .await?;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        let created = resp.json::<CreatedNote>().await?;
+        assert_eq!(created.url.as_str(), format!("http://example.com/note/{}", created.note_id));
+
+        let note_id = created.note_id.parse::<Uuid>()?;
+        let note = notes.by_id(note_id.as_hyphenated()).await?.expect("missing note");
+        assert_eq!(note.body, "This is a note.");
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn authentication_and_scopes(db: SqlitePool) -> Result<(), anyhow::Error> {
+        let temp_dir = TempDir::new("yellhole-test")?;
+        let (_, tokens, app) = app(&db, &temp_dir)?;
+        let ts = TestServer::new(app)?;
+        let note = serde_json::json!({"body": "This is a note."});
+
+        let resp = ts.post("/api/v1/notes").json(&note).send().await?;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let resp = ts.post("/api/v1/notes").bearer_auth("yh_nope").json(&note).send().await?;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let token = tokens.create("images only", &[Scope::ImagesWrite], None).await?;
+        let resp = ts.post("/api/v1/notes").bearer_auth(&token).json(&note).send().await?;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let token = tokens.create("expired", &[Scope::NotesWrite], Some(0)).await?;
+        let resp = ts.post("/api/v1/notes").bearer_auth(&token).json(&note).send().await?;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn uploading_an_image(db: SqlitePool) -> Result<(), anyhow::Error> {
+        let temp_dir = TempDir::new("yellhole-test")?;
+        let (_, tokens, app) = app(&db, &temp_dir)?;
+        let ts = TestServer::new(app)?;
+        let token = tokens.create("test", &[Scope::ImagesWrite], None).await?;
+
+        let img = fs::read("yellhole.webp").await?;
+        let resp = ts
+            .post("/api/v1/images?filename=yellhole.webp")
+            .bearer_auth(&token)
+            .header(http::header::CONTENT_TYPE, "image/webp")
+            .body(img)
+            .send()
+            .await?;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        let uploaded = resp.json::<ApiImage>().await?;
+        assert_eq!(
+            uploaded.src.as_str(),
+            format!("http://example.com/images/{}.main.webp", uploaded.image_id)
+        );
+
+        Ok(())
+    }
+
+    fn app(
+        db: &SqlitePool,
+        temp_dir: &TempDir,
+    ) -> Result<(NoteService, TokenService, Router), anyhow::Error> {
+        let notes = NoteService::new(db.clone());
+        let tokens = TokenService::new(db.clone());
+        let images = ImageService::new(db.clone(), temp_dir)?;
+        Ok((
+            notes.clone(),
+            tokens.clone(),
+            router(&HttpConfig::default())
+                .merge(public_router(&HttpConfig::default()))
+                .layer(Extension(PreviewService::new(db.clone(), images.clone())))
+                .layer(Extension(images))
+                .layer(Extension(notes))
+                .layer(Extension(tokens))
+                .layer(Extension("http://example.com".parse::<Url>()?))
+                .layer(Extension(chrono_tz::UTC)),
+        ))
+    }
+}
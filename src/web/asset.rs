@@ -2,16 +2,19 @@ use axum::extract::Path;
 use axum::http::StatusCode;
 use axum::response::{IntoResponse, Response};
 use axum::routing::{get, get_service};
-use axum::{http, Router};
+use axum::{http, Extension, Router};
 use include_dir::{include_dir, Dir};
 use tokio::io;
 use tower::ServiceBuilder;
 use tower_http::services::ServeDir;
 use tower_http::set_header::SetResponseHeaderLayer;
 
-pub fn router(images_dir: impl AsRef<std::path::Path>) -> Router {
+use super::theme::Theme;
+use crate::config::HttpConfig;
+
+pub fn router(images_dir: impl AsRef<std::path::Path>, theme: &Theme, http: &HttpConfig) -> Router {
     Router::new()
-        .route("/assets/*path", get(static_path))
+        .route("/assets/*path", get(static_path).layer(Extension(theme.clone())))
         .nest(
             "/images",
             get_service(ServiceBuilder::new().service(ServeDir::new(images_dir)))
@@ -19,14 +22,27 @@ pub fn router(images_dir: impl AsRef<std::path::Path>) -> Router {
         )
         .layer(SetResponseHeaderLayer::overriding(
             http::header::CACHE_CONTROL,
-            http::HeaderValue::from_static("max-age=31536000,immutable"),
+            http.immutable_cache_control(),
         ))
 }
 
-async fn static_path(Path(path): Path<String>) -> Result<Response, StatusCode> {
+async fn static_path(
+    Extension(theme): Extension<Theme>,
+    Path(path): Path<String>,
+) -> Result<Response, StatusCode> {
     let path = path.trim_start_matches('/');
     let mime_type = mime_guess::from_path(path).first_or_octet_stream();
     let content_type = http::HeaderValue::from_str(mime_type.as_ref()).expect("invalid header");
+
+    // Prefer the theme's version of the asset, if it has one.
+    if let Some(themed) = theme.asset(path) {
+        let contents = tokio::fs::read(&themed).await.map_err(|err| {
+            tracing::warn!(%err, ?themed, "error reading theme asset");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+        return Ok(([(http::header::CONTENT_TYPE, content_type)], contents).into_response());
+    }
+
     let file = STATIC_DIR.get_file(path).ok_or(StatusCode::NOT_FOUND)?;
     Ok(([(http::header::CONTENT_TYPE, content_type)], file.contents()).into_response())
 }
@@ -36,7 +52,7 @@ async fn io_error(err: io::Error) -> StatusCode {
     StatusCode::INTERNAL_SERVER_ERROR
 }
 
-static STATIC_DIR: Dir<'_> = include_dir!("$CARGO_MANIFEST_DIR/assets");
+pub(super) static STATIC_DIR: Dir<'_> = include_dir!("$CARGO_MANIFEST_DIR/assets");
 
 #[cfg(test)]
 mod tests {
@@ -46,7 +62,28 @@ mod tests {
 
     #[tokio::test]
     async fn static_asset() -> Result<(), anyhow::Error> {
-        let ts = TestServer::new(router("."))?;
+        let ts = TestServer::new(router(".", &Theme::default(), &HttpConfig::default()))?;
+
+        let resp = ts.get("/assets/css/mvp-1.12.css").send().await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers().get(http::header::CONTENT_TYPE),
+            Some(&http::HeaderValue::from_static("text/css")),
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn theme_asset() -> Result<(), anyhow::Error> {
+        let data_dir = tempdir::TempDir::new("yellhole-test")?;
+        let css_dir = data_dir.path().join("themes").join("assets").join("css");
+        std::fs::create_dir_all(&css_dir)?;
+        std::fs::write(css_dir.join("mvp-1.12.css"), "body { color: hotpink; }")?;
+        std::fs::write(data_dir.path().join("secret.txt"), "shh")?;
+
+        let theme = Theme::load(data_dir.path())?;
+        let ts = TestServer::new(router(".", &theme, &HttpConfig::default()))?;
 
         let resp = ts.get("/assets/css/mvp-1.12.css").send().await?;
         assert_eq!(resp.status(), StatusCode::OK);
@@ -54,13 +91,17 @@ mod tests {
             resp.headers().get(http::header::CONTENT_TYPE),
             Some(&http::HeaderValue::from_static("text/css")),
         );
+        assert_eq!(resp.text().await?, "body { color: hotpink; }");
+
+        let resp = ts.get("/assets/..%2F..%2Fsecret.txt").send().await?;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
 
         Ok(())
     }
 
     #[tokio::test]
     async fn image() -> Result<(), anyhow::Error> {
-        let ts = TestServer::new(router("."))?;
+        let ts = TestServer::new(router(".", &Theme::default(), &HttpConfig::default()))?;
 
         let resp = ts.get("/images/LICENSE").send().await?;
         assert_eq!(resp.status(), StatusCode::OK);
@@ -1,20 +1,33 @@
+use std::time::Duration;
+
 use askama::Template;
-use axum::extract::{FromRequest, RequestParts};
+use axum::extract::{FromRequest, Query, RequestParts};
 use axum::http::StatusCode;
 use axum::response::{IntoResponse, Redirect, Response};
 use axum::routing::{get, post};
-use axum::{Extension, Json, Router};
+use axum::{middleware, Extension, Json, Router};
+use axum_sessions::async_session::Session;
 use axum_sessions::extractors::{ReadableSession, WritableSession};
+use serde::Deserialize;
 use uuid::Uuid;
 
-use super::Page;
+use super::rate_limit::{self, Limit, RateLimiter};
+use super::{csrf, ClientInfo, Page, Site};
 use crate::config::Author;
+use crate::services::audit::{AuditService, AuthEventKind};
 use crate::services::passkeys::{
     AuthenticationChallenge, AuthenticationResponse, PasskeyService, RegistrationChallenge,
     RegistrationResponse,
 };
 
 pub fn router() -> Router {
+    // Allow bursts of 10 requests per IP address, refilling at 10 requests a minute, and bursts of
+    // 100 requests overall, refilling at 100 requests a minute.
+    let limiter = RateLimiter::new(
+        Limit { burst: 10, period: Duration::from_secs(6) },
+        Limit { burst: 100, period: Duration::from_millis(600) },
+    );
+
     Router::new()
         .route("/register", get(register))
         .route("/register/start", post(register_start))
@@ -22,6 +35,10 @@ pub fn router() -> Router {
         .route("/login", get(login))
         .route("/login/start", post(login_start))
         .route("/login/finish", post(login_finish))
+        .route("/logout", post(logout).route_layer(middleware::from_fn(csrf::verify)))
+        .route_layer(middleware::from_fn(move |req, next| {
+            rate_limit::limit(req, next, limiter.clone())
+        }))
 }
 
 pub struct RequireAuth;
@@ -45,27 +62,54 @@ where
 
 #[derive(Debug, Template)]
 #[template(path = "register.html")]
-struct RegisterPage {}
+struct RegisterPage {
+    site: Site,
+}
+
+#[derive(Debug, Deserialize)]
+struct RegisterOpts {
+    token: Option<String>,
+}
 
 async fn register(
+    site: Site,
     passkeys: Extension<PasskeyService>,
-    session: ReadableSession,
+    mut session: WritableSession,
+    opts: Query<RegisterOpts>,
+    client: ClientInfo,
 ) -> Result<Response, StatusCode> {
-    let registered = passkeys.any_registered().await.map_err(|err| {
-        tracing::warn!(%err, "unable to query DB");
-        StatusCode::INTERNAL_SERVER_ERROR
-    })?;
-    if registered && !session.get::<bool>("authenticated").unwrap_or(false) {
+    // Store a valid recovery token in the session so it can be redeemed on registration.
+    if let Some(token) = &opts.token {
+        if passkeys.recovery_token_is_valid(token).await.map_err(|err| {
+            tracing::warn!(%err, "unable to query DB");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })? {
+            tracing::warn!(?client, "starting passkey recovery");
+            session.insert(RECOVERY_TOKEN, token).map_err(|err| {
+                tracing::warn!(%err, "unable to store recovery token in session");
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?;
+        } else {
+            tracing::warn!(?client, "invalid or expired recovery token");
+        }
+    }
+
+    if !may_register(&passkeys, &session).await? {
         return Ok(Redirect::to("/login").into_response());
     }
 
-    Ok(Page(RegisterPage {}).into_response())
+    Ok(Page(RegisterPage { site }).into_response())
 }
 
 async fn register_start(
     passkeys: Extension<PasskeyService>,
+    session: ReadableSession,
     Extension(Author(author)): Extension<Author>,
 ) -> Result<Json<RegistrationChallenge>, StatusCode> {
+    if !may_register(&passkeys, &session).await? {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
     passkeys
         .start_registration(&author, Uuid::default().as_hyphenated().to_string().as_bytes())
         .await
@@ -78,21 +122,84 @@ async fn register_start(
 
 async fn register_finish(
     passkeys: Extension<PasskeyService>,
+    audit: Extension<AuditService>,
+    mut session: WritableSession,
+    client: ClientInfo,
     Json(resp): Json<RegistrationResponse>,
 ) -> Result<Response, StatusCode> {
-    passkeys.finish_registration(resp).await.map_err(|err| {
-        tracing::warn!(%err, "unable to finish passkey registration");
+    let registered = passkeys.any_registered().await.map_err(|err| {
+        tracing::warn!(%err, "unable to query DB");
         StatusCode::INTERNAL_SERVER_ERROR
     })?;
 
+    // If passkeys are registered and the client isn't logged in, it must redeem a recovery token.
+    // The token is only redeemed once the passkey is registered, so a failed attempt can be retried.
+    let kind = if registered && !session.get::<bool>("authenticated").unwrap_or(false) {
+        let token = session.get::<String>(RECOVERY_TOKEN).ok_or(StatusCode::FORBIDDEN)?;
+        let invalid_token = || {
+            tracing::warn!(?client, "invalid or expired recovery token");
+            StatusCode::FORBIDDEN
+        };
+        if !passkeys.recovery_token_is_valid(&token).await.map_err(|err| {
+            tracing::warn!(%err, "unable to query DB");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })? {
+            return Err(invalid_token());
+        }
+        if !passkeys.finish_recovery_registration(&token, resp).await.map_err(|err| {
+            tracing::warn!(%err, "unable to finish passkey registration");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })? {
+            return Err(invalid_token());
+        }
+        session.remove(RECOVERY_TOKEN);
+        AuthEventKind::RecoveryRegistration
+    } else {
+        passkeys.finish_registration(resp).await.map_err(|err| {
+            tracing::warn!(%err, "unable to finish passkey registration");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+        AuthEventKind::Registration
+    };
+
+    record(&audit, kind, &client).await?;
+
     Ok(StatusCode::CREATED.into_response())
 }
 
+/// The session key for a passkey recovery token.
+const RECOVERY_TOKEN: &str = "recovery_token";
+
+/// Returns `true` if the session may register a new passkey: if no passkeys are registered, if the
+/// session is authenticated, or if the session has a valid recovery token.
+async fn may_register(passkeys: &PasskeyService, session: &Session) -> Result<bool, StatusCode> {
+    if session.get::<bool>("authenticated").unwrap_or(false) {
+        return Ok(true);
+    }
+
+    let query = async {
+        if !passkeys.any_registered().await? {
+            return Ok(true);
+        }
+        match session.get::<String>(RECOVERY_TOKEN) {
+            Some(token) => passkeys.recovery_token_is_valid(&token).await,
+            None => Ok(false),
+        }
+    };
+    query.await.map_err(|err: sqlx::Error| {
+        tracing::warn!(%err, "unable to query DB");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })
+}
+
 #[derive(Debug, Template)]
 #[template(path = "login.html")]
-struct LoginPage {}
+struct LoginPage {
+    site: Site,
+}
 
 async fn login(
+    site: Site,
     passkeys: Extension<PasskeyService>,
     session: ReadableSession,
 ) -> Result<Response, StatusCode> {
@@ -108,7 +215,7 @@ async fn login(
         return Ok(Redirect::to("/register").into_response());
     }
 
-    Ok(Page(LoginPage {}).into_response())
+    Ok(Page(LoginPage { site }).into_response())
 }
 
 async fn login_start(
@@ -132,13 +239,17 @@ async fn login_start(
 
 async fn login_finish(
     passkeys: Extension<PasskeyService>,
+    audit: Extension<AuditService>,
     mut session: WritableSession,
+    client: ClientInfo,
     Json(auth): Json<AuthenticationResponse>,
 ) -> Result<Response, StatusCode> {
-    let challenge = session.get::<[u8; 32]>("challenge").ok_or_else(|| {
-        tracing::warn!("no stored authentication state");
-        StatusCode::BAD_REQUEST
-    })?;
+    // Record finish requests without a challenge as failures, as they may be replayed or forged.
+    let Some(challenge) = session.get::<[u8; 32]>("challenge") else {
+        tracing::warn!(?client, "no stored authentication state");
+        login_failed(&audit, &client).await?;
+        return Err(StatusCode::BAD_REQUEST);
+    };
     session.remove("challenge");
 
     let authenticated = passkeys.finish_authentication(auth, challenge).await.map_err(|err| {
@@ -147,19 +258,56 @@ async fn login_finish(
     })?;
 
     if authenticated {
-        session.insert("authenticated", true).map_err(|err| {
-            tracing::warn!(%err, "unable to store authentication state in session");
-            StatusCode::INTERNAL_SERVER_ERROR
-        })?;
+        metrics::increment_counter!("yellhole_logins_total", "result" => "success");
+        record(&audit, AuthEventKind::LoginSucceeded, &client).await?;
+        session
+            .insert("authenticated", true)
+            .and_then(|_| session.insert("user_agent", client.user_agent))
+            .and_then(|_| session.insert("ip_address", client.ip_address))
+            .map_err(|err| {
+                tracing::warn!(%err, "unable to store authentication state in session");
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?;
         Ok(StatusCode::ACCEPTED.into_response())
     } else {
+        login_failed(&audit, &client).await?;
         Ok(StatusCode::BAD_REQUEST.into_response())
     }
 }
 
+/// Counts and records a failed login.
+async fn login_failed(audit: &AuditService, client: &ClientInfo) -> Result<(), StatusCode> {
+    metrics::increment_counter!("yellhole_logins_total", "result" => "failure");
+    record(audit, AuthEventKind::LoginFailed, client).await
+}
+
+async fn logout(
+    audit: Extension<AuditService>,
+    client: ClientInfo,
+    mut session: WritableSession,
+) -> Result<Redirect, StatusCode> {
+    record(&audit, AuthEventKind::Logout, &client).await?;
+    session.destroy();
+    Ok(Redirect::to("/login"))
+}
+
+/// Records an authentication event for the client.
+pub async fn record(
+    audit: &AuditService,
+    kind: AuthEventKind,
+    client: &ClientInfo,
+) -> Result<(), StatusCode> {
+    audit.record(kind, client.ip_address.as_deref(), client.user_agent.as_deref()).await.map_err(
+        |err| {
+            tracing::warn!(%err, %kind, "unable to record auth event");
+            StatusCode::INTERNAL_SERVER_ERROR
+        },
+    )
+}
+
 #[cfg(test)]
 mod tests {
-    use axum::{http, middleware};
+    use axum::http;
     use axum_sessions::async_session::MemoryStore;
     use axum_sessions::SessionLayer;
     use p256::ecdsa::signature::Signer;
@@ -171,7 +319,7 @@ mod tests {
     use sqlx::SqlitePool;
     use url::Url;
 
-    use crate::config::{Author, Title};
+    use crate::config::{Author, ClientIpHeader, Title};
     use crate::test_server::TestServer;
 
     use super::*;
@@ -226,7 +374,9 @@ mod tests {
 
     #[sqlx::test]
     async fn passkey_registration_and_login(db: SqlitePool) -> Result<(), anyhow::Error> {
-        let ts = TestServer::new(app(db))?;
+        let ts = TestServer::new(app(db.clone()))?;
+
+        let audit = AuditService::new(db.clone());
 
         // Try a protected route. We should be blocked.
         let protected = ts.get("/protected").send().await?;
@@ -304,6 +454,159 @@ mod tests {
         let protected = ts.get("/protected").send().await?;
         assert_eq!(protected.status(), StatusCode::OK);
 
+        // Log out.
+        let token = ts.get("/token").send().await?.text().await?;
+        let logout = ts.post("/logout").form(&[("csrf_token", token)]).send().await?;
+        assert_eq!(logout.status(), StatusCode::SEE_OTHER);
+
+        // Try the protected resource one last time. We should be blocked again.
+        let protected = ts.get("/protected").send().await?;
+        assert_eq!(protected.status(), StatusCode::SEE_OTHER);
+
+        // Check the audit log.
+        let events = audit.most_recent(10).await?;
+        assert_eq!(
+            events.iter().map(|e| e.kind.as_str()).collect::<Vec<&str>>(),
+            vec!["logout", "login-succeeded", "registration"]
+        );
+
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("fake_passkey"))]
+    async fn registered_register_start(db: SqlitePool) -> Result<(), anyhow::Error> {
+        let ts = TestServer::new(app(db))?;
+
+        let resp = ts.post("/register/start").send().await?;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("fake_passkey"))]
+    async fn passkey_recovery(db: SqlitePool) -> Result<(), anyhow::Error> {
+        let ts = TestServer::new(app(db.clone()))?;
+
+        let passkeys = PasskeyService::new(db.clone(), &"http://example.com".parse()?);
+        let audit = AuditService::new(db);
+        let token = passkeys.create_recovery_token(Duration::from_secs(60)).await?;
+
+        // An invalid token doesn't allow registration.
+        let resp = ts.get("/register?token=nope").send().await?;
+        assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+
+        // A valid token does.
+        let resp = ts.get(&format!("/register?token={token}")).send().await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let reg_start =
+            ts.post("/register/start").send().await?.json::<RegistrationChallenge>().await?;
+
+        // Generate a P-256 ECDSA key pair and authenticator data.
+        let signing_key = SigningKey::random(&mut thread_rng());
+        let public_key =
+            PublicKey::from(signing_key.verifying_key()).to_public_key_der()?.into_vec();
+        let key_id = Sha256::new().chain_update(&public_key).finalize().to_vec();
+        let mut authenticator_data = Vec::new();
+        authenticator_data.extend(Sha256::new().chain_update(&reg_start.rp_id).finalize());
+        authenticator_data.extend([1]); // flags
+        authenticator_data.extend([0; 20]); // unused
+        authenticator_data.extend(32u16.to_be_bytes());
+        authenticator_data.extend(&key_id);
+
+        // A failed registration doesn't use up the token.
+        let reg_finish = ts
+            .post("/register/finish")
+            .json(&RegistrationResponse {
+                authenticator_data: authenticator_data.clone(),
+                client_data_json: r#"{"type":"webauthn.create","origin":"http://evil.com"}"#
+                    .as_bytes()
+                    .to_vec(),
+                public_key: public_key.clone(),
+            })
+            .send()
+            .await?;
+        assert_eq!(reg_finish.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(passkeys.recovery_token_is_valid(&token).await?);
+
+        let reg_finish = ts
+            .post("/register/finish")
+            .json(&RegistrationResponse {
+                authenticator_data,
+                client_data_json: r#"{"type":"webauthn.create","origin":"http://example.com"}"#
+                    .as_bytes()
+                    .to_vec(),
+                public_key,
+            })
+            .send()
+            .await?;
+        assert_eq!(reg_finish.status(), StatusCode::CREATED);
+        assert!(passkeys.all().await?.iter().any(|p| p.passkey_id == key_id));
+
+        // The token can only be used once.
+        let resp = ts.get(&format!("/register?token={token}")).send().await?;
+        assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+        let resp = ts.post("/register/start").send().await?;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let events = audit.most_recent(1).await?;
+        assert_eq!(events[0].kind, "recovery-registration");
+
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("fake_passkey"))]
+    async fn login_without_challenge(db: SqlitePool) -> Result<(), anyhow::Error> {
+        let ts = TestServer::new(app(db.clone()))?;
+
+        let resp = ts
+            .post("/login/finish")
+            .json(&AuthenticationResponse {
+                raw_id: vec![1; 32],
+                authenticator_data: vec![],
+                client_data_json: vec![],
+                signature: vec![],
+            })
+            .send()
+            .await?;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let events = AuditService::new(db).most_recent(1).await?;
+        assert_eq!(events[0].kind, "login-failed");
+
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("fake_passkey"))]
+    async fn rate_limited_login(db: SqlitePool) -> Result<(), anyhow::Error> {
+        let ts = TestServer::new(app(db.clone()))?;
+
+        for _ in 0..10 {
+            let resp = ts.post("/login/start").send().await?;
+            assert_eq!(resp.status(), StatusCode::OK);
+        }
+
+        let resp = ts.post("/login/start").send().await?;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        // Spoofed forwarding headers don't get a fresh bucket.
+        let resp = ts.post("/login/start").header("x-forwarded-for", "203.0.113.1").send().await?;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        // Behind a configured proxy, each forwarded client gets its own bucket.
+        let header = "Fly-Client-IP".parse::<ClientIpHeader>()?;
+        let ts = TestServer::new(app(db).layer(Extension(header)))?;
+        for _ in 0..10 {
+            let resp =
+                ts.post("/login/start").header("fly-client-ip", "203.0.113.1").send().await?;
+            assert_eq!(resp.status(), StatusCode::OK);
+        }
+
+        let resp = ts.post("/login/start").header("fly-client-ip", "203.0.113.1").send().await?;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        let resp = ts.post("/login/start").header("fly-client-ip", "203.0.113.2").send().await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+
         Ok(())
     }
 
@@ -312,20 +615,26 @@ mod tests {
         let session_layer = SessionLayer::new(store, &[69; 64])
             .with_secure(false)
             .with_same_site_policy(axum_sessions::SameSite::None);
+        let base_url = "http://example.com".parse::<Url>().unwrap();
         Router::new()
             .route("/protected", get(protected))
             .route_layer(middleware::from_extractor::<RequireAuth>())
+            .route("/token", get(token))
             .merge(router())
-            .layer(Extension(PasskeyService::new(
-                db,
-                &"http://example.com".parse::<Url>().unwrap(),
-            )))
+            .layer(Extension(PasskeyService::new(db.clone(), &base_url)))
+            .layer(Extension(AuditService::new(db)))
+            .layer(Extension(base_url))
             .layer(Extension(Author("Mr Magoo".into())))
             .layer(Extension(Title("Yellhole".into())))
+            .layer(Extension(chrono_tz::UTC))
             .layer(session_layer)
     }
 
     async fn protected() -> &'static str {
         "secure"
     }
+
+    async fn token(csrf::CsrfToken(token): csrf::CsrfToken) -> String {
+        token
+    }
 }
@@ -0,0 +1,228 @@
+use axum::body::{Body, Bytes};
+use axum::extract::{FromRequest, RequestParts};
+use axum::http::{self, Method, StatusCode};
+use axum::middleware::Next;
+use axum::response::Response;
+use axum_sessions::async_session::Session;
+use axum_sessions::SessionHandle;
+use constant_time_eq::constant_time_eq;
+use rand::{thread_rng, Rng};
+use url::Url;
+
+/// The name of the form field and session key containing the CSRF token.
+const FIELD_NAME: &str = "csrf_token";
+
+/// The name of the header which may contain the CSRF token.
+const HEADER_NAME: &str = "x-csrf-token";
+
+/// The session's synchronizer token, generated if the session doesn't have one yet. Must be
+/// extracted before any other session extractors.
+#[derive(Debug)]
+pub struct CsrfToken(pub String);
+
+#[axum::async_trait]
+impl<B> FromRequest<B> for CsrfToken
+where
+    B: Send,
+{
+    type Rejection = StatusCode;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let session = req.extensions().get::<SessionHandle>().cloned().ok_or_else(|| {
+            tracing::warn!("no session for CSRF token");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+        let mut session = session.write().await;
+        if let Some(token) = session.get::<String>(FIELD_NAME) {
+            return Ok(CsrfToken(token));
+        }
+
+        let token = hex::encode(thread_rng().gen::<[u8; 32]>());
+        session.insert(FIELD_NAME, &token).map_err(|err| {
+            tracing::warn!(%err, "unable to store CSRF token in session");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+        Ok(CsrfToken(token))
+    }
+}
+
+/// Rejects any unsafe requests which don't come from the same origin as the base URL or which don't
+/// include the session's CSRF token in the `X-CSRF-Token` header or the `csrf_token` field of a
+/// URL-encoded form. Tokens in URLs, which end up in logs and `Referer` headers, are rejected.
+pub async fn verify(req: http::Request<Body>, next: Next<Body>) -> Result<Response, StatusCode> {
+    if matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS) {
+        return Ok(next.run(req).await);
+    }
+
+    // Check the request's Origin or Referer against the base URL.
+    let base_url = req.extensions().get::<Url>().ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
+    let origin = req
+        .headers()
+        .get(http::header::ORIGIN)
+        .or_else(|| req.headers().get(http::header::REFERER))
+        .map(|v| v.to_str().ok().and_then(|s| s.parse::<Url>().ok()));
+    if let Some(origin) = origin {
+        if origin.map(|o| o.origin()) != Some(base_url.origin()) {
+            tracing::warn!(headers=?req.headers(), "cross-origin request");
+            return Err(StatusCode::FORBIDDEN);
+        }
+    }
+
+    // Find the session's token.
+    let expected = match req.extensions().get::<SessionHandle>() {
+        Some(session) => session.read().await.get::<String>(FIELD_NAME),
+        None => None,
+    };
+    let Some(expected) = expected else {
+        tracing::warn!("no CSRF token in session");
+        return Err(StatusCode::FORBIDDEN);
+    };
+
+    // Find the request's token, buffering the body if it's a URL-encoded form.
+    let (req, actual) = if let Some(token) = header_token(&req) {
+        (req, Some(token))
+    } else if is_form(&req) {
+        let (parts, body) = req.into_parts();
+        let body = hyper::body::to_bytes(body).await.map_err(|_| StatusCode::BAD_REQUEST)?;
+        let token = form_token(&body);
+        (http::Request::from_parts(parts, Body::from(body)), token)
+    } else {
+        (req, None)
+    };
+
+    if !actual.map(|t| constant_time_eq(t.as_bytes(), expected.as_bytes())).unwrap_or(false) {
+        tracing::warn!("missing or invalid CSRF token");
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    Ok(next.run(req).await)
+}
+
+/// Returns `true` if the `X-CSRF-Token` header matches the session's CSRF token.
+pub fn header_matches(headers: &http::HeaderMap, session: &Session) -> bool {
+    match (
+        headers.get(HEADER_NAME).and_then(|v| v.to_str().ok()),
+        session.get::<String>(FIELD_NAME),
+    ) {
+        (Some(actual), Some(expected)) => constant_time_eq(actual.as_bytes(), expected.as_bytes()),
+        _ => false,
+    }
+}
+
+fn header_token<B>(req: &http::Request<B>) -> Option<String> {
+    req.headers().get(HEADER_NAME).and_then(|v| v.to_str().ok()).map(|s| s.to_string())
+}
+
+fn form_token(body: &Bytes) -> Option<String> {
+    url::form_urlencoded::parse(body).find(|(k, _)| k == FIELD_NAME).map(|(_, v)| v.into_owned())
+}
+
+fn is_form<B>(req: &http::Request<B>) -> bool {
+    req.headers()
+        .get(http::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|s| s.parse::<mime::Mime>().ok())
+        .map(|m| m.essence_str() == mime::APPLICATION_WWW_FORM_URLENCODED.as_ref())
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::routing::{get, post};
+    use axum::{middleware, Extension, Router};
+    use axum_sessions::async_session::MemoryStore;
+    use axum_sessions::SessionLayer;
+
+    use crate::test_server::TestServer;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn form_posts() -> Result<(), anyhow::Error> {
+        let ts = TestServer::new(app())?;
+
+        let resp = ts.post("/protected").form(&[("body", "yes")]).send().await?;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let token = ts.get("/token").send().await?.text().await?;
+
+        let resp =
+            ts.post("/protected").form(&[("body", "yes"), ("csrf_token", "bad")]).send().await?;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let resp =
+            ts.post("/protected").form(&[("body", "yes"), ("csrf_token", &token)]).send().await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.text().await?, "body=yes&csrf_token=".to_string() + &token);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn headers() -> Result<(), anyhow::Error> {
+        let ts = TestServer::new(app())?;
+
+        let token = ts.get("/token").send().await?.text().await?;
+
+        let resp = ts.post("/protected").header(HEADER_NAME, &token).send().await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let resp = ts.post(&format!("/protected?csrf_token={token}")).send().await?;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn cross_origin_posts() -> Result<(), anyhow::Error> {
+        let ts = TestServer::new(app())?;
+
+        let token = ts.get("/token").send().await?.text().await?;
+
+        let resp = ts
+            .post("/protected")
+            .header(HEADER_NAME, &token)
+            .header(http::header::ORIGIN, "https://evil.example.com")
+            .send()
+            .await?;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let resp = ts
+            .post("/protected")
+            .header(HEADER_NAME, &token)
+            .header(http::header::REFERER, "https://evil.example.com/form")
+            .send()
+            .await?;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let resp = ts
+            .post("/protected")
+            .header(HEADER_NAME, &token)
+            .header(http::header::ORIGIN, "http://example.com")
+            .send()
+            .await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        Ok(())
+    }
+
+    fn app() -> Router {
+        let session_layer = SessionLayer::new(MemoryStore::new(), &[69; 64])
+            .with_secure(false)
+            .with_same_site_policy(axum_sessions::SameSite::None);
+        Router::new()
+            .route("/protected", post(protected))
+            .route_layer(middleware::from_fn(verify))
+            .route("/token", get(token))
+            .layer(Extension("http://example.com".parse::<Url>().unwrap()))
+            .layer(session_layer)
+    }
+
+    async fn token(CsrfToken(token): CsrfToken) -> String {
+        token
+    }
+
+    async fn protected(body: String) -> String {
+        body
+    }
+}
@@ -1,25 +1,29 @@
-use askama::Template;
-use atom_syndication::{Content, Entry, Feed, FixedDateTime, Link, Person, Text};
+use atom_syndication::{Content, Entry, Feed, Link, Person, Text};
 use axum::extract::{Path, Query};
 use axum::http::{self, StatusCode};
 use axum::response::{IntoResponse, Response};
 use axum::routing::get;
-use axum::{Extension, Router};
-use chrono::{Datelike, FixedOffset, Months, NaiveDate, Utc};
-use serde::Deserialize;
+use axum::{Extension, Json, Router};
+use chrono::{DateTime, Datelike, Months, NaiveDate, Offset, TimeZone, Utc};
+use chrono_tz::Tz;
+use pulldown_cmark::escape::escape_html;
+use scraper::{Html, Selector};
+use serde::{Deserialize, Serialize};
 use tower_http::set_header::SetResponseHeaderLayer;
 use url::Url;
 use uuid::Uuid;
 
-use super::Page;
-use crate::config::{Author, Title};
-use crate::services::notes::{Note, NoteService};
+use super::theme::{Theme, Themed};
+use super::{Site, ThemedPage};
+use crate::config::{FeedConfig, HttpConfig};
+use crate::services::notes::{excerpt, Note, NoteService};
+use crate::services::previews::LinkPreview;
 
-pub fn router() -> Router {
+pub fn router(http: &HttpConfig) -> Router {
     let immutable = Router::new().route("/note/:note_id", get(single)).layer(
         SetResponseHeaderLayer::overriding(
             http::header::CACHE_CONTROL,
-            http::HeaderValue::from_static("max-age=31536000,immutable"),
+            http.immutable_cache_control(),
         ),
     );
 
@@ -27,27 +31,145 @@ pub fn router() -> Router {
         .route("/", get(index))
         .route("/atom.xml", get(atom))
         .route("/notes/:year/:month", get(month))
+        .route("/oembed", get(oembed))
         .layer(SetResponseHeaderLayer::overriding(
             http::header::CACHE_CONTROL,
-            http::HeaderValue::from_static("max-age=300"),
+            http.cache_control(),
         ))
         .merge(immutable)
 }
 
-#[derive(Debug, Template)]
-#[template(path = "feed.html")]
-struct FeedPage {
+#[derive(Debug)]
+pub(super) struct FeedPage {
+    site: Site,
     notes: Vec<Note>,
     base_url: Url,
     newer: Option<NaiveDate>,
     older: Option<NaiveDate>,
+    meta: Option<NoteMeta>,
 }
 
-mod filters {
-    use chrono::{DateTime, Local, NaiveDateTime, TimeZone};
+impl FeedPage {
+    /// The index page, linking to the month of the oldest note.
+    pub(super) fn index(site: Site, notes: Vec<Note>, base_url: Url) -> FeedPage {
+        let older = notes
+            .last()
+            .and_then(|n| site.timezone.from_utc_datetime(&n.created_at).date_naive().with_day(1));
+        FeedPage { site, notes, base_url, newer: None, older, meta: None }
+    }
+
+    /// The page for a month, linking to the closest newer and older months with notes.
+    pub(super) fn month(
+        site: Site,
+        notes: Vec<Note>,
+        base_url: Url,
+        newer: Option<NaiveDate>,
+        older: Option<NaiveDate>,
+    ) -> FeedPage {
+        FeedPage { site, notes, base_url, newer, older, meta: None }
+    }
+
+    /// The page for a single note, with metadata for previews of links to it.
+    pub(super) fn single(site: Site, note: Note, base_url: Url) -> FeedPage {
+        let meta = Some(NoteMeta::new(&site, &note, &base_url));
+        FeedPage { site, notes: vec![note], base_url, newer: None, older: None, meta }
+    }
 
-    pub fn to_local_tz(t: &NaiveDateTime) -> askama::Result<DateTime<Local>> {
-        Ok(Local.from_utc_datetime(t))
+    /// Leaves out the link to the oEmbed endpoint, for static exports, which can't serve it.
+    pub(super) fn without_oembed(mut self) -> FeedPage {
+        if let Some(meta) = &mut self.meta {
+            meta.oembed_url = None;
+        }
+        self
+    }
+}
+
+/// The OpenGraph and Twitter Card metadata of a single note's page.
+#[derive(Debug, Serialize)]
+pub(super) struct NoteMeta {
+    /// The start of the note's text, or the title of its link preview.
+    title: String,
+    /// The page's `<title>`.
+    page_title: String,
+    description: String,
+    url: Url,
+    /// The note's first image, or its link preview's image.
+    image: Option<Url>,
+    published: String,
+    oembed_url: Option<Url>,
+}
+
+impl NoteMeta {
+    fn new(site: &Site, note: &Note, base_url: &Url) -> NoteMeta {
+        let doc = Html::parse_fragment(&note.html);
+        let text = doc.root_element().text().collect::<String>();
+        let text = text.split_whitespace().collect::<Vec<&str>>().join(" ");
+        let title = match (text.is_empty(), &note.preview) {
+            (false, _) => excerpt(&text, MAX_TITLE_LEN),
+            (true, Some(preview)) => preview.title.clone(),
+            (true, None) => String::new(),
+        };
+        let page_title = match title.as_str() {
+            "" => site.title.clone(),
+            title => format!("{title} - {}", site.title),
+        };
+
+        let img = Selector::parse("img[src]").expect("invalid selector");
+        let image = doc
+            .select(&img)
+            .find_map(|el| el.value().attr("src").map(String::from))
+            .or_else(|| note.preview.as_ref().and_then(LinkPreview::image_src))
+            .and_then(|src| base_url.join(&src).ok());
+
+        let url = base_url.join(&format!("note/{}", note.note_id)).expect("invalid URL");
+        let mut oembed_url = base_url.join("oembed").expect("invalid URL");
+        oembed_url.query_pairs_mut().append_pair("url", url.as_str()).append_pair("format", "json");
+
+        NoteMeta {
+            title,
+            page_title,
+            description: excerpt(&text, MAX_DESCRIPTION_LEN),
+            url,
+            image,
+            published: site.timezone.from_utc_datetime(&note.created_at).to_rfc3339(),
+            oembed_url: Some(oembed_url),
+        }
+    }
+}
+
+/// The longest title made from the start of a note, in characters.
+const MAX_TITLE_LEN: usize = 70;
+
+/// The longest description made from the start of a note, in characters.
+const MAX_DESCRIPTION_LEN: usize = 200;
+
+impl Themed for FeedPage {
+    const TEMPLATE: &'static str = "feed.html";
+
+    fn context(&self) -> minijinja::Value {
+        let notes = self
+            .notes
+            .iter()
+            .map(|n| {
+                minijinja::context! {
+                    note_id => n.note_id.to_string(),
+                    body => n.body,
+                    html => n.html,
+                    preview_html => n.preview.as_ref().map(LinkPreview::to_html),
+                    created_at => self.site.timezone.from_utc_datetime(&n.created_at).to_string(),
+                    published => self.site.timezone.from_utc_datetime(&n.created_at).to_rfc3339(),
+                }
+            })
+            .collect::<Vec<minijinja::Value>>();
+        let month = |d: &NaiveDate| minijinja::context! { year => d.year(), month => d.month() };
+        minijinja::context! {
+            site => self.site,
+            notes => notes,
+            base_url => self.base_url.to_string(),
+            newer => self.newer.as_ref().map(month),
+            older => self.older.as_ref().map(month),
+            meta => self.meta,
+        }
     }
 }
 
@@ -57,32 +179,44 @@ struct IndexOpts {
 }
 
 async fn index(
+    site: Site,
+    Extension(theme): Extension<Theme>,
     notes: Extension<NoteService>,
     Extension(base_url): Extension<Url>,
+    Extension(feed): Extension<FeedConfig>,
     opts: Query<IndexOpts>,
-) -> Result<Page<FeedPage>, StatusCode> {
-    let n = opts.n.unwrap_or(100);
+) -> Result<ThemedPage<FeedPage>, StatusCode> {
+    let n = opts.n.unwrap_or(feed.index_length);
     let notes = notes.most_recent(n).await.map_err(|err| {
         tracing::warn!(?err, n, "error querying feed index");
         StatusCode::INTERNAL_SERVER_ERROR
     })?;
 
-    let older = notes.last().and_then(|n| n.created_at.date().with_day(1));
-
-    Ok(Page(FeedPage { notes, base_url, newer: None, older }))
+    Ok(ThemedPage(theme, FeedPage::index(site, notes, base_url)))
 }
 
 async fn atom(
+    site: Site,
     notes: Extension<NoteService>,
     base_url: Extension<Url>,
-    Extension(Author(author)): Extension<Author>,
-    Extension(Title(title)): Extension<Title>,
+    Extension(feed): Extension<FeedConfig>,
 ) -> Result<Response, StatusCode> {
-    let notes = notes.most_recent(20).await.map_err(|err| {
+    let notes = notes.most_recent(feed.atom_length).await.map_err(|err| {
         tracing::warn!(?err, "error querying atom index");
         StatusCode::INTERNAL_SERVER_ERROR
     })?;
 
+    let feed = atom_feed(&notes, &base_url, &site);
+    Ok((
+        [(http::header::CONTENT_TYPE, http::HeaderValue::from_static(mime::TEXT_XML.as_ref()))],
+        feed.to_string(),
+    )
+        .into_response())
+}
+
+/// Returns an Atom feed of the given notes, with timestamps in the site's time zone.
+pub(super) fn atom_feed(notes: &[Note], base_url: &Url, site: &Site) -> Feed {
+    let to_fixed = |t: DateTime<Tz>| t.with_timezone(&t.offset().fix());
     let entries = notes
         .iter()
         .map(|n| Entry {
@@ -90,56 +224,60 @@ async fn atom(
             title: Text { value: n.note_id.to_string(), ..Default::default() },
             content: Some(Content {
                 content_type: Some("html".into()),
-                value: Some(n.to_html()),
+                value: Some(match &n.preview {
+                    Some(preview) => format!("{}{}", n.html, preview.to_html()),
+                    None => n.html.clone(),
+                }),
                 ..Default::default()
             }),
-            updated: FixedDateTime::from_local(n.created_at, FixedOffset::east_opt(0).unwrap()),
+            updated: to_fixed(site.timezone.from_utc_datetime(&n.created_at)),
             ..Default::default()
         })
         .collect();
 
-    let feed = Feed {
+    Feed {
         id: base_url.to_string(),
-        authors: vec![Person { name: author, ..Default::default() }],
+        authors: vec![Person { name: site.author.clone(), ..Default::default() }],
         base: Some(base_url.to_string()),
-        title: Text { value: title, ..Default::default() },
+        title: Text { value: site.title.clone(), ..Default::default() },
         entries,
         links: vec![Link { href: base_url.to_string(), rel: "self".into(), ..Default::default() }],
-        updated: FixedDateTime::from_utc(Utc::now().naive_utc(), FixedOffset::east_opt(0).unwrap()),
+        updated: to_fixed(Utc::now().with_timezone(&site.timezone)),
         ..Default::default()
-    };
-
-    Ok((
-        [(http::header::CONTENT_TYPE, http::HeaderValue::from_static(mime::TEXT_XML.as_ref()))],
-        feed.to_string(),
-    )
-        .into_response())
+    }
 }
 
 async fn month(
+    site: Site,
+    Extension(theme): Extension<Theme>,
     notes: Extension<NoteService>,
     Extension(base_url): Extension<Url>,
     Path((year, month)): Path<(i32, u32)>,
-) -> Result<Page<FeedPage>, StatusCode> {
+) -> Result<ThemedPage<FeedPage>, StatusCode> {
     let Some(start) = NaiveDate::from_ymd_opt(year, month, 1) else { return Err(StatusCode::NOT_FOUND)};
     let end = start + Months::new(1);
 
+    let internal_error = |err| {
+        tracing::warn!(?err, year, month, "error querying feed for month");
+        StatusCode::INTERNAL_SERVER_ERROR
+    };
+    let (newer, older) =
+        notes.adjacent_months(start..end, site.timezone).await.map_err(internal_error)?;
     let notes = notes
-        .date_range(start..end)
+        .date_range(start..end, site.timezone)
         .await
-        .map_err(|err| {
-            tracing::warn!(?err, year, month, "error querying feed for month");
-            StatusCode::INTERNAL_SERVER_ERROR
-        })?
+        .map_err(internal_error)?
         .ok_or(StatusCode::NOT_FOUND)?;
-    Ok(Page(FeedPage { notes, base_url, newer: Some(end), older: Some(start - Months::new(1)) }))
+    Ok(ThemedPage(theme, FeedPage::month(site, notes, base_url, newer, older)))
 }
 
 async fn single(
+    site: Site,
+    Extension(theme): Extension<Theme>,
     notes: Extension<NoteService>,
     Extension(base_url): Extension<Url>,
     Path(note_id): Path<String>,
-) -> Result<Page<FeedPage>, StatusCode> {
+) -> Result<ThemedPage<FeedPage>, StatusCode> {
     let note_id = note_id.parse::<Uuid>().map_err(|_| StatusCode::NOT_FOUND)?;
     let note = notes
         .by_id(note_id.as_hyphenated())
@@ -149,13 +287,120 @@ async fn single(
             StatusCode::INTERNAL_SERVER_ERROR
         })?
         .ok_or(StatusCode::NOT_FOUND)?;
-    Ok(Page(FeedPage { notes: vec![note], base_url, newer: None, older: None }))
+    Ok(ThemedPage(theme, FeedPage::single(site, note, base_url)))
+}
+
+#[derive(Debug, Deserialize)]
+struct OEmbedOpts {
+    url: String,
+    format: Option<String>,
+    maxwidth: Option<u32>,
+}
+
+/// An oEmbed response for a note, which embeds it as a quote.
+#[derive(Debug, Serialize)]
+struct OEmbedResponse {
+    version: &'static str,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    title: String,
+    author_name: String,
+    author_url: Url,
+    provider_name: String,
+    provider_url: Url,
+    html: String,
+    width: u32,
+    height: Option<u32>,
+}
+
+/// The width of embedded notes, unless the consumer asks for a narrower one.
+const OEMBED_WIDTH: u32 = 600;
+
+async fn oembed(
+    site: Site,
+    notes: Extension<NoteService>,
+    Extension(base_url): Extension<Url>,
+    Query(opts): Query<OEmbedOpts>,
+) -> Result<Json<OEmbedResponse>, StatusCode> {
+    if opts.format.as_deref().unwrap_or("json") != "json" {
+        return Err(StatusCode::NOT_IMPLEMENTED);
+    }
+
+    // Only embed notes from this site.
+    let url = opts.url.parse::<Url>().map_err(|_| StatusCode::NOT_FOUND)?;
+    let note_id = url
+        .as_str()
+        .strip_prefix(base_url.join("note/").expect("invalid URL").as_str())
+        .and_then(|id| id.parse::<Uuid>().ok())
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let note = notes
+        .by_id(note_id.as_hyphenated())
+        .await
+        .map_err(|err| {
+            tracing::warn!(?err, %note_id, "error querying note for oEmbed");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let meta = NoteMeta::new(&site, &note, &base_url);
+    let mut html = format!("<blockquote class=\"yellhole-note\" cite=\"{}\">\n", meta.url);
+    html.push_str(&absolutize(&note.html, &base_url));
+    if let Some(preview) = &note.preview {
+        html.push_str(&absolutize(&preview.to_html(), &base_url));
+    }
+    html.push_str(&format!("<p>&mdash; <a href=\"{}\">", meta.url));
+    escape_html(&mut html, &site.author).expect("error escaping author");
+    html.push_str(&format!(", {}</a></p>\n</blockquote>", &meta.published[..10]));
+
+    Ok(Json(OEmbedResponse {
+        version: "1.0",
+        kind: "rich",
+        title: meta.title,
+        author_name: site.author,
+        author_url: base_url.clone(),
+        provider_name: site.title,
+        provider_url: base_url,
+        html,
+        width: opts.maxwidth.unwrap_or(OEMBED_WIDTH).min(OEMBED_WIDTH),
+        height: None,
+    }))
+}
+
+/// Makes the root-relative links in `html` absolute, so it can be shown on other sites.
+fn absolutize(html: &str, base_url: &Url) -> String {
+    let base = base_url.as_str().trim_end_matches('/');
+    rewrite_links(html, |url| format!("{base}{url}"))
+}
+
+/// Replaces each root-relative `href` and `src` attribute in `html` with the result of passing it
+/// to `rewrite`.
+pub(super) fn rewrite_links(html: &str, rewrite: impl Fn(&str) -> String) -> String {
+    let mut out = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(i) = rest.find("=\"/") {
+        let (head, tail) = rest.split_at(i + 2);
+        out.push_str(head);
+        rest = tail;
+
+        let is_link = head.ends_with("href=\"") || head.ends_with("src=\"");
+        let Some(end) = rest.find('"') else { break };
+        let url = &rest[..end];
+        if !is_link || url.starts_with("//") {
+            continue;
+        }
+
+        out.push_str(&rewrite(url));
+        rest = &rest[end..];
+    }
+    out.push_str(rest);
+    out
 }
 
 #[cfg(test)]
 mod tests {
     use std::io::Cursor;
 
+    use crate::config::{Author, ProfileUrls, Title};
     use crate::test_server::TestServer;
 
     use super::*;
@@ -171,6 +416,84 @@ mod tests {
 
         let body = resp.text().await?;
         assert!(body.contains("Hello, it is a header"));
+        assert!(body.contains("<title>Yellhole</title>"));
+        assert!(body.contains("Copyright &copy; Mr Magoo"));
+
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("notes"))]
+    async fn microformats(db: SqlitePool) -> Result<(), anyhow::Error> {
+        let ts = TestServer::new(app(&db))?;
+
+        let body = ts.get("/").send().await?.text().await?;
+        let doc = serde_json::to_value(microformats::from_html(&body, ts.url())?)?;
+
+        // The author's profiles are linked with rel="me".
+        assert_eq!(doc["rels"]["me"], serde_json::json!(["https://social.example/@magoo"]));
+
+        // The index is an h-feed of h-entry notes, newest first.
+        let feed = &doc["items"][0];
+        assert_eq!(feed["type"], serde_json::json!(["h-feed"]));
+        assert_eq!(feed["properties"]["name"], serde_json::json!(["Yellhole"]));
+        let entries = feed["children"].as_array().expect("no entries");
+        assert_eq!(entries.len(), 3);
+        assert!(entries.iter().all(|e| e["type"] == serde_json::json!(["h-entry"])));
+
+        let entry = &entries[2]["properties"];
+        assert_eq!(
+            entry["url"],
+            serde_json::json!([ts.url().join("/note/b0a2170c-5e91-42ad-aa1b-dabc3c6ea5b9")?])
+        );
+        assert_eq!(entry["published"], serde_json::json!(["2022-09-07T09:43:16Z"]));
+        assert_eq!(
+            entry["content"],
+            serde_json::json!([{
+                "html": "<p>Ok, I <em>guess</em> this is fine.</p>",
+                "value": "Ok, I guess this is fine.",
+            }])
+        );
+        let author = &entry["author"][0];
+        assert_eq!(author["type"], serde_json::json!(["h-card"]));
+        assert_eq!(author["properties"]["name"], serde_json::json!(["Mr Magoo"]));
+        assert_eq!(author["properties"]["url"], serde_json::json!(["http://example.com/"]));
+
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("notes"))]
+    async fn themed(db: SqlitePool) -> Result<(), anyhow::Error> {
+        let data_dir = tempdir::TempDir::new("yellhole-test")?;
+        let templates_dir = data_dir.path().join("themes").join("templates");
+        std::fs::create_dir_all(&templates_dir)?;
+        std::fs::write(
+            templates_dir.join("layout.html"),
+            r#"<title>{{ site.title }} by {{ site.author }}</title>{% block content %}{% endblock %}"#,
+        )?;
+        let theme = Theme::load(data_dir.path())?;
+        let ts = TestServer::new(app_with(&db, theme, chrono_tz::UTC))?;
+
+        // The built-in feed template is used with the theme's layout.
+        let resp = ts.get("/notes/2022/10").send().await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let body = resp.text().await?;
+        assert!(body.starts_with("<title>Yellhole by Mr Magoo</title>"));
+        assert!(body.contains("Hello, it is a header"));
+        assert!(body.contains(r#"<a href="/notes/2022/11">newer</a>"#));
+
+        // Templates are loaded on startup.
+        std::fs::write(
+            templates_dir.join("feed.html"),
+            r#"{% for n in notes %}<p id="{{ n.note_id }}">{{ n.body }}</p>{% endfor %}"#,
+        )?;
+        let theme = Theme::load(data_dir.path())?;
+        let ts = TestServer::new(app_with(&db, theme, chrono_tz::UTC))?;
+
+        let resp = ts.get("/note/c1449d6c-6b5b-4ce4-a4d7-98853562fbf1").send().await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = resp.text().await?;
+        assert!(body.starts_with(r#"<p id="c1449d6c-6b5b-4ce4-a4d7-98853562fbf1"># Hello"#));
 
         Ok(())
     }
@@ -189,7 +512,7 @@ mod tests {
         let feed = Feed::read_from(Cursor::new(&resp.bytes().await?))?;
         assert_eq!(
             feed.entries[0].content().unwrap().value().unwrap(),
-            "<p>It's a me, <em>Mario</em>.</p>\n"
+            "<p>It\u{2019}s a me, <em>Mario</em>.</p>\n"
         );
 
         Ok(())
@@ -204,6 +527,20 @@ mod tests {
 
         let body = resp.text().await?;
         assert!(body.contains("Hello, it is a header"));
+        assert!(body.contains(r#"<a href="/notes/2022/11">newer</a>"#));
+        assert!(body.contains(r#"<a href="/notes/2022/9">older</a>"#));
+
+        let body = ts.get("/notes/2022/11").send().await?.text().await?;
+        assert!(!body.contains(">newer</a>"));
+
+        // Months without notes are skipped.
+        let note_id = "c1449d6c-6b5b-4ce4-a4d7-98853562fbf1".parse::<Uuid>()?;
+        NoteService::new(db.clone()).delete(&note_id.hyphenated()).await?;
+        let body = ts.get("/notes/2022/11").send().await?.text().await?;
+        assert!(body.contains(r#"<a href="/notes/2022/9">older</a>"#));
+        let body = ts.get("/notes/2022/9").send().await?.text().await?;
+        assert!(body.contains(r#"<a href="/notes/2022/11">newer</a>"#));
+        assert!(!body.contains(">older</a>"));
 
         Ok(())
     }
@@ -221,6 +558,131 @@ mod tests {
         Ok(())
     }
 
+    #[sqlx::test(fixtures("notes"))]
+    async fn single_note_metadata(db: SqlitePool) -> Result<(), anyhow::Error> {
+        let ts = TestServer::new(app(&db))?;
+
+        let body =
+            ts.get("/note/c1449d6c-6b5b-4ce4-a4d7-98853562fbf1").send().await?.text().await?;
+        assert!(body.contains("<title>Hello, it is a header. A Subheader - Yellhole</title>"));
+        assert!(body.contains(
+            r#"<meta property="og:title" content="Hello, it is a header. A Subheader">"#
+        ));
+        assert!(body.contains(
+            r#"<meta property="og:url" content="http://example.com/note/c1449d6c-6b5b-4ce4-a4d7-98853562fbf1">"#
+        ));
+        assert!(body.contains(r#"<meta name="twitter:card" content="summary">"#));
+        assert!(body.contains(
+            r#"<meta property="article:published_time" content="2022-10-14T20:17:31+00:00">"#
+        ));
+        assert!(body.contains(
+            r#"<link rel="alternate" type="application/json+oembed" href="http://example.com/oembed?url=http%3A%2F%2Fexample.com%2Fnote%2Fc1449d6c-6b5b-4ce4-a4d7-98853562fbf1&amp;format=json""#
+        ));
+
+        // The first image is used, and the index has no note metadata.
+        let notes = NoteService::new(db);
+        let note_id =
+            notes.create("Look: ![](/images/a.main.webp) ![](/images/b.main.webp)").await?;
+        let body = ts.get(&format!("/note/{note_id}")).send().await?.text().await?;
+        assert!(body.contains(
+            r#"<meta property="og:image" content="http://example.com/images/a.main.webp">"#
+        ));
+        assert!(body.contains(r#"<meta name="twitter:card" content="summary_large_image">"#));
+
+        let body = ts.get("/").send().await?.text().await?;
+        assert!(body.contains("<title>Yellhole</title>"));
+        assert!(!body.contains("og:title"));
+
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("notes"))]
+    async fn oembed_provider(db: SqlitePool) -> Result<(), anyhow::Error> {
+        let ts = TestServer::new(app(&db))?;
+        let note_url = "http://example.com/note/69b124f0-a4fa-40d0-83f4-06bc4213f3ca";
+
+        let resp =
+            ts.get("/oembed").query(&[("url", note_url), ("maxwidth", "400")]).send().await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let oembed = resp.json::<serde_json::Value>().await?;
+        assert_eq!(
+            oembed,
+            serde_json::json!({
+                "version": "1.0",
+                "type": "rich",
+                "title": "It\u{2019}s a me, Mario.",
+                "author_name": "Mr Magoo",
+                "author_url": "http://example.com/",
+                "provider_name": "Yellhole",
+                "provider_url": "http://example.com/",
+                "html": format!(
+                    "<blockquote class=\"yellhole-note\" cite=\"{note_url}\">\n\
+                     <p>It\u{2019}s a me, <em>Mario</em>.</p>\n\
+                     <p>&mdash; <a href=\"{note_url}\">Mr Magoo, 2022-11-14</a></p>\n</blockquote>"
+                ),
+                "width": 400,
+                "height": null,
+            })
+        );
+
+        let resp = ts.get("/oembed").query(&[("url", note_url), ("format", "xml")]).send().await?;
+        assert_eq!(resp.status(), StatusCode::NOT_IMPLEMENTED);
+
+        for url in [
+            "http://example.com/note/00000000-0000-0000-0000-000000000000",
+            "http://evil.example.com/note/69b124f0-a4fa-40d0-83f4-06bc4213f3ca",
+            "http://example.com/notes/2022/11",
+        ] {
+            let resp = ts.get("/oembed").query(&[("url", url)]).send().await?;
+            assert_eq!(resp.status(), StatusCode::NOT_FOUND, "{url}");
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn absolute_links() {
+        let base_url = "https://example.com/".parse::<Url>().unwrap();
+        assert_eq!(
+            absolutize(
+                r#"<a href="/note/1"><img src="/images/a.webp" alt="/x"></a><a href="//cdn.example">"#,
+                &base_url
+            ),
+            r#"<a href="https://example.com/note/1"><img src="https://example.com/images/a.webp" alt="/x"></a><a href="//cdn.example">"#
+        );
+    }
+
+    #[sqlx::test(fixtures("notes"))]
+    async fn timezones(db: SqlitePool) -> Result<(), anyhow::Error> {
+        let ts = TestServer::new(app_with(&db, Theme::default(), chrono_tz::America::New_York))?;
+
+        // Times are shown in the site's time zone, which is UTC-4 in October and UTC-5 in
+        // November.
+        let resp = ts.get("/note/c1449d6c-6b5b-4ce4-a4d7-98853562fbf1").send().await?;
+        let body = resp.text().await?;
+        assert!(body.contains("2022-10-14 16:17:31 EDT"));
+
+        let resp = ts.get("/note/69b124f0-a4fa-40d0-83f4-06bc4213f3ca").send().await?;
+        let body = resp.text().await?;
+        assert!(body.contains("2022-11-14 13:22:00 EST"));
+
+        let resp = ts.get("/atom.xml").send().await?;
+        let feed = Feed::read_from(Cursor::new(&resp.bytes().await?))?;
+        assert_eq!(feed.entries[0].updated().to_rfc3339(), "2022-11-14T13:22:00-05:00");
+        assert_eq!(feed.entries[1].updated().to_rfc3339(), "2022-10-14T16:17:31-04:00");
+
+        // The index links to the month of its oldest note in the site's time zone.
+        let note_id = Uuid::new_v4().hyphenated();
+        let created_at =
+            NaiveDate::from_ymd_opt(2022, 12, 1).unwrap().and_hms_opt(3, 0, 0).unwrap();
+        NoteService::new(db.clone()).import(&note_id, "Late night.", &created_at).await?;
+        let body = ts.get("/?n=1").send().await?.text().await?;
+        assert!(body.contains("Late night."));
+        assert!(body.contains(r#"<a href="/notes/2022/11">older</a>"#));
+
+        Ok(())
+    }
+
     #[sqlx::test(fixtures("notes"))]
     async fn bad_note_id(db: SqlitePool) -> Result<(), anyhow::Error> {
         let ts = TestServer::new(app(&db))?;
@@ -242,10 +704,18 @@ mod tests {
     }
 
     fn app(db: &SqlitePool) -> Router {
-        router()
+        app_with(db, Theme::default(), chrono_tz::UTC)
+    }
+
+    fn app_with(db: &SqlitePool, theme: Theme, tz: Tz) -> Router {
+        router(&HttpConfig::default())
             .layer(Extension(NoteService::new(db.clone())))
+            .layer(Extension(FeedConfig::default()))
+            .layer(Extension(theme))
             .layer(Extension("http://example.com".parse::<Url>().unwrap()))
             .layer(Extension(Author("Mr Magoo".into())))
             .layer(Extension(Title("Yellhole".into())))
+            .layer(Extension(ProfileUrls(vec!["https://social.example/@magoo".parse().unwrap()])))
+            .layer(Extension(tz))
     }
 }
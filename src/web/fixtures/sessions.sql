@@ -0,0 +1,8 @@
+insert into session (session_id, as_json, created_at, updated_at)
+values ('ZWtE3BM0Hm0cXYkXVcqQ4l+QCNqUWvtvkUIqXfM3rvA=', '{"id":"ZWtE3BM0Hm0cXYkXVcqQ4l+QCNqUWvtvkUIqXfM3rvA=","expiry":null,"data":{"authenticated":"true","user_agent":"\"Mozilla/5.0 (Garfield)\"","ip_address":"\"10.0.0.1\""}}', datetime('now', '-2 hours'), datetime('now', '-1 hour'));
+
+insert into session (session_id, as_json, created_at, updated_at)
+values ('8ysN4jo+KSyCpb6hLWuHSEOvdOrtqzN/5yiYPSzw6iM=', '{"id":"8ysN4jo+KSyCpb6hLWuHSEOvdOrtqzN/5yiYPSzw6iM=","expiry":null,"data":{"authenticated":"true","user_agent":"\"Mozilla/5.0 (Odie)\"","ip_address":"\"10.0.0.2\""}}', datetime('now', '-3 hours'), null);
+
+insert into session (session_id, as_json, created_at, updated_at)
+values ('1Ka8zRp8HJ0SSaoHxD7wVQ4w9zCnUhnPsO+OkYVE1R4=', '{"id":"1Ka8zRp8HJ0SSaoHxD7wVQ4w9zCnUhnPsO+OkYVE1R4=","expiry":null,"data":{}}', datetime('now', '-3 hours'), null);
\ No newline at end of file
@@ -0,0 +1,79 @@
+use std::collections::BTreeMap;
+
+use axum::http::{self, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Extension, Json, Router};
+use serde::Serialize;
+use tower_http::set_header::SetResponseHeaderLayer;
+
+use crate::services::health::HealthService;
+
+pub fn router() -> Router {
+    Router::new().route("/healthz", get(healthz)).route("/readyz", get(readyz)).layer(
+        SetResponseHeaderLayer::overriding(
+            http::header::CACHE_CONTROL,
+            http::HeaderValue::from_static("no-store"),
+        ),
+    )
+}
+
+/// Returns OK as long as the process is up and serving requests.
+async fn healthz() -> &'static str {
+    "ok"
+}
+
+#[derive(Debug, Serialize)]
+struct ReadinessResponse {
+    ready: bool,
+    checks: BTreeMap<&'static str, String>,
+}
+
+/// Returns OK if all dependencies are usable, otherwise Service Unavailable.
+async fn readyz(health: Extension<HealthService>) -> Response {
+    let readiness = health.check().await;
+    let ready = readiness.is_ready();
+    let checks = readiness
+        .0
+        .into_iter()
+        .map(|(name, res)| (name, res.err().unwrap_or_else(|| "ok".into())))
+        .collect();
+    let status = if ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    (status, Json(ReadinessResponse { ready, checks })).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::SqlitePool;
+
+    use crate::services::health::TaskMonitor;
+    use crate::test_server::TestServer;
+
+    use super::*;
+
+    #[sqlx::test]
+    async fn health(db: SqlitePool) -> Result<(), anyhow::Error> {
+        let data_dir = tempdir::TempDir::new("yellhole-test")?;
+        let (expiry, handle) = TaskMonitor::spawn(async {});
+        handle.await?;
+        let ts = TestServer::new(router().layer(Extension(HealthService::new(
+            db,
+            data_dir.path(),
+            expiry,
+        ))))?;
+
+        let resp = ts.get("/healthz").send().await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // The session expiry task has finished, so the server isn't ready.
+        let resp = ts.get("/readyz").send().await?;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        let body = resp.json::<serde_json::Value>().await?;
+        assert_eq!(body["ready"], false);
+        assert_eq!(body["checks"]["database"], "ok");
+        assert_eq!(body["checks"]["session_expiry"], "session expiry task has stopped");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,173 @@
+use std::time::Instant;
+
+use axum::extract::MatchedPath;
+use axum::http::{self, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Extension, Router};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use sqlx::SqlitePool;
+
+use crate::services::images::ImageService;
+use crate::services::notes::NoteService;
+use crate::services::sessions::SessionService;
+
+/// Histogram buckets, in seconds, for request latencies and image processing durations.
+const BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+/// The sources of the metrics exposed at `/metrics`. Counters and histograms are recorded as
+/// they happen; gauges are measured when scraped.
+#[derive(Clone)]
+pub struct Metrics {
+    handle: PrometheusHandle,
+    db: SqlitePool,
+    notes: NoteService,
+    images: ImageService,
+    sessions: SessionService,
+}
+
+/// Installs the global Prometheus recorder. Must only be called once per process.
+pub fn install_recorder() -> Result<PrometheusHandle, anyhow::Error> {
+    let handle = PrometheusBuilder::new()
+        .set_buckets_for_metric(Matcher::Suffix("_seconds".into()), BUCKETS)?
+        .install_recorder()?;
+    describe();
+    Ok(handle)
+}
+
+impl Metrics {
+    pub fn new(
+        handle: PrometheusHandle,
+        db: SqlitePool,
+        notes: NoteService,
+        images: ImageService,
+        sessions: SessionService,
+    ) -> Metrics {
+        Metrics { handle, db, notes, images, sessions }
+    }
+
+    async fn render(&self) -> Result<String, anyhow::Error> {
+        metrics::gauge!("yellhole_notes", self.notes.count().await? as f64);
+        metrics::gauge!("yellhole_images", self.images.count().await? as f64);
+        metrics::gauge!(
+            "yellhole_active_sessions",
+            self.sessions.authenticated().await?.len() as f64
+        );
+        metrics::gauge!("yellhole_db_connections", self.db.size() as f64);
+        metrics::gauge!("yellhole_db_idle_connections", self.db.num_idle() as f64);
+        Ok(self.handle.render())
+    }
+}
+
+fn describe() {
+    metrics::describe_counter!("yellhole_http_requests_total", "HTTP requests by route.");
+    metrics::describe_histogram!(
+        "yellhole_http_request_duration_seconds",
+        metrics::Unit::Seconds,
+        "HTTP request latency by route."
+    );
+    metrics::describe_histogram!(
+        "yellhole_image_processing_seconds",
+        metrics::Unit::Seconds,
+        "Time spent resizing uploaded images."
+    );
+    metrics::describe_counter!(
+        "yellhole_image_processing_failures_total",
+        "Uploaded images which couldn't be resized."
+    );
+    metrics::describe_counter!("yellhole_logins_total", "Passkey logins by result.");
+    metrics::describe_gauge!("yellhole_notes", "The total number of notes.");
+    metrics::describe_gauge!("yellhole_images", "The total number of images.");
+    metrics::describe_gauge!("yellhole_active_sessions", "Authenticated, unexpired sessions.");
+    metrics::describe_gauge!("yellhole_db_connections", "Open SQLite pool connections.");
+    metrics::describe_gauge!("yellhole_db_idle_connections", "Idle SQLite pool connections.");
+}
+
+pub fn router(metrics: Metrics) -> Router {
+    Router::new().route("/metrics", get(scrape)).layer(Extension(metrics))
+}
+
+async fn scrape(Extension(metrics): Extension<Metrics>) -> Result<Response, StatusCode> {
+    let body = metrics.render().await.map_err(|err| {
+        tracing::warn!(?err, "error measuring metrics");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    Ok((
+        [(http::header::CONTENT_TYPE, http::HeaderValue::from_static("text/plain; version=0.0.4"))],
+        body,
+    )
+        .into_response())
+}
+
+/// Records the count and latency of requests by method, route, and status. Must be added with
+/// `route_layer` so the matched route is known.
+pub async fn track_requests<B>(req: Request<B>, next: Next<B>) -> Response {
+    let path = req.extensions().get::<MatchedPath>().map(|p| p.as_str().to_string());
+    let method = req.method().to_string();
+    let started = Instant::now();
+
+    let resp = next.run(req).await;
+
+    let labels = [
+        ("method", method),
+        ("path", path.unwrap_or_else(|| "unknown".into())),
+        ("status", resp.status().as_u16().to_string()),
+    ];
+    metrics::increment_counter!("yellhole_http_requests_total", &labels);
+    metrics::histogram!("yellhole_http_request_duration_seconds", started.elapsed(), &labels);
+    resp
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::OnceLock;
+
+    use axum::middleware;
+
+    use crate::test_server::TestServer;
+
+    use super::*;
+
+    #[sqlx::test]
+    async fn scraping(db: SqlitePool) -> Result<(), anyhow::Error> {
+        let data_dir = tempdir::TempDir::new("yellhole-test")?;
+        let notes = NoteService::new(db.clone());
+        notes.create("It's a me, Mario.").await?;
+
+        let metrics = Metrics::new(
+            handle(),
+            db.clone(),
+            notes,
+            ImageService::new(db.clone(), data_dir.path())?,
+            SessionService::store(&db, Default::default()),
+        );
+        let app = Router::new()
+            .route("/hello/:name", get(|| async { "hello" }))
+            .route_layer(middleware::from_fn(track_requests))
+            .merge(router(metrics));
+        let ts = TestServer::new(app)?;
+
+        let resp = ts.get("/hello/mario").send().await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let resp = ts.get("/metrics").send().await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let body = resp.text().await?;
+        assert!(body.contains(
+            r#"yellhole_http_requests_total{method="GET",path="/hello/:name",status="200"} 1"#
+        ));
+        assert!(body.contains("yellhole_http_request_duration_seconds_bucket{"));
+        assert!(body.contains("yellhole_notes 1"));
+        assert!(body.contains("yellhole_active_sessions 0"));
+
+        Ok(())
+    }
+
+    /// Installs the global recorder once for all tests.
+    fn handle() -> PrometheusHandle {
+        static HANDLE: OnceLock<PrometheusHandle> = OnceLock::new();
+        HANDLE.get_or_init(|| install_recorder().expect("error installing recorder")).clone()
+    }
+}
@@ -1,13 +1,20 @@
 use std::net::SocketAddr;
 use std::path::PathBuf;
+use std::time::Duration;
 
 use askama::Template;
+use axum::extract::{ConnectInfo, FromRequest, RequestParts};
+use axum::http::header::HeaderName;
 use axum::http::{self, StatusCode};
 use axum::middleware::{self, Next};
 use axum::response::{Html, IntoResponse, Response};
+use axum::Extension;
+use chrono_tz::Tz;
 use futures::Future;
+use serde::Serialize;
 use sqlx::SqlitePool;
 use tower::ServiceBuilder;
+use tower_http::classify::{ServerErrorsAsFailures, SharedClassifier};
 use tower_http::request_id::MakeRequestUuid;
 use tower_http::sensitive_headers::{
     SetSensitiveRequestHeadersLayer, SetSensitiveResponseHeadersLayer,
@@ -17,37 +24,52 @@ use tower_http::ServiceBuilderExt;
 use tracing::Level;
 use url::Url;
 
-use crate::config::{Author, Title};
-use crate::services::images::ImageService;
-use crate::services::notes::NoteService;
+use crate::config::SessionSecret;
+use crate::config::{Author, ClientIpHeader, FeedConfig, HttpConfig, ProfileUrls, Title};
+use crate::services::audit::AuditService;
+use crate::services::export::ExportService;
+use crate::services::health::HealthService;
+use crate::services::images::{ImageService, ImageSizes};
+use crate::services::notes::{NoteService, Sanitizer};
 use crate::services::passkeys::PasskeyService;
-use crate::services::sessions::SessionService;
+use crate::services::previews::PreviewService;
+use crate::services::sessions::{self, SessionConfig, SessionService};
+use crate::services::tokens::TokenService;
+use crate::web::metrics::Metrics;
+use crate::web::theme::{Theme, Themed};
 
 mod admin;
+mod api;
 mod asset;
 mod auth;
+mod csrf;
 mod feed;
+mod health;
+mod metrics;
+mod rate_limit;
+pub mod static_site;
+pub mod theme;
 
 #[derive(Debug)]
 pub struct App {
-    db: SqlitePool,
-    data_dir: PathBuf,
-    base_url: Url,
-    title: Title,
-    author: Author,
+    pub db: SqlitePool,
+    pub data_dir: PathBuf,
+    pub base_url: Url,
+    pub title: Title,
+    pub author: Author,
+    pub profile_urls: ProfileUrls,
+    pub timezone: Tz,
+    pub sanitizer: Sanitizer,
+    pub preview_timeout: Duration,
+    pub session_config: SessionConfig,
+    pub metrics_port: Option<u16>,
+    pub client_ip_header: Option<ClientIpHeader>,
+    pub feed: FeedConfig,
+    pub http: HttpConfig,
+    pub image_sizes: ImageSizes,
 }
 
 impl App {
-    pub fn new(
-        db: SqlitePool,
-        data_dir: PathBuf,
-        base_url: Url,
-        title: Title,
-        author: Author,
-    ) -> App {
-        App { db, data_dir, base_url, title, author }
-    }
-
     pub async fn serve(
         self,
         addr: &SocketAddr,
@@ -55,36 +77,76 @@ impl App {
     ) -> anyhow::Result<()> {
         tracing::info!(%addr, base_url=%self.base_url, "starting server");
 
-        let (sessions, session_expiry) = SessionService::new(&self.db, &self.base_url);
-        let images = ImageService::new(self.db.clone(), &self.data_dir)?;
+        let (sessions, session_layer, expiry_monitor, session_expiry) =
+            SessionService::new(&self.db, &self.base_url, &self.session_config);
+        let images =
+            ImageService::new(self.db.clone(), &self.data_dir)?.with_sizes(self.image_sizes);
+        let notes = NoteService::new(self.db.clone()).with_sanitizer(self.sanitizer.clone());
+        let previews = PreviewService::new(self.db.clone(), images.clone())
+            .with_timeout(self.preview_timeout)
+            .with_sanitizer(self.sanitizer);
+        let export = ExportService::new(notes.clone(), images.clone(), &self.data_dir);
+        let SessionConfig { secret, previous_secret, .. } = self.session_config;
+        let theme = Theme::load(&self.data_dir)?;
+
+        // Only serve metrics on a separate port, if configured, to keep them off the public one.
+        if let Some(port) = self.metrics_port {
+            let metrics = metrics::router(Metrics::new(
+                metrics::install_recorder()?,
+                self.db.clone(),
+                notes.clone(),
+                images.clone(),
+                sessions.clone(),
+            ));
+            let addr = SocketAddr::from(([0, 0, 0, 0], port));
+            tracing::info!(%addr, "serving metrics");
+            tokio::spawn(async move {
+                if let Err(err) = axum::Server::bind(&addr).serve(metrics.into_make_service()).await
+                {
+                    tracing::error!(%err, "error serving metrics");
+                }
+            });
+        }
 
-        let app = admin::router()
+        let app = admin::router(&self.http)
+            .route_layer(middleware::from_fn(csrf::verify))
             .route_layer(middleware::from_extractor::<auth::RequireAuth>())
             .merge(auth::router())
-            .layer(sessions) // only enable sessions for auth and admin
-            .merge(feed::router())
-            .merge(asset::router(self.data_dir.join("images")))
+            .merge(api::router(&self.http))
+            .layer(session_layer) // only enable sessions for auth, admin, and the write API
+            .layer(middleware::from_fn(move |req, next| {
+                rotate_session_secret(req, next, secret.clone(), previous_secret.clone())
+            }))
+            .merge(feed::router(&self.http))
+            .merge(api::public_router(&self.http))
+            .merge(asset::router(self.data_dir.join("images"), &theme, &self.http))
+            .merge(health::router())
+            .route_layer(middleware::from_fn(metrics::track_requests))
             .layer(
                 ServiceBuilder::new()
+                    .add_extension(AuditService::new(self.db.clone()))
+                    .add_extension(HealthService::new(
+                        self.db.clone(),
+                        &self.data_dir,
+                        expiry_monitor,
+                    ))
                     .add_extension(PasskeyService::new(self.db.clone(), &self.base_url))
                     .add_extension(images)
-                    .add_extension(NoteService::new(self.db.clone()))
+                    .add_extension(export)
+                    .add_extension(sessions)
+                    .add_extension(notes)
+                    .add_extension(previews)
+                    .add_extension(TokenService::new(self.db.clone()))
                     .add_extension(self.base_url)
                     .add_extension(self.author)
                     .add_extension(self.title)
+                    .add_extension(self.profile_urls)
+                    .add_extension(self.timezone)
+                    .add_extension(self.feed)
+                    .add_extension(theme)
                     .set_x_request_id(MakeRequestUuid)
-                    .layer(SetSensitiveRequestHeadersLayer::new(std::iter::once(
-                        http::header::COOKIE,
-                    )))
-                    .layer(
-                        TraceLayer::new_for_http()
-                            .make_span_with(
-                                DefaultMakeSpan::new().level(Level::INFO).include_headers(true),
-                            )
-                            .on_response(
-                                DefaultOnResponse::new().level(Level::INFO).include_headers(true),
-                            ),
-                    )
+                    .layer(SetSensitiveRequestHeadersLayer::new(SENSITIVE_REQUEST_HEADERS))
+                    .layer(trace_layer())
                     .layer(SetSensitiveResponseHeadersLayer::new(std::iter::once(
                         http::header::SET_COOKIE,
                     )))
@@ -93,8 +155,14 @@ impl App {
                     .catch_panic(),
             );
 
+        // Only trust a client IP header if a reverse proxy is configured to set it.
+        let app = match self.client_ip_header {
+            Some(header) => app.layer(Extension(header)),
+            None => app,
+        };
+
         axum::Server::bind(addr)
-            .serve(app.into_make_service())
+            .serve(app.into_make_service_with_connect_info::<SocketAddr>())
             .with_graceful_shutdown(shutdown_hook)
             .await?;
 
@@ -104,24 +172,190 @@ impl App {
     }
 }
 
-#[derive(Debug, Template)]
-#[template(path = "error.html")]
+/// Request headers which carry credentials, and so are redacted from logs.
+const SENSITIVE_REQUEST_HEADERS: [HeaderName; 3] =
+    [http::header::COOKIE, http::header::AUTHORIZATION, HeaderName::from_static("x-csrf-token")];
+
+/// Logs requests and responses, including their headers.
+fn trace_layer() -> TraceLayer<SharedClassifier<ServerErrorsAsFailures>> {
+    TraceLayer::new_for_http()
+        .make_span_with(DefaultMakeSpan::new().level(Level::INFO).include_headers(true))
+        .on_response(DefaultOnResponse::new().level(Level::INFO).include_headers(true))
+}
+
+/// Identifying information about the client making a request.
+#[derive(Debug, Clone)]
+pub struct ClientInfo {
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+#[axum::async_trait]
+impl<B> FromRequest<B> for ClientInfo
+where
+    B: Send,
+{
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        Ok(ClientInfo::from_parts(req.headers(), req.extensions()))
+    }
+}
+
+impl ClientInfo {
+    pub fn from_parts(headers: &http::HeaderMap, extensions: &http::Extensions) -> ClientInfo {
+        let header = |name: &str| {
+            headers.get(name).and_then(|v| v.to_str().ok()).map(|s| s.trim().to_string())
+        };
+
+        // Clients can send any headers they like, so only use the client IP as reported by a
+        // configured reverse proxy, falling back to the address of the peer. Proxies append to
+        // `X-Forwarded-For`, so the last address is the one the proxy saw.
+        let ip_address = extensions
+            .get::<ClientIpHeader>()
+            .and_then(|ClientIpHeader(name)| header(name.as_str()))
+            .and_then(|v| v.rsplit(',').next().map(|s| s.trim().to_string()))
+            .filter(|ip| !ip.is_empty())
+            .or_else(|| extensions.get::<ConnectInfo<SocketAddr>>().map(|c| c.0.ip().to_string()));
+
+        ClientInfo { ip_address, user_agent: header(http::header::USER_AGENT.as_str()) }
+    }
+}
+
+/// The site-wide details shown on every page.
+#[derive(Debug, Clone, Serialize)]
+pub struct Site {
+    pub title: String,
+    pub author: String,
+    /// The author's profiles elsewhere, linked with `rel="me"`.
+    pub profile_urls: Vec<Url>,
+    /// The time zone in which times are shown and notes are grouped by month.
+    #[serde(skip)]
+    pub timezone: Tz,
+}
+
+impl Site {
+    pub fn new(Title(title): Title, Author(author): Author, timezone: Tz) -> Site {
+        Site { title, author, profile_urls: Vec::new(), timezone }
+    }
+
+    pub fn with_profile_urls(self, ProfileUrls(profile_urls): ProfileUrls) -> Site {
+        Site { profile_urls, ..self }
+    }
+
+    fn from_extensions(extensions: &http::Extensions) -> Option<Site> {
+        Some(
+            Site::new(
+                extensions.get::<Title>()?.clone(),
+                extensions.get::<Author>()?.clone(),
+                *extensions.get::<Tz>()?,
+            )
+            .with_profile_urls(extensions.get::<ProfileUrls>().cloned().unwrap_or_default()),
+        )
+    }
+}
+
+#[axum::async_trait]
+impl<B> FromRequest<B> for Site
+where
+    B: Send,
+{
+    type Rejection = StatusCode;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        Site::from_extensions(req.extensions()).ok_or_else(|| {
+            tracing::error!("missing site extensions");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })
+    }
+}
+
+mod filters {
+    use chrono::{DateTime, NaiveDateTime, TimeZone};
+    use chrono_tz::Tz;
+
+    /// Converts a UTC timestamp to the site's time zone.
+    pub fn to_local_tz(t: &NaiveDateTime, tz: &Tz) -> askama::Result<DateTime<Tz>> {
+        Ok(tz.from_utc_datetime(t))
+    }
+}
+
+#[derive(Debug)]
 struct ErrorPage {
+    site: Site,
     status: StatusCode,
 }
 
 impl ErrorPage {
-    fn for_status(status: StatusCode) -> Response {
-        let mut resp = Page(ErrorPage { status }).into_response();
+    fn for_status(site: Site, theme: Theme, status: StatusCode) -> Response {
+        let mut resp = ThemedPage(theme, ErrorPage { site, status }).into_response();
         *resp.status_mut() = status;
         resp
     }
 }
 
+impl Themed for ErrorPage {
+    const TEMPLATE: &'static str = "error.html";
+
+    fn context(&self) -> minijinja::Value {
+        minijinja::context! { site => self.site, status => self.status.as_u16() }
+    }
+}
+
+/// Re-signs session cookies signed with the previous session secret, if any, so the session layer
+/// will accept them.
+async fn rotate_session_secret<B>(
+    mut req: http::Request<B>,
+    next: Next<B>,
+    current: SessionSecret,
+    previous: Option<SessionSecret>,
+) -> Response {
+    let Some(previous) = previous else {
+        return next.run(req).await;
+    };
+
+    let mut resigned = false;
+    let cookies = req
+        .headers()
+        .get_all(http::header::COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .map(|cookie| {
+            let cookie = cookie.trim();
+            if let Some((sessions::COOKIE_NAME, value)) = cookie.split_once('=') {
+                if let Some(value) = sessions::resign_cookie(value, &current, &previous) {
+                    resigned = true;
+                    return format!("{}={value}", sessions::COOKIE_NAME);
+                }
+            }
+            cookie.to_string()
+        })
+        .collect::<Vec<String>>()
+        .join("; ");
+
+    if resigned {
+        if let Ok(cookies) = http::HeaderValue::from_str(&cookies) {
+            req.headers_mut().insert(http::header::COOKIE, cookies);
+        }
+    }
+
+    next.run(req).await
+}
+
 async fn handle_errors<B>(req: http::Request<B>, next: Next<B>) -> Result<Response, StatusCode> {
+    // Leave API errors as JSON.
+    if req.uri().path().starts_with("/api/") {
+        return Ok(next.run(req).await);
+    }
+
+    let site = Site::from_extensions(req.extensions());
+    let theme = req.extensions().get::<Theme>().cloned().unwrap_or_default();
     let resp = next.run(req).await;
     if resp.status().is_server_error() || resp.status() == StatusCode::NOT_FOUND {
-        return Ok(ErrorPage::for_status(resp.status()));
+        if let Some(site) = site {
+            return Ok(ErrorPage::for_status(site, theme, resp.status()));
+        }
     }
     Ok(resp)
 }
@@ -140,3 +374,105 @@ impl<T: Template> IntoResponse for Page<T> {
         }
     }
 }
+
+/// A public page, rendered with the theme's version of its template if there is one, or the
+/// built-in one.
+#[derive(Debug)]
+pub struct ThemedPage<T: Themed>(Theme, T);
+
+impl<T: Themed> IntoResponse for ThemedPage<T> {
+    fn into_response(self) -> Response {
+        match self.0.render(&self.1) {
+            Ok(body) => Html(body).into_response(),
+            Err(err) => {
+                tracing::error!(?err, "unable to render template");
+                http::StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::sync::{Arc, Mutex};
+
+    use axum::body::Body;
+    use axum::routing::get;
+    use axum::Router;
+    use tower::ServiceExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn redacting_credentials() -> Result<(), anyhow::Error> {
+        #[derive(Clone, Default)]
+        struct Logs(Arc<Mutex<Vec<u8>>>);
+
+        impl io::Write for Logs {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let logs = Logs::default();
+        let writer = logs.clone();
+        let subscriber =
+            tracing_subscriber::fmt().with_ansi(false).with_writer(move || writer.clone()).finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let app = Router::new().route("/", get(|| async { "ok" })).layer(
+            ServiceBuilder::new()
+                .layer(SetSensitiveRequestHeadersLayer::new(SENSITIVE_REQUEST_HEADERS))
+                .layer(trace_layer()),
+        );
+        let req = http::Request::builder()
+            .uri("/")
+            .header(http::header::AUTHORIZATION, "Bearer yh_secret")
+            .header(http::header::COOKIE, "session=cookie_secret")
+            .header("x-csrf-token", "csrf_secret")
+            .header(http::header::USER_AGENT, "curl/7.86.0")
+            .body(Body::empty())?;
+        let resp = app.oneshot(req).await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone())?;
+        assert!(logs.contains("curl/7.86.0"), "{logs}");
+        for secret in ["yh_secret", "cookie_secret", "csrf_secret"] {
+            assert!(!logs.contains(secret), "{logs}");
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn client_ip_addresses() {
+        let mut headers = http::HeaderMap::new();
+        headers.insert("x-forwarded-for", "203.0.113.1, 10.0.0.2".parse().unwrap());
+        headers.insert("fly-client-ip", "203.0.113.3".parse().unwrap());
+        let mut extensions = http::Extensions::new();
+        extensions.insert(ConnectInfo(SocketAddr::from(([10, 0, 0, 1], 4000))));
+
+        // Forwarding headers are ignored unless a proxy is configured.
+        let client = ClientInfo::from_parts(&headers, &extensions);
+        assert_eq!(client.ip_address.as_deref(), Some("10.0.0.1"));
+
+        // The last address in X-Forwarded-For is the one the proxy added.
+        extensions.insert("x-forwarded-for".parse::<ClientIpHeader>().unwrap());
+        let client = ClientInfo::from_parts(&headers, &extensions);
+        assert_eq!(client.ip_address.as_deref(), Some("10.0.0.2"));
+
+        extensions.insert("Fly-Client-IP".parse::<ClientIpHeader>().unwrap());
+        let client = ClientInfo::from_parts(&headers, &extensions);
+        assert_eq!(client.ip_address.as_deref(), Some("203.0.113.3"));
+
+        // Requests which didn't come through the proxy fall back to the peer address.
+        headers.remove("fly-client-ip");
+        let client = ClientInfo::from_parts(&headers, &extensions);
+        assert_eq!(client.ip_address.as_deref(), Some("10.0.0.1"));
+    }
+}
@@ -0,0 +1,157 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::http::{self, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+
+use super::ClientInfo;
+
+/// A token bucket rate limiter which limits both requests per client IP address and requests
+/// overall.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    per_ip: Limit,
+    global: Limit,
+    buckets: Arc<Mutex<Buckets>>,
+}
+
+#[derive(Debug)]
+struct Buckets {
+    global: Bucket,
+    per_ip: HashMap<String, Bucket>,
+}
+
+impl RateLimiter {
+    pub fn new(per_ip: Limit, global: Limit) -> RateLimiter {
+        let now = Instant::now();
+        RateLimiter {
+            per_ip,
+            global,
+            buckets: Arc::new(Mutex::new(Buckets {
+                global: Bucket::new(&global, now),
+                per_ip: HashMap::new(),
+            })),
+        }
+    }
+
+    /// Returns `true` if a request from the given IP address is allowed.
+    pub fn check(&self, ip_address: Option<&str>) -> bool {
+        self.check_at(ip_address, Instant::now())
+    }
+
+    fn check_at(&self, ip_address: Option<&str>, now: Instant) -> bool {
+        let mut buckets = self.buckets.lock().expect("poisoned rate limiter");
+
+        // Forget about IP addresses which have been quiet long enough to have full buckets.
+        if buckets.per_ip.len() > MAX_TRACKED_IPS {
+            let per_ip = self.per_ip;
+            buckets.per_ip.retain(|_, b| b.refill(&per_ip, now) < per_ip.burst as f64);
+        }
+
+        if let Some(ip_address) = ip_address {
+            let per_ip = self.per_ip;
+            let bucket = buckets
+                .per_ip
+                .entry(ip_address.to_string())
+                .or_insert_with(|| Bucket::new(&per_ip, now));
+            if !bucket.take(&per_ip, now) {
+                return false;
+            }
+        }
+
+        let global = self.global;
+        buckets.global.take(&global, now)
+    }
+}
+
+/// A rate limit of `burst` requests, refilled at one request every `period`.
+#[derive(Debug, Clone, Copy)]
+pub struct Limit {
+    pub burst: u32,
+    pub period: Duration,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl Bucket {
+    fn new(limit: &Limit, now: Instant) -> Bucket {
+        Bucket { tokens: limit.burst as f64, updated_at: now }
+    }
+
+    fn refill(&mut self, limit: &Limit, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.updated_at);
+        let refilled = elapsed.as_secs_f64() / limit.period.as_secs_f64();
+        self.tokens = (self.tokens + refilled).min(limit.burst as f64);
+        self.updated_at = now;
+        self.tokens
+    }
+
+    fn take(&mut self, limit: &Limit, now: Instant) -> bool {
+        if self.refill(limit, now) >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// The maximum number of IP addresses to track before pruning idle ones.
+const MAX_TRACKED_IPS: usize = 10_000;
+
+/// Rejects requests with `429 Too Many Requests` if the given rate limiter denies them.
+pub async fn limit<B>(
+    req: http::Request<B>,
+    next: Next<B>,
+    limiter: RateLimiter,
+) -> Result<Response, Response> {
+    let client = ClientInfo::from_parts(req.headers(), req.extensions());
+    if !limiter.check(client.ip_address.as_deref()) {
+        tracing::warn!(?client, uri=%req.uri(), "rate limited request");
+        return Err(StatusCode::TOO_MANY_REQUESTS.into_response());
+    }
+    Ok(next.run(req).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn per_ip_limits() {
+        let limiter = RateLimiter::new(
+            Limit { burst: 2, period: Duration::from_secs(10) },
+            Limit { burst: 100, period: Duration::from_secs(1) },
+        );
+        let now = Instant::now();
+
+        assert!(limiter.check_at(Some("10.0.0.1"), now));
+        assert!(limiter.check_at(Some("10.0.0.1"), now));
+        assert!(!limiter.check_at(Some("10.0.0.1"), now));
+        assert!(limiter.check_at(Some("10.0.0.2"), now));
+
+        assert!(!limiter.check_at(Some("10.0.0.1"), now + Duration::from_secs(5)));
+        assert!(limiter.check_at(Some("10.0.0.1"), now + Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn global_limits() {
+        let limiter = RateLimiter::new(
+            Limit { burst: 100, period: Duration::from_secs(1) },
+            Limit { burst: 2, period: Duration::from_secs(10) },
+        );
+        let now = Instant::now();
+
+        assert!(limiter.check_at(Some("10.0.0.1"), now));
+        assert!(limiter.check_at(Some("10.0.0.2"), now));
+        assert!(!limiter.check_at(Some("10.0.0.3"), now));
+        assert!(!limiter.check_at(None, now));
+        assert!(limiter.check_at(None, now + Duration::from_secs(10)));
+    }
+}
@@ -0,0 +1,506 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use atom_syndication::Content;
+use chrono::{Datelike, NaiveDate, TimeZone};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use url::Url;
+
+use super::asset::STATIC_DIR;
+use super::feed::{atom_feed, rewrite_links, FeedPage};
+use super::theme::Theme;
+use super::Site;
+use crate::config::FeedConfig;
+use crate::services::images::IMAGES_DIR;
+use crate::services::notes::{Note, NoteService};
+use crate::services::previews::LinkPreview;
+
+/// The name of the file in the output directory which records what was rendered.
+const MANIFEST: &str = ".yellhole-static.json";
+
+/// Renders the public site as static files for hosting on plain object storage.
+///
+/// All links between pages are relative, so the site can be hosted under any path. Pages for
+/// months whose notes haven't changed since the last export aren't re-rendered.
+#[derive(Debug, Clone)]
+pub struct StaticSite {
+    notes: NoteService,
+    images_dir: PathBuf,
+    base_url: Url,
+    site: Site,
+    theme: Theme,
+    feed: FeedConfig,
+}
+
+impl StaticSite {
+    pub fn new(
+        notes: NoteService,
+        data_dir: &Path,
+        base_url: Url,
+        site: Site,
+        theme: Theme,
+        feed: FeedConfig,
+    ) -> StaticSite {
+        let images_dir = data_dir.join(IMAGES_DIR);
+        StaticSite { notes, images_dir, base_url, site, theme, feed }
+    }
+
+    /// Renders the site into `dir`. If `force` is true, every page is rendered, otherwise only
+    /// the months which have changed since the last export are.
+    pub async fn export(&self, dir: &Path, force: bool) -> Result<ExportSummary, anyhow::Error> {
+        fs::create_dir_all(dir)?;
+
+        // Re-render everything if forced or if the last export was made by a different version,
+        // for a different base URL, or with different site details or a different theme.
+        let previous = read_manifest(dir).unwrap_or_default();
+        let site = self.fingerprint()?;
+        let reuse = !force
+            && previous.version == env!("CARGO_PKG_VERSION")
+            && previous.base_url == self.base_url.as_str()
+            && previous.site == site;
+
+        // Group notes by the month in which they were created in the site's time zone, newest
+        // first.
+        let mut months = BTreeMap::<NaiveDate, Vec<Note>>::new();
+        for note in self.notes.all().await? {
+            let date = self.site.timezone.from_utc_datetime(&note.created_at).date_naive();
+            let start = date.with_day(1).expect("invalid month");
+            months.entry(start).or_default().insert(0, note);
+        }
+
+        let mut summary = ExportSummary::default();
+        let mut manifest = Manifest {
+            version: env!("CARGO_PKG_VERSION").into(),
+            base_url: self.base_url.to_string(),
+            site,
+            months: BTreeMap::new(),
+        };
+
+        // Each month's page links to the closest months with notes, so its fingerprint includes
+        // them too.
+        let starts = months.keys().copied().collect::<Vec<NaiveDate>>();
+        for (i, (start, notes)) in months.into_iter().enumerate() {
+            let key = start.format("%Y-%m").to_string();
+            let newer = starts.get(i + 1).copied();
+            let older = i.checked_sub(1).map(|j| starts[j]);
+            let entry = MonthEntry {
+                fingerprint: fingerprint(&notes, newer, older),
+                notes: notes.iter().map(|n| n.note_id.to_string()).collect(),
+            };
+
+            let unchanged =
+                previous.months.get(&key).map(|e| &e.fingerprint) == Some(&entry.fingerprint);
+            if reuse && unchanged {
+                summary.months_unchanged += 1;
+                manifest.months.insert(key, entry);
+                continue;
+            }
+
+            // Remove the pages of any notes which were deleted from this month.
+            if let Some(old) = previous.months.get(&key) {
+                remove_notes(dir, old.notes.iter().filter(|id| !entry.notes.contains(id)))?;
+            }
+
+            for note in notes.iter() {
+                let page = FeedPage::single(self.site.clone(), note.clone(), self.base_url.clone())
+                    .without_oembed();
+                write_page(dir, &format!("note/{}", note.note_id), &self.theme.render(&page)?)?;
+                summary.notes_rendered += 1;
+            }
+
+            let page =
+                FeedPage::month(self.site.clone(), notes, self.base_url.clone(), newer, older);
+            let html = self.theme.render(&page)?;
+            write_page(dir, &format!("notes/{}/{}", start.year(), start.month()), &html)?;
+            summary.months_rendered += 1;
+            manifest.months.insert(key, entry);
+        }
+
+        // Remove the pages for months which no longer have any notes.
+        for (key, old) in previous.months.iter().filter(|(k, _)| !manifest.months.contains_key(*k))
+        {
+            remove_notes(dir, old.notes.iter())?;
+            let (year, month) = key.split_once('-').context("invalid month in manifest")?;
+            let month = month.trim_start_matches('0');
+            remove_dir_if_exists(&dir.join("notes").join(year).join(month))?;
+        }
+
+        // The index and feed always change when any month does, and are cheap to render.
+        let page = FeedPage::index(
+            self.site.clone(),
+            self.notes.most_recent(self.feed.index_length).await?,
+            self.base_url.clone(),
+        );
+        write_page(dir, "", &self.theme.render(&page)?)?;
+
+        let mut feed = atom_feed(
+            &self.notes.most_recent(self.feed.atom_length).await?,
+            &self.base_url,
+            &self.site,
+        );
+        for entry in feed.entries.iter_mut() {
+            if let Some(Content { value: Some(html), .. }) = &mut entry.content {
+                *html = relativize(html, 0);
+            }
+        }
+        fs::write(dir.join("atom.xml"), feed.to_string())?;
+
+        STATIC_DIR.extract(dir.join("assets"))?;
+        self.theme.copy_assets(&dir.join("assets"))?;
+        summary.images_copied = copy_images(&self.images_dir, &dir.join(IMAGES_DIR))?;
+
+        fs::write(dir.join(MANIFEST), serde_json::to_vec_pretty(&manifest)?)?;
+        tracing::info!(?dir, ?summary, "exported static site");
+        Ok(summary)
+    }
+
+    /// Returns a hash of everything besides notes which pages depend on: the site's details, its
+    /// time zone, and the theme.
+    fn fingerprint(&self) -> Result<String, anyhow::Error> {
+        let mut hasher = Sha256::new();
+        hasher.update(serde_json::to_vec(&self.site)?);
+        hasher.update(self.site.timezone.name());
+        hasher.update(self.theme.fingerprint()?);
+        Ok(hex::encode(hasher.finalize()))
+    }
+}
+
+/// The results of a static site export.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ExportSummary {
+    /// The number of month pages rendered.
+    pub months_rendered: usize,
+
+    /// The number of months skipped because they were unchanged.
+    pub months_unchanged: usize,
+
+    /// The number of note pages rendered.
+    pub notes_rendered: usize,
+
+    /// The number of images copied.
+    pub images_copied: usize,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Manifest {
+    version: String,
+    base_url: String,
+    #[serde(default)]
+    site: String,
+    months: BTreeMap<String, MonthEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct MonthEntry {
+    fingerprint: String,
+    notes: Vec<String>,
+}
+
+fn read_manifest(dir: &Path) -> Option<Manifest> {
+    let json = fs::read(dir.join(MANIFEST)).ok()?;
+    serde_json::from_slice(&json).ok()
+}
+
+fn fingerprint(notes: &[Note], newer: Option<NaiveDate>, older: Option<NaiveDate>) -> String {
+    let mut hasher = Sha256::new();
+    for month in [newer, older] {
+        hasher.update(month.map(|d| d.to_string()).unwrap_or_default());
+        hasher.update([0]);
+    }
+    for note in notes {
+        hasher.update(note.note_id.as_uuid().as_bytes());
+        hasher.update(note.created_at.to_string());
+        hasher.update((note.body.len() as u64).to_le_bytes());
+        hasher.update(&note.body);
+        hasher.update((note.html.len() as u64).to_le_bytes());
+        hasher.update(&note.html);
+        let preview = note.preview.as_ref().map(LinkPreview::to_html).unwrap_or_default();
+        hasher.update((preview.len() as u64).to_le_bytes());
+        hasher.update(preview);
+    }
+    hex::encode(hasher.finalize())
+}
+
+/// Writes a page to `{path}/index.html`, rewriting its links to be relative to it.
+fn write_page(dir: &Path, path: &str, html: &str) -> Result<(), anyhow::Error> {
+    let depth = path.split('/').filter(|s| !s.is_empty()).count();
+    let page_dir = dir.join(path);
+    fs::create_dir_all(&page_dir)?;
+    fs::write(page_dir.join("index.html"), relativize(html, depth))?;
+    Ok(())
+}
+
+fn remove_notes<'a>(
+    dir: &Path,
+    note_ids: impl Iterator<Item = &'a String>,
+) -> Result<(), anyhow::Error> {
+    for note_id in note_ids {
+        remove_dir_if_exists(&dir.join("note").join(note_id))?;
+    }
+    Ok(())
+}
+
+fn remove_dir_if_exists(path: &Path) -> Result<(), anyhow::Error> {
+    if path.exists() {
+        fs::remove_dir_all(path)?;
+    }
+    Ok(())
+}
+
+/// Copies any images which haven't already been copied, returning the number copied. Images are
+/// never modified, so existing files are left alone.
+fn copy_images(src: &Path, dst: &Path) -> Result<usize, anyhow::Error> {
+    fs::create_dir_all(dst)?;
+    let mut n = 0;
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        let target = dst.join(entry.file_name());
+        if entry.file_type()?.is_file() && !target.exists() {
+            fs::copy(entry.path(), target)?;
+            n += 1;
+        }
+    }
+    Ok(n)
+}
+
+/// Rewrites root-relative `href` and `src` attributes as relative to a page `depth` directories
+/// deep, pointing page links at their `index.html` files.
+fn relativize(html: &str, depth: usize) -> String {
+    let prefix = "../".repeat(depth);
+    rewrite_links(html, |url| {
+        let url = &url[1..];
+        let (path, suffix) = url.find(['?', '#']).map(|j| url.split_at(j)).unwrap_or((url, ""));
+        let segments = path.split('/').collect::<Vec<&str>>();
+        let path = match segments.as_slice() {
+            [""] => "index.html".into(),
+            ["note", _] | ["notes", _, _] => format!("{path}/index.html"),
+            _ => path.into(),
+        };
+        format!("{prefix}{path}{suffix}")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDateTime;
+    use sqlx::SqlitePool;
+    use tempdir::TempDir;
+    use uuid::Uuid;
+
+    use crate::config::{Author, Title};
+
+    use super::*;
+
+    #[sqlx::test]
+    async fn exporting(db: SqlitePool) -> Result<(), anyhow::Error> {
+        let data_dir = TempDir::new("yellhole-test")?;
+        let out = TempDir::new("yellhole-test")?;
+        fs::create_dir_all(data_dir.path().join(IMAGES_DIR))?;
+        fs::write(data_dir.path().join(IMAGES_DIR).join("mario.main.webp"), b"wahoo")?;
+
+        let notes = NoteService::new(db);
+        let october =
+            import(&notes, "It's a me, ![Mario](/images/mario.main.webp).", "2022-10-15 12:00")
+                .await?;
+        let november = import(&notes, "Luigi time.", "2022-11-15 12:00").await?;
+
+        let site = StaticSite::new(
+            notes.clone(),
+            data_dir.path(),
+            "https://example.com/".parse()?,
+            Site::new(Title("Yellhole".into()), Author("Mr Magoo".into()), chrono_tz::UTC),
+            Theme::default(),
+            FeedConfig::default(),
+        );
+        let summary = site.export(out.path(), false).await?;
+        assert_eq!(
+            summary,
+            ExportSummary {
+                months_rendered: 2,
+                notes_rendered: 2,
+                images_copied: 1,
+                ..Default::default()
+            }
+        );
+
+        let index = fs::read_to_string(out.path().join("index.html"))?;
+        assert!(index.contains(r#"href="assets/css/mvp-1.12.css""#));
+        assert!(index.contains(&format!(r#"href="note/{october}/index.html""#)));
+        assert!(index.contains(r#"src="images/mario.main.webp""#));
+        assert!(index.contains(r#"href="notes/2022/10/index.html""#));
+
+        let page = fs::read_to_string(out.path().join(format!("note/{november}/index.html")))?;
+        assert!(page.contains("Luigi time."));
+        assert!(page.contains(r#"href="../../index.html""#));
+        assert!(page.contains(r#"<meta property="og:title" content="Luigi time.">"#));
+        assert!(!page.contains("json+oembed"));
+
+        let month = fs::read_to_string(out.path().join("notes/2022/10/index.html"))?;
+        assert!(month.contains(r#"src="../../../images/mario.main.webp""#));
+        assert!(month.contains(r#"href="../../../notes/2022/11/index.html""#));
+
+        let atom = fs::read_to_string(out.path().join("atom.xml"))?;
+        assert!(atom.contains("images/mario.main.webp"));
+        assert!(out.path().join("images/mario.main.webp").exists());
+        assert!(out.path().join("assets/css/mvp-1.12.css").exists());
+
+        // Only the changed month is re-rendered, and deleted notes' pages are removed.
+        notes.delete(&november).await?;
+        import(&notes, "Peach time.", "2022-10-20 12:00").await?;
+        let summary = site.export(out.path(), false).await?;
+        assert_eq!(
+            summary,
+            ExportSummary { months_rendered: 1, notes_rendered: 2, ..Default::default() }
+        );
+        assert!(!out.path().join(format!("note/{november}")).exists());
+        assert!(!out.path().join("notes/2022/11").exists());
+
+        let summary = site.export(out.path(), false).await?;
+        assert_eq!(summary, ExportSummary { months_unchanged: 1, ..Default::default() });
+
+        let summary = site.export(out.path(), true).await?;
+        assert_eq!(
+            summary,
+            ExportSummary { months_rendered: 1, notes_rendered: 2, ..Default::default() }
+        );
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn navigating_months(db: SqlitePool) -> Result<(), anyhow::Error> {
+        let data_dir = TempDir::new("yellhole-test")?;
+        let out = TempDir::new("yellhole-test")?;
+        fs::create_dir_all(data_dir.path().join(IMAGES_DIR))?;
+
+        let notes = NoteService::new(db);
+        import(&notes, "Mario time.", "2022-08-15 12:00").await?;
+        import(&notes, "Luigi time.", "2022-11-15 12:00").await?;
+
+        let site = StaticSite::new(
+            notes.clone(),
+            data_dir.path(),
+            "https://example.com/".parse()?,
+            Site::new(Title("Yellhole".into()), Author("Mr Magoo".into()), chrono_tz::UTC),
+            Theme::default(),
+            FeedConfig::default(),
+        );
+        site.export(out.path(), false).await?;
+
+        // Months without notes are skipped.
+        let month =
+            |m: u32| fs::read_to_string(out.path().join(format!("notes/2022/{m}/index.html")));
+        assert!(month(11)?.contains(r#"href="../../../notes/2022/8/index.html">older"#));
+        assert!(!month(11)?.contains(">newer</a>"));
+        assert!(month(8)?.contains(r#"href="../../../notes/2022/11/index.html">newer"#));
+        assert!(!month(8)?.contains(">older</a>"));
+
+        // Adding a month re-renders its neighbors.
+        let peach = import(&notes, "Peach time.", "2022-10-15 12:00").await?;
+        let summary = site.export(out.path(), false).await?;
+        assert_eq!(
+            summary,
+            ExportSummary { months_rendered: 3, notes_rendered: 3, ..Default::default() }
+        );
+        assert!(month(11)?.contains(r#"href="../../../notes/2022/10/index.html">older"#));
+        assert!(month(8)?.contains(r#"href="../../../notes/2022/10/index.html">newer"#));
+
+        // So does removing one.
+        notes.delete(&peach).await?;
+        let summary = site.export(out.path(), false).await?;
+        assert_eq!(
+            summary,
+            ExportSummary { months_rendered: 2, notes_rendered: 2, ..Default::default() }
+        );
+        assert!(month(11)?.contains(r#"href="../../../notes/2022/8/index.html">older"#));
+        assert!(month(8)?.contains(r#"href="../../../notes/2022/11/index.html">newer"#));
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn changing_site_details(db: SqlitePool) -> Result<(), anyhow::Error> {
+        let data_dir = TempDir::new("yellhole-test")?;
+        let out = TempDir::new("yellhole-test")?;
+        fs::create_dir_all(data_dir.path().join(IMAGES_DIR))?;
+
+        let notes = NoteService::new(db);
+        import(&notes, "Mario time.", "2022-08-15 12:00").await?;
+        import(&notes, "Luigi time.", "2022-11-15 12:00").await?;
+
+        let export = |site: Site| {
+            let site = StaticSite::new(
+                notes.clone(),
+                data_dir.path(),
+                "https://example.com/".parse().unwrap(),
+                site,
+                Theme::load(data_dir.path()).unwrap(),
+                FeedConfig::default(),
+            );
+            let out = out.path().to_path_buf();
+            async move { site.export(&out, false).await }
+        };
+        let site = Site::new(Title("Yellhole".into()), Author("Mr Magoo".into()), chrono_tz::UTC);
+        let all = ExportSummary { months_rendered: 2, notes_rendered: 2, ..Default::default() };
+        assert_eq!(export(site.clone()).await?, all);
+        assert_eq!(
+            export(site.clone()).await?,
+            ExportSummary { months_unchanged: 2, ..Default::default() }
+        );
+
+        // Every page shows the title, author, and profile URLs.
+        let renamed = Site { title: "Wahoo".into(), ..site.clone() };
+        assert_eq!(export(renamed).await?, all);
+        let month = fs::read_to_string(out.path().join("notes/2022/8/index.html"))?;
+        assert!(month.contains("<title>Wahoo</title>"));
+        assert_eq!(export(site.clone()).await?, all);
+
+        // The time zone decides which month each note is in.
+        assert_eq!(export(Site { timezone: chrono_tz::Asia::Tokyo, ..site.clone() }).await?, all);
+        assert_eq!(export(site.clone()).await?, all);
+
+        // So do the theme's templates and assets.
+        let assets_dir = data_dir.path().join("themes").join("assets");
+        fs::create_dir_all(&assets_dir)?;
+        fs::write(assets_dir.join("theme.css"), "body { color: red; }")?;
+        assert_eq!(export(site.clone()).await?, all);
+        fs::write(assets_dir.join("theme.css"), "body { color: blue; }")?;
+        assert_eq!(export(site.clone()).await?, all);
+
+        let templates_dir = data_dir.path().join("themes").join("templates");
+        fs::create_dir_all(&templates_dir)?;
+        fs::write(templates_dir.join("error.html"), "Oops.")?;
+        assert_eq!(export(site.clone()).await?, all);
+        assert_eq!(
+            export(site).await?,
+            ExportSummary { months_unchanged: 2, ..Default::default() }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn relativizing_links() {
+        assert_eq!(
+            relativize(
+                r#"<a href="/">x</a><a href="/note/abc#x">y</a><img src="/images/a.webp"><a href="//example.com/">z</a><a href="https://example.com/">w</a><p title="/">"#,
+                2
+            ),
+            r#"<a href="../../index.html">x</a><a href="../../note/abc/index.html#x">y</a><img src="../../images/a.webp"><a href="//example.com/">z</a><a href="https://example.com/">w</a><p title="/">"#,
+        );
+    }
+
+    async fn import(
+        notes: &NoteService,
+        body: &str,
+        created_at: &str,
+    ) -> Result<uuid::fmt::Hyphenated, anyhow::Error> {
+        let note_id = Uuid::new_v4().hyphenated();
+        let created_at = NaiveDateTime::parse_from_str(created_at, "%F %R")?;
+        notes.import(&note_id, body, &created_at).await?;
+        Ok(note_id)
+    }
+}
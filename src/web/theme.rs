@@ -0,0 +1,164 @@
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::Context;
+use askama::{Html, MarkupDisplay};
+use minijinja::{AutoEscape, Environment};
+use sha2::{Digest, Sha256};
+
+/// The name of the directory in the data directory which holds the site's theme.
+pub const THEMES_DIR: &str = "themes";
+
+/// The built-in versions of the templates a theme can override, used for any it doesn't. The
+/// layout is shared with the compiled admin templates, so it must stay valid for both.
+const DEFAULT_TEMPLATES: &[(&str, &str)] = &[
+    ("layout.html", include_str!("../../templates/layout.html")),
+    ("feed.html", include_str!("../../templates/theme/feed.html")),
+    ("error.html", include_str!("../../templates/theme/error.html")),
+];
+
+/// The templates and static assets of the public pages, which the site owner can override in
+/// `themes/` in the data directory.
+///
+/// The public pages are always rendered at runtime using Jinja templates: those in
+/// `themes/templates`, if it exists, falling back to the built-in versions of `layout.html`,
+/// `feed.html`, and `error.html`. Files in `themes/assets` are served in place of the built-in
+/// assets with the same paths.
+#[derive(Debug, Clone)]
+pub struct Theme {
+    templates: Arc<Environment<'static>>,
+    assets_dir: Option<PathBuf>,
+}
+
+/// A page whose template can be overridden by a theme.
+pub trait Themed {
+    /// The name of the page's template.
+    const TEMPLATE: &'static str;
+
+    /// The values available to the page's template.
+    fn context(&self) -> minijinja::Value;
+}
+
+impl Theme {
+    /// Loads the theme in `data_dir`, if any. Templates are parsed up front so that errors in
+    /// them are reported on startup.
+    pub fn load(data_dir: &Path) -> Result<Theme, anyhow::Error> {
+        let dir = data_dir.join(THEMES_DIR);
+        let templates_dir = dir.join("templates");
+        let mut env = default_templates();
+        if templates_dir.is_dir() {
+            for entry in fs::read_dir(&templates_dir)? {
+                let path = entry?.path();
+                if path.extension().map(|e| e != "html").unwrap_or(true) {
+                    continue;
+                }
+                let name = path.file_name().expect("missing file name").to_string_lossy();
+                let source = fs::read_to_string(&path)?;
+                env.add_template_owned(name.to_string(), source)
+                    .with_context(|| format!("error parsing {}", path.display()))?;
+                tracing::info!(template = %name, "using theme template");
+            }
+        }
+
+        let assets_dir = Some(dir.join("assets")).filter(|d| d.is_dir());
+        if let Some(assets_dir) = &assets_dir {
+            tracing::info!(?assets_dir, "using theme assets");
+        }
+
+        Ok(Theme { templates: Arc::new(env), assets_dir })
+    }
+
+    /// Renders the page with the theme's version of its template, if any, or the built-in one.
+    pub fn render<T: Themed>(&self, page: &T) -> Result<String, anyhow::Error> {
+        Ok(self.templates.get_template(T::TEMPLATE)?.render(page.context())?)
+    }
+
+    /// Returns a hash of the templates and the theme's assets, which changes whenever they do.
+    pub fn fingerprint(&self) -> Result<String, anyhow::Error> {
+        let mut hasher = Sha256::new();
+        let mut templates = self.templates.templates().collect::<Vec<_>>();
+        templates.sort_by_key(|(name, _)| *name);
+        for (name, template) in templates {
+            hash_bytes(&mut hasher, name.as_bytes());
+            hash_bytes(&mut hasher, template.source().as_bytes());
+        }
+        if let Some(assets_dir) = &self.assets_dir {
+            hash_dir(&mut hasher, assets_dir, Path::new(""))?;
+        }
+        Ok(hex::encode(hasher.finalize()))
+    }
+
+    /// Returns the path of the theme's version of the given asset, if it has one.
+    pub fn asset(&self, path: &str) -> Option<PathBuf> {
+        let path = Path::new(path);
+        if !path.components().all(|c| matches!(c, Component::Normal(_))) {
+            return None;
+        }
+        let path = self.assets_dir.as_ref()?.join(path);
+        path.is_file().then_some(path)
+    }
+
+    /// Copies the theme's assets, if any, into `dst`, replacing any existing files.
+    pub fn copy_assets(&self, dst: &Path) -> Result<(), anyhow::Error> {
+        match &self.assets_dir {
+            Some(src) => copy_dir(src, dst),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Theme { templates: Arc::new(default_templates()), assets_dir: None }
+    }
+}
+
+fn default_templates() -> Environment<'static> {
+    let mut env = Environment::new();
+    // Escape values as the compiled templates do, which leaves the slashes in URLs alone.
+    env.set_formatter(|out, state, value| match value.as_str() {
+        Some(s) if state.auto_escape() == AutoEscape::Html && !value.is_safe() => {
+            Ok(write!(out, "{}", MarkupDisplay::new_unsafe(s, Html))?)
+        }
+        _ => minijinja::escape_formatter(out, state, value),
+    });
+    for (name, source) in DEFAULT_TEMPLATES {
+        env.add_template(name, source).expect("invalid built-in template");
+    }
+    env
+}
+
+fn hash_dir(hasher: &mut Sha256, dir: &Path, prefix: &Path) -> Result<(), anyhow::Error> {
+    let mut entries = fs::read_dir(dir)?.collect::<Result<Vec<fs::DirEntry>, _>>()?;
+    entries.sort_by_key(|e| e.file_name());
+    for entry in entries {
+        let path = prefix.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            hash_dir(hasher, &entry.path(), &path)?;
+        } else {
+            hash_bytes(hasher, path.to_string_lossy().as_bytes());
+            hash_bytes(hasher, &fs::read(entry.path())?);
+        }
+    }
+    Ok(())
+}
+
+fn hash_bytes(hasher: &mut Sha256, bytes: &[u8]) {
+    hasher.update((bytes.len() as u64).to_le_bytes());
+    hasher.update(bytes);
+}
+
+fn copy_dir(src: &Path, dst: &Path) -> Result<(), anyhow::Error> {
+    fs::create_dir_all(dst)?;
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        let target = dst.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &target)?;
+        } else {
+            fs::copy(entry.path(), target)?;
+        }
+    }
+    Ok(())
+}